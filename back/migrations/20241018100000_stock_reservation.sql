@@ -0,0 +1,10 @@
+-- volume held by orders that are waiting for their payment
+ALTER TABLE Products ADD COLUMN reserved_quantity FLOAT NOT NULL DEFAULT 0;
+
+UPDATE Products SET reserved_quantity = COALESCE((
+    SELECT SUM(OrderDetails.quantity * OrderDetails.variation_volume)
+    FROM OrderDetails INNER JOIN Orders ON Orders.id = OrderDetails.order_id
+    WHERE OrderDetails.product_id = Products.id
+        AND Orders.receipt IS NULL
+        AND Orders.canceled = FALSE
+), 0);
@@ -0,0 +1,43 @@
+-- orders paid at the counter have no payment intent: the columns have to become nullable,
+-- which sqlite can only do by rebuilding the table.
+-- OrderDetails is set aside during the rebuild, otherwise dropping Orders would cascade to it
+CREATE TABLE Orders_new
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires TIMESTAMP NULL,
+    payment_method VARCHAR(20) NOT NULL DEFAULT "stripe" CHECK( payment_method IN ("stripe", "cash", "external_card")),
+    payment_intent_id VARCHAR(255) UNIQUE,
+    canceled BOOLEAN NOT NULL DEFAULT FALSE,
+    client_secret VARCHAR(255) UNIQUE,
+    payment_status VARCHAR(20) CHECK( payment_status IN ("canceled", "processing", "succeeded")),
+    user_email VARCHAR(255),
+    receipt VARCHAR(255) UNIQUE,
+    served BOOLEAN NOT NULL DEFAULT FALSE
+);
+INSERT INTO Orders_new (id, timestamp, expires, payment_intent_id, canceled, client_secret, payment_status, user_email, receipt, served)
+SELECT id, timestamp, expires, payment_intent_id, canceled, client_secret, payment_status, user_email, receipt, served FROM Orders;
+
+CREATE TABLE OrderDetails_tmp AS SELECT * FROM OrderDetails;
+DROP TABLE OrderDetails;
+DROP TABLE Orders;
+ALTER TABLE Orders_new RENAME TO Orders;
+
+CREATE TABLE OrderDetails
+(
+	id INTEGER PRIMARY KEY NOT NULL,
+    order_id INT UNSIGNED NOT NULL,
+    product_id INT UNSIGNED NOT NULL, -- no constrains bc might be deleted
+    item_name VARCHAR(255) NOT NULL,
+    unit_price_ht INT NOT NULL,
+    tva FLOAT NOT NULL,
+    quantity INT UNSIGNED NOT NULL,
+    variation_volume FLOAT NOT NULL,
+    CONSTRAINT `fk_order_id`
+        FOREIGN KEY (order_id) REFERENCES Orders (id)
+        ON DELETE CASCADE
+        ON UPDATE RESTRICT,
+    CONSTRAINT `uq_order_id_product_id` UNIQUE (order_id, item_name)
+);
+INSERT INTO OrderDetails SELECT * FROM OrderDetails_tmp;
+DROP TABLE OrderDetails_tmp;
@@ -0,0 +1,15 @@
+ALTER TABLE OrderDetails ADD COLUMN refunded_quantity INT UNSIGNED NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS Refunds
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    order_id INT UNSIGNED NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    amount INT NOT NULL, -- ttc, in cents
+    reason TEXT NOT NULL,
+    provider_refund_id VARCHAR(255) UNIQUE, -- NULL for orders paid at the counter
+    CONSTRAINT `fk_order_id_refund`
+        FOREIGN KEY (order_id) REFERENCES Orders (id)
+        ON DELETE CASCADE
+        ON UPDATE RESTRICT
+);
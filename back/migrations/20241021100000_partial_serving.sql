@@ -0,0 +1,5 @@
+ALTER TABLE OrderDetails ADD COLUMN served_quantity INT UNSIGNED NOT NULL DEFAULT 0;
+
+-- orders that were marked as served before are considered fully handed over
+UPDATE OrderDetails SET served_quantity = quantity - refunded_quantity
+WHERE order_id IN (SELECT id FROM Orders WHERE served = TRUE);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS Outbox
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    payload TEXT NOT NULL, -- json serialized OutboxMessage
+    status VARCHAR(20) NOT NULL DEFAULT "pending" CHECK( status IN ("pending", "delivered", "dead")),
+    attempts INT UNSIGNED NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_outbox_due ON Outbox (status, next_attempt_at);
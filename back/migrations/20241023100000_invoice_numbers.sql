@@ -0,0 +1,26 @@
+-- one counter per year, incremented in the transaction that marks an order as paid so that a
+-- rolled back payment does not leave a hole in the sequence
+CREATE TABLE InvoiceSequences (
+    year INTEGER PRIMARY KEY NOT NULL,
+    last_number INTEGER NOT NULL
+);
+
+ALTER TABLE Orders ADD COLUMN invoice_number TEXT;
+CREATE UNIQUE INDEX orders_invoice_number ON Orders(invoice_number);
+
+-- orders paid before the numbering existed are numbered in order of creation
+UPDATE Orders SET invoice_number = numbered.invoice_number
+FROM (
+    SELECT
+        id,
+        strftime('%Y', timestamp) || '-' || printf('%06d', ROW_NUMBER() OVER (
+            PARTITION BY strftime('%Y', timestamp) ORDER BY id
+        )) AS invoice_number
+    FROM Orders WHERE receipt IS NOT NULL
+) AS numbered
+WHERE Orders.id = numbered.id;
+
+INSERT INTO InvoiceSequences (year, last_number)
+SELECT CAST(strftime('%Y', timestamp) AS INTEGER), COUNT(*)
+FROM Orders WHERE receipt IS NOT NULL
+GROUP BY strftime('%Y', timestamp);
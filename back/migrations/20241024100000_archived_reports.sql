@@ -0,0 +1,8 @@
+-- report files written in the reports directory when the bar closes
+CREATE TABLE ArchivedReports (
+    id INTEGER PRIMARY KEY NOT NULL,
+    bar_opening_id INTEGER NOT NULL UNIQUE REFERENCES BarOpenings(id),
+    csv_file TEXT NOT NULL,
+    pdf_file TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,6 @@
+-- when the order was paid and when its last line was handed over, for the time-to-serve
+-- statistics of the reports. Older orders only get a payment time
+ALTER TABLE Orders ADD COLUMN paid_at TIMESTAMP;
+ALTER TABLE Orders ADD COLUMN served_at TIMESTAMP;
+
+UPDATE Orders SET paid_at = timestamp WHERE receipt IS NOT NULL;
@@ -0,0 +1,11 @@
+-- every change of Products.stock_quantity, so that the volume sold over a period can be
+-- checked against what left the stock. Volumes are signed, negative when the stock decreases
+CREATE TABLE StockMovements (
+    id INTEGER PRIMARY KEY NOT NULL,
+    product_id INTEGER NOT NULL, -- no constraint, products might be deleted
+    order_id INTEGER,
+    reason TEXT NOT NULL CHECK (reason IN ('sale', 'refund', 'adjustment')),
+    volume FLOAT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX stock_movements_created_at ON StockMovements(created_at);
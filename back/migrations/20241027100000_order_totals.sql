@@ -0,0 +1,13 @@
+-- the TTC total of an order, written with its lines, so that searches can filter and sort on
+-- it. Refunds are not deducted. Existing orders get the same line rounding as the app:
+-- VAT is computed on each line and rounded half away from zero to the cent
+ALTER TABLE Orders ADD COLUMN total_ttc INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX orders_total_ttc ON Orders(total_ttc, id);
+
+UPDATE Orders SET total_ttc = COALESCE((
+    SELECT SUM(
+        unit_price_ht * quantity
+        + (unit_price_ht * quantity * CAST(ROUND(tva * 10000) AS INTEGER) + 5000) / 10000
+    )
+    FROM OrderDetails WHERE order_id = Orders.id
+), 0);
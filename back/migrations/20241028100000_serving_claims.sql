@@ -0,0 +1,5 @@
+-- the waiter handling an order: a claim keeps other counters from serving the same receipt
+-- for a while, and the waiter who handed the order over is kept with the serving time
+ALTER TABLE Orders ADD COLUMN claimed_by INTEGER REFERENCES Users (id) ON DELETE SET NULL;
+ALTER TABLE Orders ADD COLUMN claimed_at TIMESTAMP;
+ALTER TABLE Orders ADD COLUMN served_by INTEGER REFERENCES Users (id) ON DELETE SET NULL;
@@ -0,0 +1,15 @@
+-- who did what from the admin and waiter screens. The actor's email is copied so that the
+-- entries outlive the user, the values are JSON
+CREATE TABLE IF NOT EXISTS AuditLog
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    actor_id INTEGER REFERENCES Users (id) ON DELETE SET NULL,
+    actor_email VARCHAR(255) NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    target_kind VARCHAR(16) NOT NULL,
+    target_id TEXT,
+    before TEXT,
+    after TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS audit_log_target ON AuditLog (target_kind, target_id);
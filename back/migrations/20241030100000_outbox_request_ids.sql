@@ -0,0 +1,2 @@
+-- the request that enqueued the message, its id is logged and sent along when delivering it
+ALTER TABLE Outbox ADD COLUMN request_id TEXT;
@@ -0,0 +1,4 @@
+-- orders paid after being canceled, when the stock they had reserved was sold in the
+-- meantime: the payment is kept but the stock is left untouched, and the order has to be
+-- refunded or checked by hand
+ALTER TABLE Orders ADD COLUMN needs_review BOOLEAN NOT NULL DEFAULT FALSE;
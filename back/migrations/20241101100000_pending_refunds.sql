@@ -0,0 +1,21 @@
+-- a refund asked to the payment provider is written as pending before the call and settled
+-- once the provider answered: the stock is given back when it succeeded, the refunded
+-- quantities when it failed. Pending refunds are sent again with the same idempotency key
+ALTER TABLE Refunds ADD COLUMN status VARCHAR(16) NOT NULL DEFAULT 'succeeded';
+CREATE INDEX refunds_status ON Refunds(status);
+
+CREATE TABLE IF NOT EXISTS RefundLines
+(
+    refund_id INTEGER NOT NULL,
+    detail_id INTEGER NOT NULL,
+    quantity INT UNSIGNED NOT NULL,
+    PRIMARY KEY (refund_id, detail_id),
+    CONSTRAINT `fk_refund_id_refund_line`
+        FOREIGN KEY (refund_id) REFERENCES Refunds (id)
+        ON DELETE CASCADE
+        ON UPDATE RESTRICT,
+    CONSTRAINT `fk_detail_id_refund_line`
+        FOREIGN KEY (detail_id) REFERENCES OrderDetails (id)
+        ON DELETE CASCADE
+        ON UPDATE RESTRICT
+);
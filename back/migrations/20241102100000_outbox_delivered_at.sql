@@ -0,0 +1,3 @@
+-- delivered messages are purged some days after their delivery
+ALTER TABLE Outbox ADD COLUMN delivered_at TIMESTAMP;
+UPDATE Outbox SET delivered_at = created_at WHERE status = 'delivered';
@@ -0,0 +1,4 @@
+-- the stock of an unpaid order is only reserved for a few minutes, much less than the
+-- lifetime of the order. NULL once the reservation is given back (or was never taken)
+ALTER TABLE Orders ADD COLUMN reserved_until TIMESTAMP;
+UPDATE Orders SET reserved_until = expires WHERE receipt IS NULL AND canceled = FALSE;
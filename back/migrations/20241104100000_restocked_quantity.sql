@@ -0,0 +1,8 @@
+-- units that went back to the stock after a refund, served units are refunded without
+-- being restocked
+ALTER TABLE OrderDetails ADD COLUMN restocked_quantity INT UNSIGNED NOT NULL DEFAULT 0;
+UPDATE OrderDetails SET restocked_quantity = (
+    SELECT COALESCE(SUM(RefundLines.quantity), 0) FROM RefundLines
+    INNER JOIN Refunds ON Refunds.id = RefundLines.refund_id
+    WHERE RefundLines.detail_id = OrderDetails.id AND Refunds.status = 'succeeded'
+);
@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::time::OffsetDateTime, QueryBuilder, Sqlite, SqlitePool, Transaction};
+
+use crate::{admin::user::User, errors::ServerError, utils::serialize_time};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    Logout,
+    OpenBar,
+    CloseBar,
+    SetClosingMessage,
+    CreateProduct,
+    EditProduct,
+    DeleteProduct,
+    MoveProduct,
+    AddVariation,
+    RemoveVariation,
+    EditVariation,
+    AddUser,
+    DeleteUser,
+    UpdateRole,
+    DisconnectUser,
+    ClaimOrder,
+    SetServed,
+    ServeDetail,
+    CreateCounterOrder,
+    RefundOrder,
+    ReplayOutboxMessage,
+}
+
+/// what an action was done on, stored as a kind and an id
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditTarget {
+    Bar,
+    Product(u32),
+    Variation(u32),
+    /// users are designated by their email in every admin route
+    User(String),
+    Order(u32),
+    OutboxMessage(u32),
+}
+impl AuditTarget {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Bar => "bar",
+            Self::Product(_) => "product",
+            Self::Variation(_) => "variation",
+            Self::User(_) => "user",
+            Self::Order(_) => "order",
+            Self::OutboxMessage(_) => "outbox_message",
+        }
+    }
+    fn id(&self) -> Option<String> {
+        match self {
+            Self::Bar => None,
+            Self::Product(id) | Self::Variation(id) | Self::Order(id) | Self::OutboxMessage(id) => {
+                Some(id.to_string())
+            }
+            Self::User(email) => Some(email.clone()),
+        }
+    }
+}
+
+pub type AuditEntryId = u32;
+
+#[derive(Serialize, Debug)]
+pub struct AuditEntry {
+    pub id: AuditEntryId,
+    pub actor_email: String,
+    pub action: AuditAction,
+    pub target_kind: String,
+    pub target_id: Option<String>,
+    pub before: Value,
+    pub after: Value,
+    #[serde(serialize_with = "serialize_time")]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: u32,
+    actor_email: String,
+    action: AuditAction,
+    target_kind: String,
+    target_id: Option<String>,
+    before: Option<String>,
+    after: Option<String>,
+    created_at: OffsetDateTime,
+}
+
+/// to call in the transaction of the action, so that the entry is committed along with it.
+/// `Value::Null` stands for no value: nothing before a creation, nothing after a deletion
+pub async fn record(
+    transaction: &mut Transaction<'_, Sqlite>,
+    actor: &User,
+    action: AuditAction,
+    target: AuditTarget,
+    before: Value,
+    after: Value,
+) -> Result<(), ServerError> {
+    let to_text = |value: Value| (!value.is_null()).then(|| value.to_string());
+    let (target_kind, target_id) = (target.kind(), target.id());
+    let (before, after) = (to_text(before), to_text(after));
+    sqlx::query!(
+        "INSERT INTO AuditLog (actor_id, actor_email, action, target_kind, target_id, before, after)
+        VALUES (?, ?, ?, ?, ?, ?, ?)",
+        actor.id,
+        actor.email,
+        action,
+        target_kind,
+        target_id,
+        before,
+        after
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// what `get_entries` looks for, every entry by default
+#[derive(Default)]
+pub struct AuditFilters<'a> {
+    pub actor_email: Option<&'a str>,
+    pub action: Option<AuditAction>,
+    pub target_kind: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub date_begin: Option<OffsetDateTime>,
+    pub date_end: Option<OffsetDateTime>,
+}
+
+/// most recent first, `before` is the id of the last entry of the previous page
+pub async fn get_entries(
+    pool: &SqlitePool,
+    filters: &AuditFilters<'_>,
+    before: Option<AuditEntryId>,
+    limit: u32,
+) -> Result<Vec<AuditEntry>, ServerError> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT id, actor_email, action, target_kind, target_id, before, after, created_at
+        FROM AuditLog WHERE TRUE",
+    );
+    if let Some(actor_email) = filters.actor_email {
+        query.push(" AND actor_email = ").push_bind(actor_email);
+    }
+    if let Some(action) = filters.action {
+        query.push(" AND action = ").push_bind(action);
+    }
+    if let Some(target_kind) = filters.target_kind {
+        query.push(" AND target_kind = ").push_bind(target_kind);
+    }
+    if let Some(target_id) = filters.target_id {
+        query.push(" AND target_id = ").push_bind(target_id);
+    }
+    if let Some(date_begin) = filters.date_begin {
+        query
+            .push(" AND created_at >= datetime(")
+            .push_bind(date_begin)
+            .push(")");
+    }
+    if let Some(date_end) = filters.date_end {
+        query
+            .push(" AND created_at <= datetime(")
+            .push_bind(date_end)
+            .push(")");
+    }
+    if let Some(before) = before {
+        query.push(" AND id < ").push_bind(before);
+    }
+    query.push(" ORDER BY id DESC LIMIT ").push_bind(limit);
+
+    let rows = query.build_query_as::<AuditRow>().fetch_all(pool).await?;
+    let from_text = |text: Option<String>| -> Result<Value, ServerError> {
+        Ok(match text {
+            Some(text) => serde_json::from_str(&text)?,
+            None => Value::Null,
+        })
+    };
+    rows.into_iter()
+        .map(|row| {
+            Ok(AuditEntry {
+                id: row.id,
+                actor_email: row.actor_email,
+                action: row.action,
+                target_kind: row.target_kind,
+                target_id: row.target_id,
+                before: from_text(row.before)?,
+                after: from_text(row.after)?,
+                created_at: row.created_at,
+            })
+        })
+        .collect()
+}
+
+#[sqlx::test]
+async fn test_audit_log(pool: SqlitePool) {
+    use serde_json::json;
+
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let mut transaction = pool.begin().await.unwrap();
+    record(
+        &mut transaction,
+        &admin,
+        AuditAction::EditVariation,
+        AuditTarget::Variation(1),
+        json!({ "price_ht": 820 }),
+        json!({ "price_ht": 850 }),
+    )
+    .await
+    .unwrap();
+    record(
+        &mut transaction,
+        &waiter,
+        AuditAction::SetServed,
+        AuditTarget::Order(12),
+        json!({ "served": false }),
+        json!({ "served": true }),
+    )
+    .await
+    .unwrap();
+    record(
+        &mut transaction,
+        &admin,
+        AuditAction::DeleteUser,
+        AuditTarget::User(waiter.email.clone()),
+        json!({ "role": "waiter" }),
+        Value::Null,
+    )
+    .await
+    .unwrap();
+    transaction.commit().await.unwrap();
+
+    let all = get_entries(&pool, &AuditFilters::default(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0].action, AuditAction::DeleteUser);
+    assert_eq!(all[0].target_id.as_deref(), Some(waiter.email.as_str()));
+    assert_eq!(all[0].after, Value::Null);
+
+    let by_admin = AuditFilters {
+        actor_email: Some(&admin.email),
+        ..Default::default()
+    };
+    let entries = get_entries(&pool, &by_admin, None, 1).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    let entries = get_entries(&pool, &by_admin, Some(entries[0].id), 1)
+        .await
+        .unwrap();
+    assert_eq!(entries[0].action, AuditAction::EditVariation);
+    assert_eq!(entries[0].before, json!({ "price_ht": 820 }));
+    assert_eq!(entries[0].after, json!({ "price_ht": 850 }));
+
+    let on_order = AuditFilters {
+        target_kind: Some("order"),
+        target_id: Some("12"),
+        ..Default::default()
+    };
+    let entries = get_entries(&pool, &on_order, None, 10).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].actor_email, waiter.email);
+
+    let later = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+    let filters = AuditFilters {
+        date_begin: Some(later),
+        ..Default::default()
+    };
+    assert!(get_entries(&pool, &filters, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+}
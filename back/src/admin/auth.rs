@@ -1,12 +1,10 @@
 use crate::errors::{ServerError, SessionError};
 
-use sqlx::{types::time::OffsetDateTime, SqlitePool};
+use sqlx::{types::time::OffsetDateTime, SqliteConnection, SqlitePool};
 use std::time::Duration;
 
 use uuid::Uuid;
 
-use super::user::User;
-
 const SESSION_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
 
 #[derive(Clone, Debug)]
@@ -16,7 +14,7 @@ pub struct Session {
     pub uuid: String,
 }
 impl Session {
-    async fn delete_old_sessions(pool: &SqlitePool) -> Result<(), ServerError> {
+    pub async fn delete_old_sessions(pool: &SqlitePool) -> Result<(), ServerError> {
         sqlx::query!("DELETE FROM Sessions WHERE CURRENT_TIMESTAMP > expires")
             .execute(pool)
             .await
@@ -24,15 +22,17 @@ impl Session {
         Ok(())
     }
 
-    pub async fn delete_if_exists(pool: &SqlitePool, uuid: &str) -> Result<(), ServerError> {
+    pub async fn delete_if_exists(
+        conn: &mut SqliteConnection,
+        uuid: &str,
+    ) -> Result<(), ServerError> {
         sqlx::query!("DELETE FROM Sessions WHERE uuid = ?", uuid)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         Ok(())
     }
 
-    pub async fn new(pool: &SqlitePool, email: String) -> Result<Session, SessionError> {
-        Session::delete_old_sessions(pool).await?;
+    pub async fn new(conn: &mut SqliteConnection, email: String) -> Result<Session, SessionError> {
         // Session::delete_if_exists(pool, &email).await?;
         let session = Session {
             uuid: Uuid::new_v4().to_string(),
@@ -40,8 +40,10 @@ impl Session {
             email,
         };
 
-        let user = User::get_from_email(pool, &session.email)
-            .await?
+        let user = sqlx::query!("SELECT id FROM Users WHERE email = ?", session.email)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(ServerError::Sqlx)?
             .ok_or(SessionError::UserNotFound(session.email.clone()))?;
 
         sqlx::query!(
@@ -50,7 +52,7 @@ impl Session {
             session.expires,
             session.uuid
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await
         .map_err(ServerError::Sqlx)?;
 
@@ -69,10 +71,9 @@ impl Session {
         pool: &SqlitePool,
         email: &str,
     ) -> Result<Vec<Session>, ServerError> {
-        Session::delete_old_sessions(pool).await?;
         let sessions = sqlx::query_as!(
             Session,
-                "SELECT uuid, expires, email FROM Sessions INNER JOIN Users ON Sessions.user_id = Users.id WHERE Users.email = ?",
+                "SELECT uuid, expires, email FROM Sessions INNER JOIN Users ON Sessions.user_id = Users.id WHERE Users.email = ? AND expires > CURRENT_TIMESTAMP",
                 email
             )
             .fetch_all(pool)
@@ -84,14 +85,18 @@ impl Session {
 
 #[sqlx::test]
 async fn test_new_session(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "elicolh@gmail.com";
-    let res = Session::new(&pool, email.into()).await.unwrap();
+    let res = Session::new(&mut conn, email.into()).await.unwrap();
     assert_eq!(res.email, email);
     //valid uuid
     let session_uuid = <uuid::Uuid as std::str::FromStr>::from_str(&res.uuid).unwrap();
     assert_eq!(session_uuid.get_version(), Some(uuid::Version::Random));
 
-    let user = User::get_from_email(&pool, email).await.unwrap().unwrap();
+    let user = super::user::User::get_from_email(&pool, email)
+        .await
+        .unwrap()
+        .unwrap();
     let session_in_db = sqlx::query!("SELECT * FROM Sessions WHERE uuid = ?", res.uuid)
         .fetch_one(&pool)
         .await
@@ -107,7 +112,8 @@ async fn test_new_session(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_new_session_for_non_existant_user(pool: SqlitePool) {
-    let res = Session::new(&pool, "test@example.com".into()).await;
+    let mut conn = pool.acquire().await.unwrap();
+    let res = Session::new(&mut conn, "test@example.com".into()).await;
     assert!(res.is_err());
     if let Err(SessionError::UserNotFound(email)) = res {
         assert_eq!(email, "test@example.com")
@@ -118,9 +124,10 @@ async fn test_new_session_for_non_existant_user(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_get_all(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "elicolh@gmail.com";
-    let session1 = Session::new(&pool, email.to_owned()).await.unwrap();
-    let session2 = Session::new(&pool, email.to_owned()).await.unwrap();
+    let session1 = Session::new(&mut conn, email.to_owned()).await.unwrap();
+    let session2 = Session::new(&mut conn, email.to_owned()).await.unwrap();
 
     let sessions = Session::get_all(&pool).await.unwrap();
     assert_eq!(sessions.len(), 2);
@@ -136,10 +143,11 @@ async fn test_get_all_no_sessions(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_get_all_for_email(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email1 = "elicolh@gmail.com";
     let email2 = "eli.sauvage@utt.fr";
-    let session1 = Session::new(&pool, email1.to_owned()).await.unwrap();
-    Session::new(&pool, email2.to_owned()).await.unwrap();
+    let session1 = Session::new(&mut conn, email1.to_owned()).await.unwrap();
+    Session::new(&mut conn, email2.to_owned()).await.unwrap();
 
     let sessions = Session::get_all_sessions_for_email(&pool, email1)
         .await
@@ -150,9 +158,10 @@ async fn test_get_all_for_email(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_get_all_for_email_no_session(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email1 = "elicolh@gmail.com";
     let email2 = "eli.sauvage@utt.fr";
-    Session::new(&pool, email2.to_owned()).await.unwrap();
+    Session::new(&mut conn, email2.to_owned()).await.unwrap();
 
     let sessions = Session::get_all_sessions_for_email(&pool, email1)
         .await
@@ -163,9 +172,10 @@ async fn test_get_all_for_email_no_session(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn delete_old_sessions_test(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "elicolh@gmail.com";
-    let session1 = Session::new(&pool, email.to_owned()).await.unwrap();
-    let session2 = Session::new(&pool, email.to_owned()).await.unwrap();
+    let session1 = Session::new(&mut conn, email.to_owned()).await.unwrap();
+    let session2 = Session::new(&mut conn, email.to_owned()).await.unwrap();
 
     sqlx::query!(
         "UPDATE Sessions SET expires = datetime(CURRENT_TIMESTAMP, '-1 minute') WHERE uuid = ?",
@@ -183,11 +193,12 @@ async fn delete_old_sessions_test(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_delete_if_exists(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "elicolh@gmail.com";
-    let session1 = Session::new(&pool, email.to_owned()).await.unwrap();
-    let session2 = Session::new(&pool, email.to_owned()).await.unwrap();
+    let session1 = Session::new(&mut conn, email.to_owned()).await.unwrap();
+    let session2 = Session::new(&mut conn, email.to_owned()).await.unwrap();
 
-    Session::delete_if_exists(&pool, &session1.uuid)
+    Session::delete_if_exists(&mut conn, &session1.uuid)
         .await
         .unwrap();
     let sessions = Session::get_all(&pool).await.unwrap();
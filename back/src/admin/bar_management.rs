@@ -1,7 +1,11 @@
 use serde::Serialize;
-use sqlx::{types::time::OffsetDateTime, SqlitePool};
+use sqlx::{types::time::OffsetDateTime, Connection, SqliteConnection, SqlitePool};
 
-use crate::{errors::ServerError, utils::serialize_time};
+use crate::{
+    errors::ServerError,
+    outbox::{self, OutboxMessage},
+    utils::serialize_time,
+};
 
 #[derive(Serialize)]
 pub struct Bar {
@@ -21,70 +25,96 @@ impl Bar {
         Ok(res)
     }
 
-    pub async fn open(&mut self, pool: &SqlitePool) -> Result<(), ServerError> {
+    pub async fn open(&mut self, conn: &mut SqliteConnection) -> Result<(), ServerError> {
         sqlx::query!("UPDATE Bar SET is_open = TRUE")
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         let now = OffsetDateTime::now_utc();
         sqlx::query!("UPDATE Bar SET open_since = ?", now)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         self.is_open = true;
         self.open_since = now;
         Ok(())
     }
 
-    pub async fn close(&mut self, pool: &SqlitePool) -> Result<(), ServerError> {
+    /// records the opening that just ended, its report is archived by the outbox
+    pub async fn close(
+        &mut self,
+        conn: &mut SqliteConnection,
+    ) -> Result<BarOpeningId, ServerError> {
+        let mut transaction = conn.begin().await?;
         sqlx::query!("UPDATE Bar SET is_open = FALSE")
-            .execute(pool)
+            .execute(&mut *transaction)
             .await?;
-        sqlx::query!(
+        let bar_opening_id = sqlx::query!(
             "INSERT INTO BarOpenings (begin, end) VALUES (?, CURRENT_TIMESTAMP)",
             self.open_since
         )
-        .execute(pool)
+        .execute(&mut *transaction)
+        .await?
+        .last_insert_rowid() as BarOpeningId;
+        outbox::enqueue(
+            &mut transaction,
+            &OutboxMessage::ArchiveReport { bar_opening_id },
+        )
         .await?;
+        transaction.commit().await?;
         self.is_open = false;
-        Ok(())
+        Ok(bar_opening_id)
     }
 
     pub async fn set_closing_message(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         msg: String,
     ) -> Result<(), ServerError> {
         sqlx::query!("UPDATE Bar SET closing_message = ?", msg)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         self.closing_message = msg;
         Ok(())
     }
 }
 
+pub type BarOpeningId = u32;
+
 #[derive(Serialize)]
 pub struct BarOpening {
+    pub id: BarOpeningId,
     #[serde(serialize_with = "serialize_time")]
-    begin: OffsetDateTime,
+    pub begin: OffsetDateTime,
     #[serde(serialize_with = "serialize_time")]
-    end: OffsetDateTime,
+    pub end: OffsetDateTime,
 }
 pub async fn get_bar_openings(pool: &SqlitePool) -> Result<Vec<BarOpening>, ServerError> {
-    let res = sqlx::query!("SELECT begin, end FROM BarOpenings")
-        .fetch_all(pool)
-        .await?;
-    Ok(res
-        .into_iter()
-        .map(|r| BarOpening {
-            begin: r.begin,
-            end: r.end,
-        })
-        .collect())
+    let res = sqlx::query_as!(
+        BarOpening,
+        "SELECT id as \"id: u32\", begin, end FROM BarOpenings ORDER BY begin"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(res)
+}
+pub async fn get_bar_opening(
+    pool: &SqlitePool,
+    id: BarOpeningId,
+) -> Result<Option<BarOpening>, ServerError> {
+    let res = sqlx::query_as!(
+        BarOpening,
+        "SELECT id as \"id: u32\", begin, end FROM BarOpenings WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(res)
 }
 
 #[sqlx::test]
 async fn test_bar_open_close(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let mut bar = Bar::get(&pool).await.unwrap();
-    bar.open(&pool).await.unwrap();
+    bar.open(&mut conn).await.unwrap();
     assert!(bar.is_open);
     assert!(
         sqlx::query!("SELECT is_open as \"is_open: bool\" FROM Bar")
@@ -93,7 +123,7 @@ async fn test_bar_open_close(pool: SqlitePool) {
             .unwrap()
             .is_open
     );
-    bar.close(&pool).await.unwrap();
+    bar.close(&mut conn).await.unwrap();
     assert!(!bar.is_open);
     assert!(
         !sqlx::query!("SELECT is_open as \"is_open: bool\" FROM Bar")
@@ -106,6 +136,7 @@ async fn test_bar_open_close(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_closing_message(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let mut bar = Bar::get(&pool).await.unwrap();
     let messages = vec![
         "bar fermé",
@@ -113,7 +144,7 @@ async fn test_closing_message(pool: SqlitePool) {
         "le bar est femeé\nspecial character ❌",
     ];
     for message in messages {
-        bar.set_closing_message(&pool, message.to_string())
+        bar.set_closing_message(&mut conn, message.to_string())
             .await
             .unwrap();
         assert_eq!(bar.closing_message, message);
@@ -131,13 +162,14 @@ async fn test_closing_message(pool: SqlitePool) {
 #[sqlx::test]
 async fn test_get_openings(pool: SqlitePool) {
     use std::time::Duration;
+    let mut conn = pool.acquire().await.unwrap();
 
     let mut bar = Bar::get(&pool).await.unwrap();
-    bar.open(&pool).await.unwrap();
-    bar.close(&pool).await.unwrap();
-    bar.open(&pool).await.unwrap();
+    bar.open(&mut conn).await.unwrap();
+    bar.close(&mut conn).await.unwrap();
+    bar.open(&mut conn).await.unwrap();
     std::thread::sleep(Duration::from_secs(1));
-    bar.close(&pool).await.unwrap();
+    bar.close(&mut conn).await.unwrap();
 
     let openings = get_bar_openings(&pool).await.unwrap();
     assert_eq!(openings.len(), 2);
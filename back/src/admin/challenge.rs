@@ -58,7 +58,7 @@ impl ChallengeManager {
             .collect::<Vec<String>>();
 
         if cfg!(debug_assertions) {
-            println!(
+            tracing::debug!(
                 "challenge created for user {email}, code is: {:?}",
                 code.join(" - ")
             );
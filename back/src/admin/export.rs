@@ -0,0 +1,309 @@
+use futures_util::{stream, Stream, TryStreamExt};
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
+use tokio::sync::mpsc;
+
+use crate::{
+    app::{
+        money::{Amounts, Money, VatRate},
+        orders::PaymentMethod,
+    },
+    errors::ServerError,
+    utils::format_datetime,
+};
+
+/// rows are sent by batches of this size
+const CHUNK_ROWS: usize = 200;
+
+const HEADER: [&str; 18] = [
+    "Commande",
+    "Facture",
+    "Date",
+    "Moyen de paiement",
+    "Payment intent",
+    "Reçu",
+    "Email",
+    "Servie",
+    "Article",
+    "Quantité",
+    "Quantité remboursée",
+    "Prix unitaire HT",
+    "TVA",
+    "Total HT",
+    "Total TVA",
+    "Total TTC",
+    "Remboursé TTC",
+    "Net TTC",
+];
+
+/// every line of the paid orders between `begin` and `end` (included), as CSV chunks.
+/// Rows are read from the database while the response is sent, so the whole export is never
+/// held in memory
+pub fn export_order_lines(
+    pool: SqlitePool,
+    begin: OffsetDateTime,
+    end: OffsetDateTime,
+) -> impl Stream<Item = Result<Vec<u8>, ServerError>> {
+    // the producer stops as soon as the receiver is dropped, i.e. when the client goes away
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(e) = write_order_lines(&pool, begin, end, &sender).await {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+async fn write_order_lines(
+    pool: &SqlitePool,
+    begin: OffsetDateTime,
+    end: OffsetDateTime,
+    sender: &mpsc::Sender<Result<Vec<u8>, ServerError>>,
+) -> Result<(), ServerError> {
+    let mut builder = csv::WriterBuilder::new();
+    builder.delimiter(b';');
+    let mut writer = builder.from_writer(vec![]);
+    writer.write_record(HEADER)?;
+
+    let mut rows = sqlx::query!(
+        "SELECT
+            Orders.id as \"order_id: u32\",
+            invoice_number,
+            timestamp,
+            payment_method as \"payment_method: PaymentMethod\",
+            payment_intent_id,
+            receipt,
+            user_email,
+            served as \"served: bool\",
+            item_name,
+            quantity as \"quantity: u32\",
+            refunded_quantity as \"refunded_quantity: u32\",
+            unit_price_ht as \"unit_price_ht: i64\",
+            tva as \"tva: f32\"
+        FROM Orders INNER JOIN OrderDetails ON OrderDetails.order_id = Orders.id
+        WHERE receipt IS NOT NULL
+            AND quantity != 0
+            AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+        ORDER BY Orders.id, OrderDetails.id",
+        begin,
+        end
+    )
+    .fetch(pool);
+
+    let mut pending_rows = 0;
+    while let Some(row) = rows.try_next().await? {
+        let unit_price_ht = Money::from_cents(row.unit_price_ht);
+        let tva = VatRate::from_fraction(row.tva);
+        let line = Amounts::for_line(unit_price_ht, row.quantity, tva);
+        // refunds are computed from the net amounts, the refunded column is what the Refunds
+        // of the line add up to
+        let net = Amounts::for_line(unit_price_ht, row.quantity - row.refunded_quantity, tva);
+        writer.write_record([
+            row.order_id.to_string(),
+            row.invoice_number.unwrap_or_default(),
+            format_datetime(row.timestamp),
+            payment_method_name(row.payment_method).to_owned(),
+            row.payment_intent_id.unwrap_or_default(),
+            row.receipt.unwrap_or_default(),
+            row.user_email.unwrap_or_default(),
+            if row.served { "oui" } else { "non" }.to_owned(),
+            row.item_name,
+            row.quantity.to_string(),
+            row.refunded_quantity.to_string(),
+            unit_price_ht.to_decimal_string(),
+            tva.to_string(),
+            line.ht.to_decimal_string(),
+            line.vat.to_decimal_string(),
+            line.ttc.to_decimal_string(),
+            (line.ttc - net.ttc).to_decimal_string(),
+            net.ttc.to_decimal_string(),
+        ])?;
+        pending_rows += 1;
+        if pending_rows == CHUNK_ROWS {
+            pending_rows = 0;
+            let full = std::mem::replace(&mut writer, builder.from_writer(vec![]));
+            let chunk = full.into_inner().map_err(|e| e.into_error())?;
+            if sender.send(Ok(chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    let chunk = writer.into_inner().map_err(|e| e.into_error())?;
+    let _ = sender.send(Ok(chunk)).await;
+    Ok(())
+}
+
+fn payment_method_name(payment_method: PaymentMethod) -> &'static str {
+    match payment_method {
+        PaymentMethod::Stripe => "stripe",
+        PaymentMethod::Cash => "cash",
+        PaymentMethod::ExternalCard => "external_card",
+    }
+}
+
+#[sqlx::test]
+async fn test_export_order_lines(pool: SqlitePool) {
+    use crate::app::{
+        order_events::OrderEvents,
+        orders::{Cart, CartElement, Order},
+    };
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let begin = OffsetDateTime::now_utc() - std::time::Duration::from_secs(60);
+    let cart = |quantity| Cart {
+        elements: vec![CartElement {
+            variation_id: 2,
+            quantity,
+        }],
+    };
+    Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart(3),
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    for _ in 0..CHUNK_ROWS {
+        Order::generate_from_counter(
+            &pool,
+            &OrderEvents::new(),
+            &waiter,
+            cart(1),
+            PaymentMethod::Cash,
+        )
+        .await
+        .unwrap();
+    }
+    let end = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+
+    let chunks: Vec<Vec<u8>> = export_order_lines(pool.clone(), begin, end)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(chunks.len(), 2);
+    let csv = String::from_utf8(chunks.concat()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 1 + CHUNK_ROWS + 1);
+    assert!(lines[0].starts_with("Commande;Facture;Date"));
+    let first: Vec<&str> = lines[1].split(';').collect();
+    assert_eq!(first[8], "ipa (demie)");
+    assert_eq!(&first[13..], ["15,00", "3,00", "18,00", "0,00", "18,00"]);
+
+    // nothing outside of the range
+    let chunks: Vec<Vec<u8>> = export_order_lines(pool, end, end)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(chunks.concat()).unwrap().lines().count(),
+        1
+    );
+}
+
+#[sqlx::test]
+async fn test_export_matches_refunds(pool: SqlitePool) {
+    use crate::{
+        app::{
+            order_events::OrderEvents,
+            orders::{Cart, CartElement, Order},
+            refunds::{self, RefundLine},
+        },
+        payment_provider::{PaymentProvider, TestPaymentProvider},
+    };
+    use std::sync::Arc;
+    let admin = crate::admin::user::User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    // 33c at 5.5%, where refunding units one by one does not round like the whole line
+    let product_id = sqlx::query!(
+        "INSERT INTO Products (name, description, stock_quantity, position)
+        VALUES ('sirop', '', 10, 6)"
+    )
+    .execute(&pool)
+    .await
+    .unwrap()
+    .last_insert_rowid();
+    let variation_id = sqlx::query!(
+        "INSERT INTO ProductVariations (name, product_id, price_ht, tva, volume, available_to_order)
+        VALUES ('dose', ?, 33, 0.055, 0.02, TRUE)",
+        product_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap()
+    .last_insert_rowid() as u32;
+    let begin = OffsetDateTime::now_utc() - std::time::Duration::from_secs(60);
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id,
+                quantity: 3,
+            },
+            CartElement {
+                variation_id: 1,
+                quantity: 2,
+            },
+        ],
+    };
+    let order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    for detail in order.get_details(&pool).await.unwrap() {
+        let lines = vec![RefundLine {
+            detail_id: detail.detail_id,
+            quantity: 1,
+        }];
+        refunds::refund_order(
+            &pool,
+            payment_provider.clone(),
+            &OrderEvents::new(),
+            &admin,
+            &order,
+            Some(lines),
+            "",
+        )
+        .await
+        .unwrap();
+    }
+    let end = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+
+    let chunks: Vec<Vec<u8>> = export_order_lines(pool.clone(), begin, end)
+        .try_collect()
+        .await
+        .unwrap();
+    let csv = String::from_utf8(chunks.concat()).unwrap();
+    let cents = |amount: &str| amount.replace(',', "").parse::<i64>().unwrap();
+    let (mut charged, mut refunded, mut net) = (0, 0, 0);
+    for line in csv.lines().skip(1) {
+        let columns: Vec<&str> = line.split(';').collect();
+        charged += cents(columns[15]);
+        refunded += cents(columns[16]);
+        net += cents(columns[17]);
+    }
+    let recorded = refunds::get_refunded_amounts(&pool, &[order.id])
+        .await
+        .unwrap()[&order.id]
+        .cents();
+    assert_eq!(refunded, recorded);
+    assert_eq!(charged, refunded + net);
+    // the syrup line: 104 charged, 70 left once a unit is refunded
+    assert_eq!(recorded, 34 + 984);
+}
@@ -1,5 +1,10 @@
+pub(crate) mod audit;
 pub(crate) mod auth;
 pub(crate) mod bar_management;
 pub(crate) mod challenge;
+pub(crate) mod export;
 pub(crate) mod report;
+pub(crate) mod report_archive;
+pub(crate) mod session_report;
 pub(crate) mod user;
+pub(crate) mod volume_report;
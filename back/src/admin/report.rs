@@ -1,56 +1,440 @@
 use std::collections::HashMap;
 
 use serde::Serialize;
-use sqlx::SqlitePool;
-use tokio::task::JoinSet;
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
 
 use crate::{
-    app::orders::{Order, OrderDetailElement},
+    app::{
+        money::{self, Amounts, Money, VatRate, VatSummaryLine},
+        orders::PaymentMethod,
+    },
     errors::ServerError,
+    pdf::{PageWriter, BOLD, MARGIN, REGULAR},
+    utils::format_datetime,
 };
 
-pub type Report = Vec<ReportItem>;
+#[derive(Serialize)]
+pub struct Report {
+    items: Vec<ReportItem>,
+    payment_methods: Vec<PaymentMethodReport>,
+    vat_summary: Vec<VatSummaryLine>,
+}
+
 #[derive(Serialize)]
 pub struct ReportItem {
     item_name: String,
     quantity: u32,
-    tva: f32,
-    subtotal_ht: i32,
-    subtotal_ttc: i32,
+    tva: VatRate,
+    subtotal_ht: Money,
+    subtotal_ttc: Money,
 }
 
-pub async fn process_orders_to_report(
-    pool: &SqlitePool,
-    orders: Vec<Order>,
-) -> Result<Report, ServerError> {
-    let mut unique_items: HashMap<String, ReportItem> = HashMap::new();
-    let mut handles: JoinSet<Result<Vec<OrderDetailElement>, ServerError>> = JoinSet::new();
-    orders.into_iter().for_each(|order| {
-        let thread_pool = pool.to_owned();
-        handles.spawn(async move { order.get_details(&thread_pool).await });
-    });
-    handles
-        .join_all()
-        .await
-        .into_iter()
-        .collect::<Result<Vec<Vec<OrderDetailElement>>, ServerError>>()?
+#[derive(Serialize)]
+pub struct PaymentMethodReport {
+    payment_method: PaymentMethod,
+    order_count: u32,
+    subtotal_ht: Money,
+    subtotal_ttc: Money,
+}
+
+fn payment_method_label(payment_method: PaymentMethod) -> &'static str {
+    match payment_method {
+        PaymentMethod::Stripe => "En ligne",
+        PaymentMethod::Cash => "Espèces",
+        PaymentMethod::ExternalCard => "Carte (TPE)",
+    }
+}
+
+impl Report {
+    /// the paid orders between `begin` and `end` (included). Identical lines are grouped by
+    /// the database, their amounts are then computed once with the usual rounding
+    pub async fn for_period(
+        pool: &SqlitePool,
+        begin: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> Result<Report, ServerError> {
+        let mut payment_methods: Vec<PaymentMethodReport> = sqlx::query!(
+            "SELECT
+                payment_method as \"payment_method: PaymentMethod\",
+                COUNT(*) as \"order_count: u32\"
+            FROM Orders
+            WHERE receipt IS NOT NULL AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+            GROUP BY payment_method
+            ORDER BY payment_method",
+            begin,
+            end
+        )
+        .fetch_all(pool)
+        .await?
         .into_iter()
-        .flatten()
-        .for_each(|order_detail| {
-            if let Some(item) = unique_items.get_mut(&order_detail.item_name) {
-                item.quantity += order_detail.quantity;
-                item.subtotal_ttc += order_detail.subtotal_ttc;
-                item.subtotal_ht += order_detail.subtotal_ht;
-            } else {
-                let item = ReportItem {
-                    item_name: order_detail.item_name.clone(),
-                    quantity: order_detail.quantity,
-                    tva: order_detail.tva,
-                    subtotal_ht: order_detail.subtotal_ht,
-                    subtotal_ttc: order_detail.subtotal_ttc,
-                };
-                unique_items.insert(order_detail.item_name, item);
+        .map(|r| PaymentMethodReport {
+            payment_method: r.payment_method,
+            order_count: r.order_count,
+            subtotal_ht: Money::ZERO,
+            subtotal_ttc: Money::ZERO,
+        })
+        .collect();
+
+        // refunded units are not part of the takings: a line counts for the amounts of its
+        // net quantity, and the refunds are computed from these net amounts so that what was
+        // charged is exactly what was refunded plus what is reported
+        let lines = sqlx::query!(
+            "SELECT
+                payment_method as \"payment_method: PaymentMethod\",
+                item_name,
+                unit_price_ht as \"unit_price_ht: i64\",
+                tva as \"tva: f32\",
+                quantity - refunded_quantity as \"net_quantity!: u32\",
+                COUNT(*) as \"line_count: u32\"
+            FROM Orders INNER JOIN OrderDetails ON OrderDetails.order_id = Orders.id
+            WHERE receipt IS NOT NULL
+                AND quantity != 0
+                AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+            GROUP BY payment_method, item_name, unit_price_ht, tva, quantity - refunded_quantity",
+            begin,
+            end
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // the same item may have been sold at different rates if its VAT changed
+        let mut unique_items: HashMap<(String, VatRate), ReportItem> = HashMap::new();
+        let mut vat_lines: Vec<(VatRate, Amounts)> = vec![];
+        for line in lines {
+            let tva = VatRate::from_fraction(line.tva);
+            let amounts = Amounts::for_line(
+                Money::from_cents(line.unit_price_ht),
+                line.net_quantity,
+                tva,
+            ) * line.line_count;
+            if let Some(method_report) = payment_methods
+                .iter_mut()
+                .find(|m| m.payment_method == line.payment_method)
+            {
+                method_report.subtotal_ht += amounts.ht;
+                method_report.subtotal_ttc += amounts.ttc;
             }
-        });
-    Ok(unique_items.into_values().collect())
+            vat_lines.push((tva, amounts));
+            let item = unique_items
+                .entry((line.item_name, tva))
+                .or_insert_with_key(|(item_name, tva)| ReportItem {
+                    item_name: item_name.clone(),
+                    quantity: 0,
+                    tva: *tva,
+                    subtotal_ht: Money::ZERO,
+                    subtotal_ttc: Money::ZERO,
+                });
+            item.quantity += line.net_quantity * line.line_count;
+            item.subtotal_ht += amounts.ht;
+            item.subtotal_ttc += amounts.ttc;
+        }
+        let mut items: Vec<ReportItem> = unique_items.into_values().collect();
+        items.sort_by(|a, b| a.item_name.cmp(&b.item_name).then(a.tva.cmp(&b.tva)));
+        Ok(Report {
+            items,
+            payment_methods,
+            vat_summary: money::vat_summary(vat_lines),
+        })
+    }
+
+    pub fn order_count(&self) -> u32 {
+        self.payment_methods.iter().map(|m| m.order_count).sum()
+    }
+
+    /// what was cashed, refunds deducted
+    pub fn takings(&self) -> Amounts {
+        self.vat_summary.iter().map(|line| line.amounts).sum()
+    }
+
+    /// one section per table, separated with `;` and decimal commas for french spreadsheets
+    pub fn to_csv(&self) -> Result<Vec<u8>, ServerError> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b';')
+            .flexible(true)
+            .from_writer(vec![]);
+        writer.write_record(["Article", "Quantité", "TVA", "Total HT", "Total TTC"])?;
+        for item in &self.items {
+            writer.write_record([
+                item.item_name.clone(),
+                item.quantity.to_string(),
+                item.tva.to_string(),
+                item.subtotal_ht.to_decimal_string(),
+                item.subtotal_ttc.to_decimal_string(),
+            ])?;
+        }
+        writer.write_record(["Moyen de paiement", "Commandes", "Total HT", "Total TTC"])?;
+        for method in &self.payment_methods {
+            writer.write_record([
+                payment_method_label(method.payment_method).to_owned(),
+                method.order_count.to_string(),
+                method.subtotal_ht.to_decimal_string(),
+                method.subtotal_ttc.to_decimal_string(),
+            ])?;
+        }
+        writer.write_record(["Taux de TVA", "Base HT", "TVA", "TTC"])?;
+        for line in &self.vat_summary {
+            writer.write_record([
+                line.rate.to_string(),
+                line.amounts.ht.to_decimal_string(),
+                line.amounts.vat.to_decimal_string(),
+                line.amounts.ttc.to_decimal_string(),
+            ])?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| ServerError::Io(e.into_error()))
+    }
+
+    pub fn to_pdf(&self, begin: OffsetDateTime, end: OffsetDateTime) -> Vec<u8> {
+        let mut writer = PageWriter::new();
+        writer.text(BOLD, 18.0, MARGIN, "Rapport d'ouverture");
+        writer.text(
+            REGULAR,
+            10.0,
+            MARGIN,
+            &format!("Début : {}", format_datetime(begin)),
+        );
+        writer.text(
+            REGULAR,
+            10.0,
+            MARGIN,
+            &format!("Fin : {}", format_datetime(end)),
+        );
+        writer.skip(1.0);
+
+        let columns = [MARGIN, 300.0, 360.0, 420.0, 500.0];
+        writer.row(
+            BOLD,
+            &columns,
+            &["Article", "Qté", "TVA", "Total HT", "Total TTC"],
+        );
+        for item in &self.items {
+            writer.row(
+                REGULAR,
+                &columns,
+                &[
+                    &item.item_name,
+                    &item.quantity.to_string(),
+                    &item.tva.to_string(),
+                    &item.subtotal_ht.to_string(),
+                    &item.subtotal_ttc.to_string(),
+                ],
+            );
+        }
+        writer.skip(1.0);
+
+        let columns = [MARGIN, 200.0, 300.0, 400.0];
+        writer.row(
+            BOLD,
+            &columns,
+            &["Moyen de paiement", "Commandes", "Total HT", "Total TTC"],
+        );
+        for method in &self.payment_methods {
+            writer.row(
+                REGULAR,
+                &columns,
+                &[
+                    payment_method_label(method.payment_method),
+                    &method.order_count.to_string(),
+                    &method.subtotal_ht.to_string(),
+                    &method.subtotal_ttc.to_string(),
+                ],
+            );
+        }
+        writer.skip(1.0);
+
+        writer.row(BOLD, &columns, &["Taux de TVA", "Base HT", "TVA", "TTC"]);
+        for line in &self.vat_summary {
+            writer.row(
+                REGULAR,
+                &columns,
+                &[
+                    &line.rate.to_string(),
+                    &line.amounts.ht.to_string(),
+                    &line.amounts.vat.to_string(),
+                    &line.amounts.ttc.to_string(),
+                ],
+            );
+        }
+        writer.finish()
+    }
+}
+
+#[sqlx::test]
+async fn test_report_by_payment_method(pool: SqlitePool) {
+    use crate::{
+        app::order_events::OrderEvents,
+        app::orders::{Cart, CartElement, Order},
+        app::refunds::{self, RefundLine},
+        payment_provider::{PaymentProvider, TestPaymentProvider},
+    };
+    use std::sync::Arc;
+    let admin = crate::admin::user::User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = |variation_id, quantity| Cart {
+        elements: vec![CartElement {
+            variation_id,
+            quantity,
+        }],
+    };
+    let begin = OffsetDateTime::now_utc() - std::time::Duration::from_secs(60);
+    let mut orders = vec![];
+    for (variation_id, quantity, payment_method) in [
+        (1, 2, PaymentMethod::Cash),
+        (4, 1, PaymentMethod::Cash),
+        (7, 1, PaymentMethod::ExternalCard),
+    ] {
+        orders.push(
+            Order::generate_from_counter(
+                &pool,
+                &OrderEvents::new(),
+                &waiter,
+                cart(variation_id, quantity),
+                payment_method,
+            )
+            .await
+            .unwrap(),
+        );
+    }
+    // one of the two pints of the first order is refunded
+    let details = orders[0].get_details(&pool).await.unwrap();
+    let lines = vec![RefundLine {
+        detail_id: details[0].detail_id,
+        quantity: 1,
+    }];
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    refunds::refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &orders[0],
+        Some(lines),
+        "",
+    )
+    .await
+    .unwrap();
+    let end = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+    let report = Report::for_period(&pool, begin, end).await.unwrap();
+    assert_eq!(report.items.len(), 3);
+    let ipa = report
+        .items
+        .iter()
+        .find(|i| i.subtotal_ht.cents() == 820)
+        .unwrap();
+    assert_eq!(ipa.quantity, 1);
+    let cash = report
+        .payment_methods
+        .iter()
+        .find(|m| m.payment_method == PaymentMethod::Cash)
+        .unwrap();
+    assert_eq!(cash.order_count, 2);
+    assert_eq!(cash.subtotal_ht.cents(), 820 + 640);
+    assert_eq!(cash.subtotal_ttc.cents(), 984 + 768);
+    let card = report
+        .payment_methods
+        .iter()
+        .find(|m| m.payment_method == PaymentMethod::ExternalCard)
+        .unwrap();
+    assert_eq!(card.order_count, 1);
+    assert_eq!(card.subtotal_ht.cents(), 650);
+    // everything in the populated data is at 20%
+    assert_eq!(report.vat_summary.len(), 1);
+    let vat = report.vat_summary[0].amounts;
+    assert_eq!(vat.ht.cents(), 820 + 640 + 650);
+    assert_eq!(vat.ttc.cents(), 984 + 768 + 780);
+    assert_eq!(vat.ttc, vat.ht + vat.vat);
+    assert!(!report
+        .payment_methods
+        .iter()
+        .any(|m| m.payment_method == PaymentMethod::Stripe));
+}
+
+#[sqlx::test]
+async fn test_refunds_add_up_to_net(pool: SqlitePool) {
+    use crate::{
+        app::order_events::OrderEvents,
+        app::orders::{Cart, CartElement, Order},
+        app::refunds::{self, RefundLine},
+        payment_provider::{PaymentProvider, TestPaymentProvider},
+    };
+    use std::sync::Arc;
+    let admin = crate::admin::user::User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    // 3 x 33c at 5.5%: the VAT of a single unit rounds up, the one of the whole line down
+    let product_id = sqlx::query!(
+        "INSERT INTO Products (name, description, stock_quantity, position)
+        VALUES ('sirop', '', 10, 6)"
+    )
+    .execute(&pool)
+    .await
+    .unwrap()
+    .last_insert_rowid();
+    let variation_id = sqlx::query!(
+        "INSERT INTO ProductVariations (name, product_id, price_ht, tva, volume, available_to_order)
+        VALUES ('dose', ?, 33, 0.055, 0.02, TRUE)",
+        product_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap()
+    .last_insert_rowid() as u32;
+    let begin = OffsetDateTime::now_utc() - std::time::Duration::from_secs(60);
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id,
+            quantity: 3,
+        }],
+    };
+    let order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let details = order.get_details(&pool).await.unwrap();
+    let charged = details[0].subtotal_ttc;
+    assert_eq!(charged.cents(), 104);
+
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let mut refunded = Money::ZERO;
+    for expected in [34, 35] {
+        let lines = vec![RefundLine {
+            detail_id: details[0].detail_id,
+            quantity: 1,
+        }];
+        let amount = refunds::refund_order(
+            &pool,
+            payment_provider.clone(),
+            &OrderEvents::new(),
+            &admin,
+            &order,
+            Some(lines),
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(amount.cents(), expected);
+        refunded += amount;
+        let end = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+        let report = Report::for_period(&pool, begin, end).await.unwrap();
+        assert_eq!(charged, refunded + report.takings().ttc);
+    }
 }
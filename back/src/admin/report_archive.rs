@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use serde::Serialize;
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
+
+use crate::{
+    admin::{
+        bar_management::{self, BarOpeningId},
+        report::Report,
+    },
+    errors::ServerError,
+    utils::serialize_time,
+};
+
+/// also served as static files under `/api/admin/bar/reports`
+pub const REPORTS_DIR_PATH: &str = "./reports";
+
+#[derive(Serialize)]
+pub struct ArchivedReport {
+    pub id: u32,
+    pub bar_opening_id: BarOpeningId,
+    #[serde(serialize_with = "serialize_time")]
+    pub begin: OffsetDateTime,
+    #[serde(serialize_with = "serialize_time")]
+    pub end: OffsetDateTime,
+    pub csv_file: String,
+    pub pdf_file: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub created_at: OffsetDateTime,
+}
+
+/// writes the CSV and PDF reports of a bar opening in `reports_dir`. Running it again for the
+/// same opening rewrites the same files
+pub async fn archive_report(
+    pool: &SqlitePool,
+    reports_dir: &Path,
+    bar_opening_id: BarOpeningId,
+) -> Result<(), ServerError> {
+    let opening = bar_management::get_bar_opening(pool, bar_opening_id)
+        .await?
+        .ok_or(ServerError::Sqlx(sqlx::Error::RowNotFound))?;
+    let report = Report::for_period(pool, opening.begin, opening.end).await?;
+
+    let name = format!(
+        "rapport-{bar_opening_id:04}-{}{:02}{:02}",
+        opening.begin.year(),
+        opening.begin.month() as u8,
+        opening.begin.day()
+    );
+    let csv_file = format!("{name}.csv");
+    let pdf_file = format!("{name}.pdf");
+    std::fs::create_dir_all(reports_dir)?;
+    std::fs::write(reports_dir.join(&csv_file), report.to_csv()?)?;
+    std::fs::write(
+        reports_dir.join(&pdf_file),
+        report.to_pdf(opening.begin, opening.end),
+    )?;
+
+    sqlx::query!(
+        "INSERT INTO ArchivedReports (bar_opening_id, csv_file, pdf_file) VALUES (?, ?, ?)
+        ON CONFLICT (bar_opening_id) DO UPDATE SET created_at = CURRENT_TIMESTAMP",
+        bar_opening_id,
+        csv_file,
+        pdf_file
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// most recent first
+pub async fn get_archived_reports(pool: &SqlitePool) -> Result<Vec<ArchivedReport>, ServerError> {
+    let reports = sqlx::query_as!(
+        ArchivedReport,
+        "SELECT
+            ArchivedReports.id as \"id: u32\",
+            bar_opening_id as \"bar_opening_id: u32\",
+            begin,
+            end,
+            csv_file,
+            pdf_file,
+            created_at
+        FROM ArchivedReports INNER JOIN BarOpenings ON BarOpenings.id = bar_opening_id
+        ORDER BY begin DESC"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(reports)
+}
+
+#[sqlx::test]
+async fn test_archive_report_on_close(pool: SqlitePool) {
+    use crate::{
+        admin::bar_management::Bar,
+        app::{
+            order_events::OrderEvents,
+            orders::{Cart, CartElement, Order, PaymentMethod},
+        },
+        outbox::{self, OutboxMessage, OutboxStatus},
+    };
+    let mut conn = pool.acquire().await.unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut bar = Bar::get(&pool).await.unwrap();
+    bar.open(&mut conn).await.unwrap();
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 2,
+        }],
+    };
+    Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let bar_opening_id = bar.close(&mut conn).await.unwrap();
+
+    let pending = outbox::get_entries(&pool, Some(OutboxStatus::Pending), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(
+        pending[0].message,
+        OutboxMessage::ArchiveReport { bar_opening_id }
+    );
+    // delivered with the real reports directory by the outbox, a temporary one here
+    let reports_dir = std::env::temp_dir().join(format!("bnc-reports-{}", uuid::Uuid::new_v4()));
+    archive_report(&pool, &reports_dir, bar_opening_id)
+        .await
+        .unwrap();
+    archive_report(&pool, &reports_dir, bar_opening_id)
+        .await
+        .unwrap();
+
+    let reports = get_archived_reports(&pool).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].bar_opening_id, bar_opening_id);
+    let csv = std::fs::read_to_string(reports_dir.join(&reports[0].csv_file)).unwrap();
+    assert!(csv.contains("ipa (pinte);2;20%;16,40;19,68"));
+    assert!(csv.contains("Espèces;1;16,40;19,68"));
+    let pdf = std::fs::read(reports_dir.join(&reports[0].pdf_file)).unwrap();
+    assert!(pdf.starts_with(b"%PDF-"));
+    std::fs::remove_dir_all(reports_dir).unwrap();
+}
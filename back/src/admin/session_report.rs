@@ -0,0 +1,318 @@
+use serde::Serialize;
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
+
+use crate::{
+    admin::{
+        bar_management::{self, BarOpening, BarOpeningId},
+        report::Report,
+    },
+    app::{
+        money::{Amounts, Money},
+        orders::OrderId,
+    },
+    errors::ServerError,
+};
+
+/// the report of one bar opening, with the figures used to compare openings
+#[derive(Serialize)]
+pub struct SessionReport {
+    bar_opening: BarOpening,
+    order_count: u32,
+    takings: Amounts,
+    /// TTC per order, refunds deducted. Fully refunded orders are left out of the count, so
+    /// that they do not lower the average
+    average_basket: Money,
+    /// TTC per hour of opening
+    revenue_per_hour: Money,
+    serving_times: ServingTimes,
+    /// paid orders that still have something to hand over
+    unserved_orders: Vec<OrderId>,
+    volumes: Vec<ProductVolume>,
+    report: Report,
+}
+
+/// seconds between the payment of an order and the moment its last line was handed over,
+/// only for the orders that were fully served
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct ServingTimes {
+    served_count: u32,
+    average: Option<i64>,
+    median: Option<i64>,
+    max: Option<i64>,
+}
+
+/// litres handed over for one product
+#[derive(Serialize)]
+pub struct ProductVolume {
+    product_id: u32,
+    product_name: String,
+    volume: f64,
+}
+
+impl SessionReport {
+    pub async fn for_bar_opening(
+        pool: &SqlitePool,
+        bar_opening_id: BarOpeningId,
+    ) -> Result<Option<SessionReport>, ServerError> {
+        let Some(bar_opening) = bar_management::get_bar_opening(pool, bar_opening_id).await? else {
+            return Ok(None);
+        };
+        let (begin, end) = (bar_opening.begin, bar_opening.end);
+        let report = Report::for_period(pool, begin, end).await?;
+        let order_count = report.order_count();
+        let unserved_orders = sqlx::query!(
+            "SELECT id as \"id: u32\" FROM Orders
+            WHERE receipt IS NOT NULL AND served = FALSE
+                AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+            ORDER BY id",
+            begin,
+            end
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| r.id)
+        .collect();
+
+        let basket_count = sqlx::query!(
+            "SELECT COUNT(*) as \"count: u32\" FROM Orders
+            WHERE receipt IS NOT NULL
+                AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+                AND EXISTS (
+                    SELECT 1 FROM OrderDetails
+                    WHERE order_id = Orders.id AND quantity > refunded_quantity
+                )",
+            begin,
+            end
+        )
+        .fetch_one(pool)
+        .await?
+        .count;
+
+        let takings = report.takings();
+        let average_basket = match basket_count {
+            0 => Money::ZERO,
+            count => Money::from_cents((takings.ttc.cents() + count as i64 / 2) / count as i64),
+        };
+        let opened_seconds = (end - begin).whole_seconds().max(1);
+        let revenue_per_hour = Money::from_cents(takings.ttc.cents() * 3600 / opened_seconds);
+
+        Ok(Some(SessionReport {
+            order_count,
+            takings,
+            average_basket,
+            revenue_per_hour,
+            serving_times: get_serving_times(pool, begin, end).await?,
+            unserved_orders,
+            volumes: get_served_volumes(pool, begin, end).await?,
+            report,
+            bar_opening,
+        }))
+    }
+}
+
+async fn get_serving_times(
+    pool: &SqlitePool,
+    begin: OffsetDateTime,
+    end: OffsetDateTime,
+) -> Result<ServingTimes, ServerError> {
+    let mut durations: Vec<i64> = sqlx::query!(
+        "SELECT paid_at as \"paid_at!\", served_at as \"served_at!\" FROM Orders
+        WHERE receipt IS NOT NULL AND paid_at IS NOT NULL AND served_at IS NOT NULL
+            AND timestamp >= datetime(?) AND timestamp <= datetime(?)",
+        begin,
+        end
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| (r.served_at - r.paid_at).whole_seconds().max(0))
+    .collect();
+    if durations.is_empty() {
+        return Ok(ServingTimes::default());
+    }
+    durations.sort_unstable();
+    let count = durations.len();
+    let median = if count.is_multiple_of(2) {
+        (durations[count / 2 - 1] + durations[count / 2]) / 2
+    } else {
+        durations[count / 2]
+    };
+    Ok(ServingTimes {
+        served_count: count as u32,
+        average: Some(durations.iter().sum::<i64>() / count as i64),
+        median: Some(median),
+        max: durations.last().copied(),
+    })
+}
+
+async fn get_served_volumes(
+    pool: &SqlitePool,
+    begin: OffsetDateTime,
+    end: OffsetDateTime,
+) -> Result<Vec<ProductVolume>, ServerError> {
+    let volumes = sqlx::query_as!(
+        ProductVolume,
+        "SELECT
+            OrderDetails.product_id as \"product_id: u32\",
+            COALESCE(Products.name, '') as \"product_name!: String\",
+            CAST(SUM(served_quantity * variation_volume) AS REAL) as \"volume!: f64\"
+        FROM Orders
+            INNER JOIN OrderDetails ON OrderDetails.order_id = Orders.id
+            LEFT JOIN Products ON Products.id = OrderDetails.product_id
+        WHERE receipt IS NOT NULL AND timestamp >= datetime(?) AND timestamp <= datetime(?)
+        GROUP BY OrderDetails.product_id
+        ORDER BY OrderDetails.product_id",
+        begin,
+        end
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(volumes)
+}
+
+/// two openings side by side, the differences are the second one minus the first one
+#[derive(Serialize)]
+pub struct SessionComparison {
+    first: SessionReport,
+    second: SessionReport,
+    difference: SessionDifference,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SessionDifference {
+    order_count: i64,
+    takings_ttc: Money,
+    average_basket: Money,
+    revenue_per_hour: Money,
+    average_serving_time: Option<i64>,
+}
+
+pub fn compare_sessions(first: SessionReport, second: SessionReport) -> SessionComparison {
+    let difference = SessionDifference {
+        order_count: second.order_count as i64 - first.order_count as i64,
+        takings_ttc: second.takings.ttc - first.takings.ttc,
+        average_basket: second.average_basket - first.average_basket,
+        revenue_per_hour: second.revenue_per_hour - first.revenue_per_hour,
+        average_serving_time: second
+            .serving_times
+            .average
+            .zip(first.serving_times.average)
+            .map(|(second, first)| second - first),
+    };
+    SessionComparison {
+        first,
+        second,
+        difference,
+    }
+}
+
+#[sqlx::test]
+async fn test_session_report(pool: SqlitePool) {
+    use crate::{
+        app::{
+            order_events::OrderEvents,
+            orders::{Cart, CartElement, Order, PaymentMethod},
+            refunds,
+        },
+        payment_provider::{PaymentProvider, TestPaymentProvider},
+    };
+    use std::sync::Arc;
+    let mut conn = pool.acquire().await.unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let admin = crate::admin::user::User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = |variation_id, quantity| Cart {
+        elements: vec![CartElement {
+            variation_id,
+            quantity,
+        }],
+    };
+    let events = OrderEvents::new();
+    let mut bar = bar_management::Bar::get(&pool).await.unwrap();
+
+    bar.open(&mut conn).await.unwrap();
+    let mut served =
+        Order::generate_from_counter(&pool, &events, &waiter, cart(1, 2), PaymentMethod::Cash)
+            .await
+            .unwrap();
+    served
+        .set_served(&pool, &events, &waiter, true)
+        .await
+        .unwrap();
+    let waiting =
+        Order::generate_from_counter(&pool, &events, &waiter, cart(4, 1), PaymentMethod::Cash)
+            .await
+            .unwrap();
+    // counted as an order, but not in the average basket
+    let refunded =
+        Order::generate_from_counter(&pool, &events, &waiter, cart(1, 1), PaymentMethod::Cash)
+            .await
+            .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    refunds::refund_order(
+        &pool,
+        payment_provider,
+        &events,
+        &admin,
+        &refunded,
+        None,
+        "",
+    )
+    .await
+    .unwrap();
+    let first_id = bar.close(&mut conn).await.unwrap();
+
+    let first = SessionReport::for_bar_opening(&pool, first_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.bar_opening.id, first_id);
+    assert_eq!(first.order_count, 3);
+    assert_eq!(first.takings.ttc, Money::from_cents(1968 + 768));
+    assert_eq!(first.average_basket, Money::from_cents(1368));
+    assert_eq!(first.unserved_orders, vec![waiting.id]);
+    // the refunded order has nothing left to hand over
+    assert_eq!(first.serving_times.served_count, 2);
+    assert!(first.serving_times.median.is_some());
+    // only what was handed over is poured
+    assert_eq!(first.volumes.len(), 2);
+    assert_eq!(first.volumes[0].product_name, "ipa");
+    assert_eq!(first.volumes[0].volume, 1.0);
+    assert_eq!(first.volumes[1].volume, 0.0);
+
+    // the next opening starts after the first one ended
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    bar.open(&mut conn).await.unwrap();
+    let second_id = bar.close(&mut conn).await.unwrap();
+    let second = SessionReport::for_bar_opening(&pool, second_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(second.order_count, 0);
+    assert_eq!(second.serving_times, ServingTimes::default());
+
+    let comparison = compare_sessions(first, second);
+    assert_eq!(
+        comparison.difference,
+        SessionDifference {
+            order_count: -3,
+            takings_ttc: Money::from_cents(-(1968 + 768)),
+            average_basket: Money::from_cents(-1368),
+            revenue_per_hour: comparison.second.revenue_per_hour
+                - comparison.first.revenue_per_hour,
+            average_serving_time: None,
+        }
+    );
+
+    assert!(SessionReport::for_bar_opening(&pool, 42)
+        .await
+        .unwrap()
+        .is_none());
+}
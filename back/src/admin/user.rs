@@ -1,7 +1,7 @@
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
 use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize, Serializer};
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
 
 use crate::{
     errors::{ServerError, UserManagementError, UserParseError},
@@ -36,16 +36,19 @@ where
 
 impl User {
     pub async fn create(
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         email: &str,
         role: Role,
     ) -> Result<User, UserManagementError> {
-        let existing_user = User::get_from_email(pool, email).await?;
+        let existing_user = sqlx::query!("SELECT id FROM Users WHERE email = ?", email)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(ServerError::Sqlx)?;
         if existing_user.is_some() {
             return Err(UserManagementError::UserAlreadyExists(email.to_owned()));
         }
         let id = sqlx::query!("INSERT INTO Users (email, role) VALUES (?, ?)", email, role)
-            .execute(pool)
+            .execute(&mut *conn)
             .await
             .map_err(ServerError::Sqlx)?
             .last_insert_rowid() as u32;
@@ -104,7 +107,7 @@ impl User {
 
     pub async fn get_from_uuid(pool: &SqlitePool, uuid: &str) -> Result<Option<User>, ServerError> {
         let email_record = match sqlx::query!(
-            "SELECT email FROM Users INNER JOIN Sessions ON Sessions.user_id = Users.id WHERE uuid = ?",
+            "SELECT email FROM Users INNER JOIN Sessions ON Sessions.user_id = Users.id WHERE uuid = ? AND expires > CURRENT_TIMESTAMP",
             uuid
         )
         .fetch_optional(pool)
@@ -116,20 +119,24 @@ impl User {
         User::get_from_email(pool, &email_record.email).await
     }
 
-    pub async fn update_role(self, pool: &SqlitePool, new_role: Role) -> Result<(), ServerError> {
+    pub async fn update_role(
+        self,
+        conn: &mut SqliteConnection,
+        new_role: Role,
+    ) -> Result<(), ServerError> {
         sqlx::query!(
             "UPDATE Users SET role = ? WHERE email = ?",
             new_role,
             self.email
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         Ok(())
     }
 
-    pub async fn delete(self, pool: &SqlitePool) -> Result<(), ServerError> {
+    pub async fn delete(self, conn: &mut SqliteConnection) -> Result<(), ServerError> {
         sqlx::query!("DELETE FROM Users WHERE email = ?", self.email)
-            .execute(pool)
+            .execute(&mut *conn)
             .await
             .map_err(ServerError::Sqlx)?;
         Ok(())
@@ -183,28 +190,30 @@ impl std::ops::Deref for AdminUser {
 
 #[sqlx::test]
 async fn test_user_create_update_role_delete(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "user@example.com";
     let role = Role::Admin;
-    let user = User::create(&pool, email, role).await.unwrap();
+    let user = User::create(&mut conn, email, role).await.unwrap();
     assert_eq!(user.email, email);
     assert!(user.active_sessions.is_empty());
     assert_eq!(user.role, Role::Admin);
 
-    user.update_role(&pool, Role::Waiter).await.unwrap();
+    user.update_role(&mut conn, Role::Waiter).await.unwrap();
     let user = User::get_from_email(&pool, email).await.unwrap().unwrap();
     assert_eq!(user.role, Role::Waiter);
 
-    user.delete(&pool).await.unwrap();
+    user.delete(&mut conn).await.unwrap();
     let user = User::get_from_email(&pool, email).await.unwrap();
     assert!(user.is_none());
 }
 
 #[sqlx::test]
 async fn test_user_duplicate(pool: SqlitePool) {
+    let mut conn = pool.acquire().await.unwrap();
     let email = "user@example.com";
     let role = Role::Admin;
-    User::create(&pool, email, role).await.unwrap();
-    let user2 = User::create(&pool, email, role).await;
+    User::create(&mut conn, email, role).await.unwrap();
+    let user2 = User::create(&mut conn, email, role).await;
     assert!(user2.is_err());
     match user2.unwrap_err() {
         UserManagementError::UserAlreadyExists(m) => {
@@ -216,7 +225,9 @@ async fn test_user_duplicate(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_user_extractor(pool: SqlitePool) {
-    use crate::{mail_manager::TestMailManager, routes::InnerState};
+    use crate::{
+        mail_manager::TestMailManager, payment_provider::TestPaymentProvider, routes::InnerState,
+    };
     use axum::http::StatusCode;
     use axum::{
         http::{method::Method, Request},
@@ -225,15 +236,19 @@ async fn test_user_extractor(pool: SqlitePool) {
     };
     use std::sync::Arc;
     use tower::util::ServiceExt;
+    let mut conn = pool.acquire().await.unwrap();
     let email = "user@example.com";
-    let _user = User::create(&pool, email, Role::Waiter).await.unwrap();
-    let session = Session::new(&pool, email.to_owned()).await.unwrap();
+    let _user = User::create(&mut conn, email, Role::Waiter).await.unwrap();
+    let session = Session::new(&mut conn, email.to_owned()).await.unwrap();
     let state = InnerState {
         challenge_manager: Default::default(),
         pool,
         mail_manager: Arc::new(Box::new(TestMailManager {
             ..Default::default()
         })),
+        payment_provider: Arc::new(Box::new(TestPaymentProvider::default())),
+        order_events: Default::default(),
+        metrics_token: None,
     };
     let app = Router::new()
         .route("/", get(test_fn))
@@ -274,7 +289,9 @@ async fn test_user_extractor(pool: SqlitePool) {
 
 #[sqlx::test]
 async fn test_admin_extractor(pool: SqlitePool) {
-    use crate::{mail_manager::TestMailManager, routes::InnerState};
+    use crate::{
+        mail_manager::TestMailManager, payment_provider::TestPaymentProvider, routes::InnerState,
+    };
     use axum::http::StatusCode;
     use axum::{
         http::{method::Method, Request},
@@ -283,18 +300,26 @@ async fn test_admin_extractor(pool: SqlitePool) {
     };
     use std::sync::Arc;
     use tower::util::ServiceExt;
+    let mut conn = pool.acquire().await.unwrap();
     let email = "user@example.com";
-    let _user = User::create(&pool, email, Role::Waiter).await.unwrap();
+    let _user = User::create(&mut conn, email, Role::Waiter).await.unwrap();
     let email_admin = "admin@example.com";
-    let _admin = User::create(&pool, email_admin, Role::Admin).await.unwrap();
-    let session = Session::new(&pool, email.to_owned()).await.unwrap();
-    let session_admin = Session::new(&pool, email_admin.to_owned()).await.unwrap();
+    let _admin = User::create(&mut conn, email_admin, Role::Admin)
+        .await
+        .unwrap();
+    let session = Session::new(&mut conn, email.to_owned()).await.unwrap();
+    let session_admin = Session::new(&mut conn, email_admin.to_owned())
+        .await
+        .unwrap();
     let state = InnerState {
         challenge_manager: Default::default(),
         pool,
         mail_manager: Arc::new(Box::new(TestMailManager {
             ..Default::default()
         })),
+        payment_provider: Arc::new(Box::new(TestPaymentProvider::default())),
+        order_events: Default::default(),
+        metrics_token: None,
     };
     let app = Router::new()
         .route("/", get(test_fn))
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
+
+use crate::{app::stock_movements::StockMovementReason, errors::ServerError};
+
+/// litres sold of one product over a period, all variations together, next to what left its
+/// stock over the same period
+#[derive(Serialize)]
+pub struct ProductVolumeReport {
+    product_id: u32,
+    product_name: String,
+    /// refunds deducted
+    sold_volume: f64,
+    variations: Vec<VariationVolume>,
+    stock: StockReconciliation,
+}
+
+#[derive(Serialize)]
+pub struct VariationVolume {
+    item_name: String,
+    quantity: u32,
+    volume: f64,
+}
+
+/// signed stock movements, negative when the stock decreased
+#[derive(Serialize, Default)]
+pub struct StockReconciliation {
+    sales: f64,
+    refunds: f64,
+    adjustments: f64,
+    /// how much the stock changed over the period
+    change: f64,
+    /// sold volume that did not leave the stock over the period, 0 unless an order paid in
+    /// the period was refunded after it, or the other way around
+    discrepancy: f64,
+}
+
+/// sales are counted on the payment date of their order, stock movements on their own date
+pub async fn get_volume_report(
+    pool: &SqlitePool,
+    begin: OffsetDateTime,
+    end: OffsetDateTime,
+) -> Result<Vec<ProductVolumeReport>, ServerError> {
+    let mut products: BTreeMap<u32, ProductVolumeReport> = BTreeMap::new();
+    let sold = sqlx::query!(
+        "SELECT
+            OrderDetails.product_id as \"product_id: u32\",
+            COALESCE(Products.name, '') as \"product_name!: String\",
+            item_name,
+            CAST(SUM(quantity - refunded_quantity) AS INTEGER) as \"quantity!: u32\",
+            CAST(SUM((quantity - refunded_quantity) * variation_volume) AS REAL) as \"volume!: f64\"
+        FROM Orders
+            INNER JOIN OrderDetails ON OrderDetails.order_id = Orders.id
+            LEFT JOIN Products ON Products.id = OrderDetails.product_id
+        WHERE receipt IS NOT NULL AND paid_at >= datetime(?) AND paid_at <= datetime(?)
+        GROUP BY OrderDetails.product_id, item_name
+        ORDER BY item_name",
+        begin,
+        end
+    )
+    .fetch_all(pool)
+    .await?;
+    for line in sold {
+        let product = products
+            .entry(line.product_id)
+            .or_insert_with(|| ProductVolumeReport {
+                product_id: line.product_id,
+                product_name: line.product_name,
+                sold_volume: 0.0,
+                variations: vec![],
+                stock: StockReconciliation::default(),
+            });
+        product.sold_volume += line.volume;
+        product.variations.push(VariationVolume {
+            item_name: line.item_name,
+            quantity: line.quantity,
+            volume: line.volume,
+        });
+    }
+
+    let movements = sqlx::query!(
+        "SELECT
+            StockMovements.product_id as \"product_id: u32\",
+            COALESCE(Products.name, '') as \"product_name!: String\",
+            reason as \"reason: StockMovementReason\",
+            CAST(SUM(volume) AS REAL) as \"volume!: f64\"
+        FROM StockMovements LEFT JOIN Products ON Products.id = StockMovements.product_id
+        WHERE created_at >= datetime(?) AND created_at <= datetime(?)
+        GROUP BY StockMovements.product_id, reason",
+        begin,
+        end
+    )
+    .fetch_all(pool)
+    .await?;
+    for movement in movements {
+        let product = products
+            .entry(movement.product_id)
+            .or_insert_with(|| ProductVolumeReport {
+                product_id: movement.product_id,
+                product_name: movement.product_name,
+                sold_volume: 0.0,
+                variations: vec![],
+                stock: StockReconciliation::default(),
+            });
+        match movement.reason {
+            StockMovementReason::Sale => product.stock.sales += movement.volume,
+            StockMovementReason::Refund => product.stock.refunds += movement.volume,
+            StockMovementReason::Adjustment => product.stock.adjustments += movement.volume,
+        }
+    }
+
+    Ok(products
+        .into_values()
+        .map(|mut product| {
+            let stock = &mut product.stock;
+            stock.change = stock.sales + stock.refunds + stock.adjustments;
+            stock.discrepancy = product.sold_volume + stock.sales + stock.refunds;
+            product
+        })
+        .collect())
+}
+
+#[sqlx::test]
+async fn test_volume_report(pool: SqlitePool) {
+    use crate::app::{
+        order_events::OrderEvents,
+        orders::{Cart, CartElement, Order, PaymentMethod},
+        products::Product,
+        refunds::{self, RefundLine},
+    };
+    use crate::payment_provider::{PaymentProvider, TestPaymentProvider};
+    use std::sync::Arc;
+    let mut conn = pool.acquire().await.unwrap();
+    let admin = crate::admin::user::User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let begin = OffsetDateTime::now_utc() - std::time::Duration::from_secs(60);
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id: 1,
+                quantity: 2,
+            },
+            CartElement {
+                variation_id: 2,
+                quantity: 2,
+            },
+            CartElement {
+                variation_id: 4,
+                quantity: 1,
+            },
+        ],
+    };
+    let events = OrderEvents::new();
+    let order = Order::generate_from_counter(&pool, &events, &waiter, cart, PaymentMethod::Cash)
+        .await
+        .unwrap();
+    let pints = order
+        .get_details(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|d| d.item_name == "ipa (pinte)")
+        .unwrap();
+    let provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    refunds::refund_order(
+        &pool,
+        provider,
+        &events,
+        &admin,
+        &order,
+        Some(vec![RefundLine {
+            detail_id: pints.detail_id,
+            quantity: 1,
+        }]),
+        "renversée",
+    )
+    .await
+    .unwrap();
+    let mut blonde = Product::get(&pool, 2).await.unwrap().unwrap();
+    blonde.set_stock_quantity(&mut conn, 60.0).await.unwrap();
+    let end = OffsetDateTime::now_utc() + std::time::Duration::from_secs(60);
+
+    let report = get_volume_report(&pool, begin, end).await.unwrap();
+    assert_eq!(report.len(), 2);
+    let ipa = &report[0];
+    assert_eq!(ipa.product_name, "ipa");
+    // one pint and two halves
+    assert_eq!(ipa.sold_volume, 1.0);
+    assert_eq!(ipa.variations.len(), 2);
+    assert_eq!(ipa.variations[0].item_name, "ipa (demie)");
+    assert_eq!(ipa.variations[1].quantity, 1);
+    assert_eq!(ipa.stock.sales, -1.5);
+    assert_eq!(ipa.stock.refunds, 0.5);
+    assert_eq!(ipa.stock.change, -1.0);
+    assert_eq!(ipa.stock.discrepancy, 0.0);
+
+    let blonde = &report[1];
+    assert_eq!(blonde.sold_volume, 0.5);
+    assert_eq!(blonde.stock.sales, -0.5);
+    assert_eq!(blonde.stock.adjustments, 10.5);
+    assert_eq!(blonde.stock.change, 10.0);
+
+    let report = get_volume_report(&pool, end, end).await.unwrap();
+    assert!(report.is_empty());
+}
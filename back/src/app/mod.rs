@@ -1,9 +1,15 @@
+pub(crate) mod money;
 pub(crate) mod stripe;
 
 mod products_model;
 pub(crate) use products_model::product_variations;
 pub(crate) use products_model::products;
+pub(crate) use products_model::stock_movements;
 
 mod orders_model;
+pub(crate) use orders_model::invoice;
+pub(crate) use orders_model::mail;
+pub(crate) use orders_model::order_events;
 pub(crate) use orders_model::orders;
 pub(crate) use orders_model::receipt;
+pub(crate) use orders_model::refunds;
@@ -0,0 +1,248 @@
+//! every amount handled by the app goes through here so that what is charged, what is
+//! refunded, what is mailed and what is reported are always the same numbers.
+//!
+//! Rounding rule: amounts are integer cents and VAT is computed once per order line, on the
+//! line's HT total (unit price HT x quantity), rounded half away from zero to the cent. The
+//! line TTC is HT + VAT, and an order total is the sum of its lines.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Mul, Sub, SubAssign},
+};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+/// an amount in cents
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_cents(cents: i64) -> Money {
+        Money(cents)
+    }
+    pub fn cents(self) -> i64 {
+        self.0
+    }
+    /// `12,50`, what spreadsheets expect in a french locale
+    pub fn to_decimal_string(self) -> String {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        format!("{sign}{},{:02}", self.0.abs() / 100, self.0.abs() % 100)
+    }
+}
+impl Add for Money {
+    type Output = Money;
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        self.0 += rhs.0
+    }
+}
+impl Sub for Money {
+    type Output = Money;
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+impl SubAssign for Money {
+    fn sub_assign(&mut self, rhs: Money) {
+        self.0 -= rhs.0
+    }
+}
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+/// `12,50€`, as written on french receipts
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}€", self.to_decimal_string())
+    }
+}
+
+/// a VAT rate in basis points (20% = 2000)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VatRate(u32);
+
+impl VatRate {
+    /// rates are stored as fractions (0.2) in the database, every rate in use has an exact
+    /// basis point value
+    pub fn from_fraction(fraction: f32) -> VatRate {
+        VatRate((fraction as f64 * 10_000.0).round() as u32)
+    }
+    pub fn as_fraction(self) -> f64 {
+        self.0 as f64 / 10_000.0
+    }
+    /// the VAT due on an HT amount, rounded half away from zero
+    pub fn vat_on(self, ht: Money) -> Money {
+        let scaled = ht.0 * self.0 as i64;
+        let rounded = (scaled.abs() + 5_000) / 10_000;
+        Money(if scaled < 0 { -rounded } else { rounded })
+    }
+}
+/// serialized as a fraction (0.2) like the `tva` fields the front already uses
+impl Serialize for VatRate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_fraction())
+    }
+}
+/// `5,5%`
+impl fmt::Display for VatRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (units, decimals) = (self.0 / 100, self.0 % 100);
+        if decimals == 0 {
+            write!(f, "{units}%")
+        } else if decimals % 10 == 0 {
+            write!(f, "{units},{}%", decimals / 10)
+        } else {
+            write!(f, "{units},{decimals:02}%")
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Amounts {
+    pub ht: Money,
+    pub vat: Money,
+    pub ttc: Money,
+}
+impl Amounts {
+    /// the amounts of one order line, see the module documentation for the rounding rule
+    pub fn for_line(unit_price_ht: Money, quantity: u32, rate: VatRate) -> Amounts {
+        let ht = Money(unit_price_ht.0 * quantity as i64);
+        let vat = rate.vat_on(ht);
+        Amounts {
+            ht,
+            vat,
+            ttc: ht + vat,
+        }
+    }
+}
+impl Add for Amounts {
+    type Output = Amounts;
+    fn add(self, rhs: Amounts) -> Amounts {
+        Amounts {
+            ht: self.ht + rhs.ht,
+            vat: self.vat + rhs.vat,
+            ttc: self.ttc + rhs.ttc,
+        }
+    }
+}
+impl AddAssign for Amounts {
+    fn add_assign(&mut self, rhs: Amounts) {
+        *self = *self + rhs
+    }
+}
+impl Sub for Amounts {
+    type Output = Amounts;
+    fn sub(self, rhs: Amounts) -> Amounts {
+        Amounts {
+            ht: self.ht - rhs.ht,
+            vat: self.vat - rhs.vat,
+            ttc: self.ttc - rhs.ttc,
+        }
+    }
+}
+/// the amounts of `n` identical lines, each one already rounded
+impl Mul<u32> for Amounts {
+    type Output = Amounts;
+    fn mul(self, n: u32) -> Amounts {
+        Amounts {
+            ht: Money(self.ht.0 * n as i64),
+            vat: Money(self.vat.0 * n as i64),
+            ttc: Money(self.ttc.0 * n as i64),
+        }
+    }
+}
+impl Sum for Amounts {
+    fn sum<I: Iterator<Item = Amounts>>(iter: I) -> Amounts {
+        iter.fold(Amounts::default(), Add::add)
+    }
+}
+
+/// the totals of one VAT rate, as required on receipts and in the accounting
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct VatSummaryLine {
+    pub rate: VatRate,
+    #[serde(flatten)]
+    pub amounts: Amounts,
+}
+
+/// sums line amounts per rate, ordered by rate. Each line is already rounded so the summary
+/// adds up to the order totals
+pub fn vat_summary(lines: impl IntoIterator<Item = (VatRate, Amounts)>) -> Vec<VatSummaryLine> {
+    let mut per_rate: BTreeMap<VatRate, Amounts> = BTreeMap::new();
+    for (rate, amounts) in lines {
+        *per_rate.entry(rate).or_default() += amounts;
+    }
+    per_rate
+        .into_iter()
+        .map(|(rate, amounts)| VatSummaryLine { rate, amounts })
+        .collect()
+}
+
+#[test]
+fn test_line_rounding() {
+    let rate = VatRate::from_fraction(0.2);
+    assert_eq!(rate, VatRate(2000));
+    assert_eq!(VatRate::from_fraction(0.055), VatRate(550));
+
+    let line = Amounts::for_line(Money::from_cents(820), 2, rate);
+    assert_eq!(line.ht, Money::from_cents(1640));
+    assert_eq!(line.vat, Money::from_cents(328));
+    assert_eq!(line.ttc, Money::from_cents(1968));
+
+    // 3 x 0.33 = 0.99 HT, 5.5% = 0.05445 -> 0.05
+    let line = Amounts::for_line(Money::from_cents(33), 3, VatRate::from_fraction(0.055));
+    assert_eq!(line.vat, Money::from_cents(5));
+    // 0.10 HT at 5.5% = 0.0055 -> 0.01, half away from zero
+    let line = Amounts::for_line(Money::from_cents(10), 1, VatRate::from_fraction(0.055));
+    assert_eq!(line.vat, Money::from_cents(1));
+    assert_eq!(
+        VatRate::from_fraction(0.055).vat_on(Money::from_cents(-10)),
+        Money::from_cents(-1)
+    );
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(Money::from_cents(1250).to_string(), "12,50€");
+    assert_eq!(Money::from_cents(5).to_string(), "0,05€");
+    assert_eq!(Money::from_cents(-984).to_string(), "-9,84€");
+    assert_eq!(Money::from_cents(-5).to_decimal_string(), "-0,05");
+    assert_eq!(VatRate(2000).to_string(), "20%");
+    assert_eq!(VatRate(550).to_string(), "5,5%");
+    assert_eq!(VatRate(1000).to_string(), "10%");
+}
+
+#[test]
+fn test_vat_summary() {
+    let alcohol = VatRate::from_fraction(0.2);
+    let food = VatRate::from_fraction(0.055);
+    let summary = vat_summary([
+        (
+            alcohol,
+            Amounts::for_line(Money::from_cents(820), 2, alcohol),
+        ),
+        (food, Amounts::for_line(Money::from_cents(650), 1, food)),
+        (
+            alcohol,
+            Amounts::for_line(Money::from_cents(640), 1, alcohol),
+        ),
+    ]);
+    assert_eq!(summary.len(), 2);
+    assert_eq!(summary[0].rate, food);
+    assert_eq!(summary[0].amounts.vat, Money::from_cents(36));
+    assert_eq!(summary[1].rate, alcohol);
+    assert_eq!(summary[1].amounts.ht, Money::from_cents(1640 + 640));
+    assert_eq!(summary[1].amounts.vat, Money::from_cents(328 + 128));
+    assert_eq!(summary[1].amounts.ttc, Money::from_cents(1968 + 768));
+}
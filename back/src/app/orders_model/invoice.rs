@@ -0,0 +1,276 @@
+use std::env;
+
+use sqlx::{types::time::OffsetDateTime, Sqlite, SqlitePool, Transaction};
+
+use crate::{
+    app::money::{self, Money, VatSummaryLine},
+    errors::ServerError,
+    pdf::{PageWriter, BOLD, MARGIN, REGULAR},
+    utils::format_datetime,
+};
+
+use super::orders::{Order, OrderDetailElement, OrderId};
+
+/// what is printed on the invoice of a paid order
+pub struct Invoice {
+    pub number: String,
+    pub bar_name: String,
+    /// when the order was paid, the number belongs to the sequence of that year
+    pub paid_at: OffsetDateTime,
+    pub receipt: String,
+    pub lines: Vec<OrderDetailElement>,
+    pub vat_summary: Vec<VatSummaryLine>,
+    pub total_ht: Money,
+    pub total_ttc: Money,
+}
+
+impl Invoice {
+    /// None if the order was not paid yet
+    pub async fn for_order(
+        pool: &SqlitePool,
+        order: &Order,
+    ) -> Result<Option<Invoice>, ServerError> {
+        let (Some(receipt), Some(number)) = (&order.receipt, &order.invoice_number) else {
+            return Ok(None);
+        };
+        let paid_at = sqlx::query!(
+            "SELECT COALESCE(paid_at, timestamp) as \"paid_at!: OffsetDateTime\"
+            FROM Orders WHERE id = ?",
+            order.id
+        )
+        .fetch_one(pool)
+        .await?
+        .paid_at;
+        let lines = order.get_details(pool).await?;
+        let vat_summary = money::vat_summary(lines.iter().map(|d| (d.tva, d.amounts())));
+        Ok(Some(Invoice {
+            number: number.clone(),
+            bar_name: env::var("VITE_BAR_NAME").unwrap_or_default(),
+            paid_at,
+            receipt: receipt.to_string(),
+            total_ht: lines.iter().map(|d| d.subtotal_ht).sum(),
+            total_ttc: lines.iter().map(|d| d.subtotal_ttc).sum(),
+            lines,
+            vat_summary,
+        }))
+    }
+
+    pub fn file_name(order_id: OrderId) -> String {
+        format!("facture-{order_id}.pdf")
+    }
+
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut writer = PageWriter::new();
+        writer.text(BOLD, 18.0, MARGIN, &self.bar_name);
+        writer.skip(1.0);
+        writer.text(BOLD, 14.0, MARGIN, &format!("Facture n° {}", self.number));
+        writer.text(
+            REGULAR,
+            10.0,
+            MARGIN,
+            &format!("Date : {}", format_datetime(self.paid_at)),
+        );
+        writer.text(REGULAR, 10.0, MARGIN, &format!("Reçu : {}", self.receipt));
+        writer.skip(1.0);
+
+        let columns = [MARGIN, 300.0, 360.0, 420.0, 500.0];
+        writer.row(
+            BOLD,
+            &columns,
+            &["Article", "Qté", "TVA", "Total HT", "Total TTC"],
+        );
+        for line in &self.lines {
+            let mut name = line.item_name.clone();
+            if line.refunded_quantity > 0 {
+                name += &format!(" ({} remboursé)", line.refunded_quantity);
+            }
+            writer.row(
+                REGULAR,
+                &columns,
+                &[
+                    &name,
+                    &line.quantity.to_string(),
+                    &line.tva.to_string(),
+                    &line.subtotal_ht.to_string(),
+                    &line.subtotal_ttc.to_string(),
+                ],
+            );
+        }
+        writer.skip(0.5);
+        writer.row(
+            BOLD,
+            &columns,
+            &[
+                "Total",
+                "",
+                "",
+                &self.total_ht.to_string(),
+                &self.total_ttc.to_string(),
+            ],
+        );
+        writer.skip(1.0);
+
+        let columns = [MARGIN, 150.0, 250.0, 350.0];
+        writer.row(BOLD, &columns, &["Taux", "Base HT", "TVA", "TTC"]);
+        for line in &self.vat_summary {
+            writer.row(
+                REGULAR,
+                &columns,
+                &[
+                    &line.rate.to_string(),
+                    &line.amounts.ht.to_string(),
+                    &line.amounts.vat.to_string(),
+                    &line.amounts.ttc.to_string(),
+                ],
+            );
+        }
+        writer.finish()
+    }
+}
+
+/// takes the next number of the year of `paid_at`, to be called in the transaction that marks
+/// the order as paid at `paid_at`: sqlite serializes writing transactions so two orders cannot
+/// get the same number, and a rollback gives the number back
+pub(super) async fn next_invoice_number(
+    transaction: &mut Transaction<'_, Sqlite>,
+    paid_at: OffsetDateTime,
+) -> Result<String, ServerError> {
+    let year = paid_at.year();
+    let next = sqlx::query!(
+        "INSERT INTO InvoiceSequences (year, last_number) VALUES (?, 1)
+        ON CONFLICT (year) DO UPDATE SET last_number = last_number + 1
+        RETURNING year as \"year: i32\", last_number as \"last_number: u32\"",
+        year
+    )
+    .fetch_one(&mut **transaction)
+    .await?;
+    Ok(format!("{}-{:06}", next.year, next.last_number))
+}
+
+#[sqlx::test]
+async fn test_invoice(pool: SqlitePool) {
+    use crate::app::{
+        order_events::OrderEvents,
+        orders::{Cart, CartElement, PaymentMethod},
+    };
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id: 1,
+                quantity: 2,
+            },
+            CartElement {
+                variation_id: 7,
+                quantity: 1,
+            },
+        ],
+    };
+    let order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let invoice = Invoice::for_order(&pool, &order).await.unwrap().unwrap();
+    assert_eq!(invoice.total_ttc.cents(), 2 * 984 + 780);
+    assert_eq!(invoice.vat_summary.len(), 1);
+    assert_eq!(invoice.vat_summary[0].amounts.ttc, invoice.total_ttc);
+
+    let pdf = invoice.to_pdf();
+    assert!(pdf.starts_with(b"%PDF-"));
+    let pdf = String::from_utf8_lossy(&pdf);
+    assert!(pdf.contains("/Count 1"));
+    // strings are written as literals, in the WinAnsi encoding
+    assert!(pdf.contains("(Total)"));
+    assert!(pdf.contains("/WinAnsiEncoding"));
+}
+
+#[sqlx::test]
+async fn test_invoice_numbers(pool: SqlitePool) {
+    use crate::{
+        app::{
+            order_events::OrderEvents,
+            orders::{self, Cart, CartElement, PaymentMethod},
+        },
+        payment_provider::{PaymentProvider, TestPaymentProvider},
+    };
+    use std::sync::Arc;
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = || Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 1,
+        }],
+    };
+    let year = OffsetDateTime::now_utc().year();
+    let first = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart(),
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(first.invoice_number, Some(format!("{year}-000001")));
+
+    // a number taken in a rolled back transaction is given back
+    let mut transaction = pool.begin().await.unwrap();
+    next_invoice_number(&mut transaction, OffsetDateTime::now_utc())
+        .await
+        .unwrap();
+    transaction.rollback().await.unwrap();
+
+    let second = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart(),
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(second.invoice_number, Some(format!("{year}-000002")));
+
+    let filters = orders::OrderFilters {
+        invoice_number: Some("000002"),
+        ..Default::default()
+    };
+    let found = orders::search_orders(&pool, &filters, Default::default(), None, 10)
+        .await
+        .unwrap()
+        .orders;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, second.id);
+
+    // created on the last evening of the previous year, paid this year
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let order_id = Order::generate_from_cart(&pool, payment_provider, cart())
+        .await
+        .unwrap();
+    let last_evening = format!("{}-12-31 23:30:00", year - 1);
+    sqlx::query!(
+        "UPDATE Orders SET timestamp = ? WHERE id = ?",
+        last_evening,
+        order_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let mut late = Order::get(&pool, order_id).await.unwrap().unwrap();
+    late.mark_as_paid(&pool, &OrderEvents::new()).await.unwrap();
+    assert_eq!(late.invoice_number, Some(format!("{year}-000003")));
+    let invoice = Invoice::for_order(&pool, &late).await.unwrap().unwrap();
+    assert_eq!(invoice.paid_at.year(), year);
+}
@@ -12,7 +12,7 @@ use crate::{
     mail_manager::MailManager,
 };
 
-use super::orders::Order;
+use super::{invoice::Invoice, orders::Order};
 
 pub async fn send_qr(
     pool: &SqlitePool,
@@ -29,7 +29,7 @@ pub async fn send_qr(
         .clone()
         .ok_or_else(|| SendReceiptEmailError::NoReceipt)?;
 
-    let img = receipt.get_qr_code()?.render::<Luma<u8>>().build();
+    let img = receipt.get_qr_code(order.id)?.render::<Luma<u8>>().build();
     let mut res: Vec<u8> = vec![];
     png::PngEncoder::new(&mut res).write_image(
         &img,
@@ -38,37 +38,53 @@ pub async fn send_qr(
         image::ExtendedColorType::L8,
     )?;
     let attachment = Attachment::new("recu.png".to_owned()).body(res, "image/png".parse().unwrap());
+    let invoice = Invoice::for_order(pool, order)
+        .await?
+        .ok_or_else(|| SendReceiptEmailError::NoReceipt)?;
+    let invoice_attachment = Attachment::new(Invoice::file_name(order.id))
+        .body(invoice.to_pdf(), "application/pdf".parse().unwrap());
     let body = SinglePart::plain(format!(
         "Merci pour votre commande.
-Vous trouverez en pièce jointe le qr-code à montrer au bar.
+Vous trouverez en pièce jointe le qr-code à montrer au bar, ainsi que votre facture.
 
 Résumé de votre commande :
 {}
 
-Total: {}€
+Total: {}
+
+Dont TVA :
+{}
 
 
 Reçu: {}",
-        order
-            .get_details(pool)
-            .await?
+        invoice
+            .lines
+            .iter()
+            .map(|d| format!("{} x {} = {}", d.quantity, d.item_name, d.subtotal_ttc))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        invoice.total_ttc,
+        invoice
+            .vat_summary
             .iter()
-            .map(|d| format!(
-                "{} x {} = {}€",
-                d.quantity,
-                d.item_name,
-                d.subtotal_ttc / 100
+            .map(|line| format!(
+                "TVA {} : base HT {}, TVA {}, TTC {}",
+                line.rate, line.amounts.ht, line.amounts.vat, line.amounts.ttc
             ))
             .collect::<Vec<String>>()
             .join("\n"),
-        order.get_full_price_ttc(pool).await? / 100,
         *receipt
     ));
     let email = Message::builder()
         .to(to.clone())
         .from(mail_manager.get_sender()?)
         .subject("Merci pour votre commande")
-        .multipart(MultiPart::mixed().singlepart(body).singlepart(attachment))
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(body)
+                .singlepart(attachment)
+                .singlepart(invoice_attachment),
+        )
         .map_err(ServerError::EmailBuild)?;
     mail_manager.send_mail(email).await?;
     Ok(())
@@ -1,4 +1,7 @@
 //pub(crate) mod cart;
+pub(crate) mod invoice;
 pub(crate) mod mail;
+pub(crate) mod order_events;
 pub(crate) mod orders;
 pub(crate) mod receipt;
+pub(crate) mod refunds;
@@ -0,0 +1,40 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::app::orders::OrderId;
+
+/// the screens only need to know which order to refetch, a lagging subscriber just
+/// misses the oldest events
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OrderEvent {
+    /// a new order is ready to be prepared
+    Paid { order_id: OrderId },
+    /// something was handed over or refunded
+    ServingChanged { order_id: OrderId, served: bool },
+}
+
+#[derive(Clone)]
+pub struct OrderEvents {
+    sender: broadcast::Sender<OrderEvent>,
+}
+impl OrderEvents {
+    pub fn new() -> OrderEvents {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        OrderEvents { sender }
+    }
+    pub fn publish(&self, event: OrderEvent) {
+        // an error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<OrderEvent> {
+        self.sender.subscribe()
+    }
+}
+impl Default for OrderEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,25 +1,37 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
-use sqlx::{types::time::OffsetDateTime, Sqlite, SqlitePool, Transaction};
+use serde_json::{json, Value};
+use sqlx::{types::time::OffsetDateTime, QueryBuilder, Sqlite, SqlitePool, Transaction};
 use uuid::Uuid;
 
 use crate::{
+    admin::{
+        audit::{self, AuditAction, AuditTarget},
+        user::User,
+    },
     app::{
-        orders_model::mail,
+        invoice,
+        money::{Amounts, Money, VatRate},
+        order_events::{OrderEvent, OrderEvents},
         product_variations::Variation,
-        products,
+        products::{self, Product},
         receipt::Receipt,
-        stripe::{
-            self,
-            payment_intents::{PaymentIntent, PaymentIntentStatus},
-        },
+        stock_movements,
+        stripe::payment_intents::{PaymentIntent, PaymentIntentId, PaymentIntentStatus},
     },
     errors::{OrderManagementError, OrderProcessError, ServerError},
-    mail_manager::MailManager,
+    monitoring::{self, OrderStage},
+    outbox::{self, OutboxMessage},
+    payment_provider::PaymentProvider,
 };
 
 const ORDER_DURATION: Duration = Duration::from_secs(10 * 60 * 60);
+/// how long the stock of an unpaid order stays reserved, an order paid later gets its stock
+/// checked again
+const RESERVATION_DURATION: Duration = Duration::from_secs(5 * 60);
+/// how long a claimed order is kept from the other waiters
+const CLAIM_DURATION: Duration = Duration::from_secs(2 * 60);
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct CartElement {
@@ -32,32 +44,87 @@ pub struct Cart {
     pub elements: Vec<CartElement>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum PaymentMethod {
+    Stripe,
+    /// taken by a waiter at the counter
+    Cash,
+    /// taken by a waiter on a payment terminal that is not linked to the app
+    ExternalCard,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServingStatus {
+    Unserved,
+    PartiallyServed,
+    Served,
+}
+
+impl ServingStatus {
+    /// lines that were fully refunded have nothing left to serve
+    pub fn of(details: &[OrderDetailElement]) -> ServingStatus {
+        let served: u32 = details.iter().map(|d| d.served_quantity).sum();
+        let remaining: u32 = details.iter().map(|d| d.remaining_quantity).sum();
+        if remaining == 0 {
+            ServingStatus::Served
+        } else if served == 0 {
+            ServingStatus::Unserved
+        } else {
+            ServingStatus::PartiallyServed
+        }
+    }
+}
+
 pub type OrderId = u32;
+pub type OrderDetailId = u32;
 #[derive(Serialize)]
 pub struct OrderDetailElement {
+    pub detail_id: OrderDetailId,
     pub item_name: String,
     pub quantity: u32,
-    pub tva: f32,
-    pub subtotal_ht: i32,
-    pub subtotal_ttc: i32,
+    pub refunded_quantity: u32,
+    pub served_quantity: u32,
+    /// what is left to hand over to the customer
+    pub remaining_quantity: u32,
+    pub unit_price_ht: Money,
+    pub tva: VatRate,
+    pub subtotal_ht: Money,
+    pub subtotal_vat: Money,
+    pub subtotal_ttc: Money,
+}
+impl OrderDetailElement {
+    pub fn amounts(&self) -> Amounts {
+        Amounts {
+            ht: self.subtotal_ht,
+            vat: self.subtotal_vat,
+            ttc: self.subtotal_ttc,
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, sqlx::FromRow)]
 pub struct Order {
     pub id: OrderId,
     pub timestamp: OffsetDateTime,
     pub user_email: Option<String>,
     pub receipt: Option<Receipt>,
-    pub payment_intent_id: String,
+    /// assigned when the order is paid, `<year>-<number>` without gaps within a year
+    pub invoice_number: Option<String>,
+    pub payment_intent_id: Option<PaymentIntentId>,
+    pub payment_method: PaymentMethod,
     pub served: bool,
+    /// paid after its cancellation while the stock had run out, to refund or check by hand
+    pub needs_review: bool,
 }
 
 impl Order {
     pub async fn get(pool: &SqlitePool, id: OrderId) -> Result<Option<Order>, ServerError> {
-        cancel_expired_orders(pool);
         let order_opt = sqlx::query_as!(
             Order,
-            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", payment_intent_id, served as \"served!: bool\" from Orders WHERE id = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
+            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders WHERE id = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
             id
         )
         .fetch_optional(pool)
@@ -72,7 +139,7 @@ impl Order {
     ) -> Result<Option<Order>, ServerError> {
         let order_opt = sqlx::query_as!(
            Order,
-            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", payment_intent_id, served as \"served!: bool\" from Orders WHERE client_secret = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
+            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders WHERE client_secret = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
             client_secret
         )
         .fetch_optional(pool)
@@ -86,7 +153,7 @@ impl Order {
     ) -> Result<Option<Order>, ServerError> {
         let order_opt = sqlx::query_as!(
             Order,
-            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", payment_intent_id, served as \"served!: bool\" from Orders WHERE receipt = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
+            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders WHERE receipt = ? AND (expires > CURRENT_TIMESTAMP OR expires IS NULL)",
             receipt
         )
         .fetch_optional(pool)
@@ -94,31 +161,221 @@ impl Order {
         Ok(order_opt)
     }
 
+    pub async fn get_from_payment_intent_id(
+        pool: &SqlitePool,
+        payment_intent_id: &str,
+    ) -> Result<Option<Order>, ServerError> {
+        let order_opt = sqlx::query_as!(
+            Order,
+            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders WHERE payment_intent_id = ?",
+            payment_intent_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(order_opt)
+    }
+
     pub async fn set_email(&mut self, pool: &SqlitePool, email: &str) -> Result<(), ServerError> {
+        let mut transaction = pool.begin().await?;
         sqlx::query!(
             "UPDATE Orders SET user_email = ? WHERE id = ?",
             email,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *transaction)
         .await?;
+        self.enqueue_metadata(&mut transaction, "email", email)
+            .await?;
+        transaction.commit().await?;
         self.user_email = Some(email.to_owned());
-        stripe::api::push_metadata(&self.payment_intent_id, "email", email).await?;
         Ok(())
     }
 
-    pub async fn set_served(&mut self, pool: &SqlitePool, served: bool) -> Result<(), ServerError> {
-        println!("set_served {} {}", self.id, served);
-        sqlx::query!("UPDATE Orders SET served = ? WHERE id = ?", served, self.id)
-            .execute(pool)
+    /// takes the order for `waiter` before handing it over, so that a receipt shown at two
+    /// counters is only served once. Fails if the order was already served, or claimed by
+    /// another waiter less than `CLAIM_DURATION` ago; claiming again renews the claim
+    pub async fn claim_for_serving(
+        &self,
+        pool: &SqlitePool,
+        waiter: &User,
+    ) -> Result<(), OrderManagementError> {
+        let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+        claim(&mut transaction, self.id, waiter).await?;
+        audit::record(
+            &mut transaction,
+            waiter,
+            AuditAction::ClaimOrder,
+            AuditTarget::Order(self.id),
+            Value::Null,
+            Value::Null,
+        )
+        .await?;
+        transaction.commit().await.map_err(ServerError::Sqlx)?;
+        Ok(())
+    }
+
+    /// marking as served goes through the same claim as `claim_for_serving`, marking as
+    /// not served gives the order back to every waiter
+    pub async fn set_served(
+        &mut self,
+        pool: &SqlitePool,
+        order_events: &OrderEvents,
+        waiter: &User,
+        served: bool,
+    ) -> Result<(), OrderManagementError> {
+        let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+        if served {
+            claim(&mut transaction, self.id, waiter).await?;
+            sqlx::query!(
+                "UPDATE OrderDetails SET served_quantity = MAX(served_quantity, quantity - refunded_quantity)
+                WHERE order_id = ?",
+                self.id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::Sqlx)?;
+        } else {
+            sqlx::query!(
+                "UPDATE OrderDetails SET served_quantity = 0 WHERE order_id = ?",
+                self.id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::Sqlx)?;
+        }
+        sqlx::query!(
+            "UPDATE Orders SET
+                served = ?,
+                served_at = CASE WHEN ? THEN COALESCE(served_at, CURRENT_TIMESTAMP) END,
+                served_by = CASE WHEN ? THEN ? END,
+                claimed_by = CASE WHEN ? THEN claimed_by END,
+                claimed_at = CASE WHEN ? THEN claimed_at END
+            WHERE id = ?",
+            served,
+            served,
+            served,
+            waiter.id,
+            served,
+            served,
+            self.id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?;
+        self.enqueue_metadata(&mut transaction, "commande_servie", &served.to_string())
             .await?;
+        audit::record(
+            &mut transaction,
+            waiter,
+            AuditAction::SetServed,
+            AuditTarget::Order(self.id),
+            json!({ "served": self.served }),
+            json!({ "served": served }),
+        )
+        .await?;
+        transaction.commit().await.map_err(ServerError::Sqlx)?;
         self.served = served;
-        stripe::api::push_metadata(
-            &self.payment_intent_id,
-            "commande_servie",
-            &served.to_string(),
+        order_events.publish(OrderEvent::ServingChanged {
+            order_id: self.id,
+            served,
+        });
+        Ok(())
+    }
+
+    /// hands over `quantity` units of one line, the order is marked as served once every
+    /// line is either served or refunded
+    pub async fn serve_detail(
+        &mut self,
+        pool: &SqlitePool,
+        order_events: &OrderEvents,
+        waiter: &User,
+        detail_id: OrderDetailId,
+        quantity: u32,
+    ) -> Result<(), OrderManagementError> {
+        if self.receipt.is_none() {
+            return Err(OrderManagementError::OrderNotPaid);
+        }
+        if quantity == 0 {
+            return Err(OrderManagementError::InvalidServeQuantity(detail_id));
+        }
+        let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+        claim(&mut transaction, self.id, waiter).await?;
+        let updated = sqlx::query!(
+            "UPDATE OrderDetails SET served_quantity = served_quantity + ?
+            WHERE id = ? AND order_id = ? AND quantity - refunded_quantity - served_quantity >= ?",
+            quantity,
+            detail_id,
+            self.id,
+            quantity
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?
+        .rows_affected();
+        if updated == 0 {
+            let exists = sqlx::query!(
+                "SELECT id FROM OrderDetails WHERE id = ? AND order_id = ?",
+                detail_id,
+                self.id
+            )
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(ServerError::Sqlx)?
+            .is_some();
+            return Err(if exists {
+                OrderManagementError::InvalidServeQuantity(detail_id)
+            } else {
+                OrderManagementError::OrderDetailNotFound(detail_id)
+            });
+        }
+        let served = update_served_flag(&mut transaction, self.id).await?;
+        if served {
+            sqlx::query!(
+                "UPDATE Orders SET served_by = ? WHERE id = ?",
+                waiter.id,
+                self.id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::Sqlx)?;
+        }
+        if served != self.served {
+            self.enqueue_metadata(&mut transaction, "commande_servie", &served.to_string())
+                .await?;
+        }
+        audit::record(
+            &mut transaction,
+            waiter,
+            AuditAction::ServeDetail,
+            AuditTarget::Order(self.id),
+            Value::Null,
+            json!({ "detail_id": detail_id, "quantity": quantity }),
         )
         .await?;
+        transaction.commit().await.map_err(ServerError::Sqlx)?;
+        self.served = served;
+        order_events.publish(OrderEvent::ServingChanged {
+            order_id: self.id,
+            served,
+        });
+        Ok(())
+    }
+
+    /// orders paid at the counter have no payment intent to annotate
+    async fn enqueue_metadata(
+        &self,
+        transaction: &mut Transaction<'_, Sqlite>,
+        key: &str,
+        value: &str,
+    ) -> Result<(), ServerError> {
+        if let Some(payment_intent_id) = &self.payment_intent_id {
+            let message = OutboxMessage::StripeMetadata {
+                payment_intent_id: payment_intent_id.clone(),
+                key: key.to_owned(),
+                value: value.to_owned(),
+            };
+            outbox::enqueue(transaction, &message).await?;
+        }
         Ok(())
     }
 
@@ -126,292 +383,1805 @@ impl Order {
         &self,
         pool: &SqlitePool,
     ) -> Result<Vec<OrderDetailElement>, ServerError> {
-        let detail = sqlx::query!(
-            "SELECT
-                item_name,
-                quantity as \"quantity: u32\",
-                tva as \"tva: f32\",
-                unit_price_ht as \"unit_price_ht: i32\"
-            FROM OrderDetails
-            WHERE order_id = ?
-                AND quantity != 0",
-            self.id
-        )
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|e| OrderDetailElement {
-            item_name: e.item_name,
-            quantity: e.quantity,
-            tva: e.tva,
-            subtotal_ht: e.unit_price_ht * e.quantity as i32,
-            subtotal_ttc: e.unit_price_ht * e.quantity as i32 * (1.0 + e.tva).round() as i32,
-        })
-        .collect();
-
-        Ok(detail)
+        let mut details = get_details_of_orders(pool, &[self.id]).await?;
+        Ok(details.remove(&self.id).unwrap_or_default())
     }
 
-    async fn mark_as_paid(
+    /// idempotent: both the webhook and the customer polling the payment status can
+    /// reach this, only the first one to set the receipt does the rest of the work
+    pub async fn mark_as_paid(
         &mut self,
         pool: &SqlitePool,
-        mail_manager: Arc<Box<dyn MailManager>>,
+        order_events: &OrderEvents,
     ) -> Result<(), ServerError> {
         let receipt = Uuid::new_v4().to_string();
-        sqlx::query!(
-            "UPDATE Orders SET receipt = ?, expires = NULL WHERE id = ?",
+        let paid_at = OffsetDateTime::now_utc();
+        let mut transaction = pool.begin().await?;
+        let updated = sqlx::query!(
+            "UPDATE Orders SET receipt = ?, paid_at = datetime(?), expires = NULL
+            WHERE id = ? AND receipt IS NULL",
             receipt,
+            paid_at,
             self.id
         )
-        .execute(pool)
-        .await?;
-        stripe::api::push_metadata(&self.payment_intent_id, "reçu", &receipt).await?;
-        self.receipt = Some(Receipt(receipt));
-
-        let self_thread = self.clone();
-        let pool_thread = pool.to_owned();
-        tokio::spawn(async move {
-            if let Err(e) = mail::send_qr(&pool_thread, mail_manager, &self_thread).await {
-                eprintln!("error while sending receipt mail : {e:?}")
-            }
-        });
-        type ProductId = u32;
-        type Volume = f32;
-        let detail: Vec<(ProductId, Volume)> = sqlx::query!(
-            "SELECT product_id as \"product_id: u32\", quantity, variation_volume as \"variation_volume: f32\"
-            FROM OrderDetails
-            WHERE order_id = ?",
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            transaction.rollback().await?;
+            let paid = sqlx::query!(
+                "SELECT receipt, invoice_number FROM Orders WHERE id = ?",
+                self.id
+            )
+            .fetch_one(pool)
+            .await?;
+            self.receipt = paid.receipt.map(Receipt);
+            self.invoice_number = paid.invoice_number;
+            return Ok(());
+        }
+        // a canceled order, or one whose reservation expired, already gave its stock back,
+        // it may have been sold since
+        let was_released = sqlx::query!(
+            "SELECT reserved_until IS NULL as \"released!: bool\" FROM Orders WHERE id = ?",
             self.id
         )
-        .fetch_all(pool)
+        .fetch_one(&mut *transaction)
         .await?
-        .into_iter()
-        .map(|r| (r.product_id, r.quantity as f32 * r.variation_volume))
-        .collect();
-        let mut pool_transaction: Transaction<'static, Sqlite> =
-            pool.begin().await.map_err(ServerError::Sqlx)?;
-
-        for (product_id, volume) in detail {
+        .released;
+        let needs_review = was_released && !is_still_in_stock(&mut transaction, self.id).await?;
+        if needs_review {
+            tracing::warn!(
+                order_id = self.id,
+                "order paid after its cancellation without enough stock left, to refund"
+            );
+        } else {
+            let reservation_factor = if was_released { 0 } else { 1 };
             sqlx::query!(
-                "UPDATE Products SET stock_quantity = stock_quantity - ? WHERE id = ?",
-                volume,
-                product_id
+                "UPDATE Products SET
+                    stock_quantity = stock_quantity - ordered.volume,
+                    reserved_quantity = reserved_quantity - ordered.volume * ?
+                FROM (
+                    SELECT product_id, SUM(quantity * variation_volume) as volume
+                    FROM OrderDetails WHERE order_id = ? GROUP BY product_id
+                ) AS ordered
+                WHERE Products.id = ordered.product_id",
+                reservation_factor,
+                self.id
             )
-            .execute(&mut *pool_transaction)
-            .await
-            .map_err(ServerError::Sqlx)?;
+            .execute(&mut *transaction)
+            .await?;
+            stock_movements::record_sale(&mut transaction, self.id).await?;
         }
-
-        pool_transaction.commit().await.map_err(ServerError::Sqlx)?;
+        let invoice_number = invoice::next_invoice_number(&mut transaction, paid_at).await?;
+        sqlx::query!(
+            "UPDATE Orders SET canceled = FALSE, reserved_until = NULL, needs_review = ?,
+                invoice_number = ?
+            WHERE id = ?",
+            needs_review,
+            invoice_number,
+            self.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        self.enqueue_metadata(&mut transaction, "reçu", &receipt)
+            .await?;
+        outbox::enqueue(
+            &mut transaction,
+            &OutboxMessage::ReceiptMail { order_id: self.id },
+        )
+        .await?;
+        transaction.commit().await?;
+        self.receipt = Some(Receipt(receipt));
+        self.invoice_number = Some(invoice_number);
+        self.needs_review = needs_review;
+        monitoring::count_order(OrderStage::Paid);
+        order_events.publish(OrderEvent::Paid { order_id: self.id });
         Ok(())
     }
 
     pub async fn generate_from_cart(
         pool: &SqlitePool,
+        payment_provider: Arc<Box<dyn PaymentProvider>>,
         cart: Cart,
     ) -> Result<OrderId, OrderProcessError> {
         let products = products::get_all(pool).await?;
         let variations = Variation::get_all(pool).await?;
-        let mut total_price: i32 = 0;
-        for cart_element in &cart.elements {
-            let variation = variations
-                .iter()
-                .find(|e| e.id == cart_element.variation_id)
-                .ok_or(OrderProcessError::VariationNotFound(
-                    cart_element.variation_id,
-                ))?;
-            let product = products
-                .iter()
-                .find(|e| e.id == variation.product_id)
-                .ok_or(OrderProcessError::ProductNotFound(variation.product_id))?;
-
-            if product.stock_quantity as f32 >= cart_element.quantity as f32 * variation.volume {
-                total_price += (variation.price_ht as f32 * (1f32 + variation.tva)) as i32
-                    * cart_element.quantity as i32;
-            } else {
+        let (lines, total_price) = resolve_cart(&products, &variations, &cart)?;
+
+        let payment_intent = payment_provider
+            .create_payment_intent(total_price.cents())
+            .await?;
+        let order_id = match insert_order_reserving_stock(pool, &payment_intent, &lines).await {
+            Ok(order_id) => order_id,
+            Err(e) => {
+                if let Err(cancel_error) = payment_provider
+                    .cancel_payment_intent(&payment_intent.id)
+                    .await
+                {
+                    tracing::error!("could not cancel unused payment intent: {cancel_error:?}");
+                }
+                return Err(e);
+            }
+        };
+        monitoring::count_order(OrderStage::Created);
+        Ok(order_id)
+    }
+
+    /// the waiter already took the payment: the order is paid right away and the stock is
+    /// decremented without going through a reservation
+    pub async fn generate_from_counter(
+        pool: &SqlitePool,
+        order_events: &OrderEvents,
+        waiter: &User,
+        cart: Cart,
+        payment_method: PaymentMethod,
+    ) -> Result<Order, OrderProcessError> {
+        if payment_method == PaymentMethod::Stripe {
+            return Err(OrderProcessError::InvalidPaymentMethod);
+        }
+        let products = products::get_all(pool).await?;
+        let variations = Variation::get_all(pool).await?;
+        let (lines, total_price) = resolve_cart(&products, &variations, &cart)?;
+
+        let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+        for (product, variation, quantity) in &lines {
+            let volume = *quantity as f32 * variation.volume;
+            let taken = sqlx::query!(
+                "UPDATE Products SET stock_quantity = stock_quantity - ?
+                WHERE id = ? AND stock_quantity - reserved_quantity >= ?",
+                volume,
+                product.id,
+                volume
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::Sqlx)?
+            .rows_affected();
+            if taken == 0 {
                 return Err(OrderProcessError::NotEnoughStock(
                     product.name.clone(),
                     product.id,
                 ));
             }
         }
-
-        let payment_intent = stripe::api::create_payment_intent(total_price as i64).await?;
-        let expires = OffsetDateTime::now_utc() + ORDER_DURATION;
+        let receipt = Uuid::new_v4().to_string();
+        let paid_at = OffsetDateTime::now_utc();
+        let invoice_number = invoice::next_invoice_number(&mut transaction, paid_at).await?;
         let order_id = sqlx::query!(
-            "INSERT INTO Orders (expires, payment_intent_id, client_secret) VALUES (?, ?, ?)",
-            expires,
-            payment_intent.id,
-            payment_intent.client_secret
+            "INSERT INTO Orders (payment_method, receipt, invoice_number, paid_at)
+            VALUES (?, ?, ?, datetime(?))",
+            payment_method,
+            receipt,
+            invoice_number,
+            paid_at
         )
-        .execute(pool)
+        .execute(&mut *transaction)
         .await
         .map_err(ServerError::Sqlx)?
         .last_insert_rowid() as u32;
-        stripe::api::push_metadata(&payment_intent.id, "order_id", &order_id.to_string()).await?;
-        for cart_element in cart.elements.iter().filter(|e| e.quantity > 0) {
-            let variation = variations
-                .iter()
-                .find(|e| e.id == cart_element.variation_id)
-                .ok_or(OrderProcessError::VariationNotFound(
-                    cart_element.variation_id,
-                ))?;
-            let product = products
-                .iter()
-                .find(|e| e.id == variation.product_id)
-                .ok_or(OrderProcessError::ProductNotFound(variation.product_id))?;
-            let item_name = if variation.name.is_empty() {
-                product.name.clone()
-            } else {
-                format!("{} ({})", product.name, variation.name)
-            };
-            sqlx::query!(
-                "INSERT INTO OrderDetails(
-                    order_id,
-                    product_id,
-                    item_name,
-                    unit_price_ht,
-                    tva,
-                    quantity,
-                    variation_volume
-                    ) VALUES (?, ?, ?, ?, ?, ?, ?)",
-                order_id,
-                variation.product_id,
-                item_name,
-                variation.price_ht,
-                variation.tva,
-                cart_element.quantity,
-                variation.volume
-            )
-            .execute(pool)
-            .await
-            .map_err(ServerError::Sqlx)?;
-        }
-        let pool = pool.to_owned();
-        tokio::spawn(async move {
-            let order = Order::get(&pool, order_id)
-                .await
-                .expect("could not fetch order while setting details metadatas");
-            if let Some(order) = order {
-                let details = order
-                    .get_details(&pool)
-                    .await
-                    .expect("could not fetch details while setting details metadatas");
-                for detail in details {
-                    stripe::api::push_metadata(
-                        &order.payment_intent_id,
-                        &format!("produit: {}", detail.item_name),
-                        &format!("quantité : {}", detail.quantity),
-                    )
-                    .await
-                    .expect("could not set metadata");
-                }
-            }
-        });
-        Ok(order_id)
+        insert_details(&mut transaction, order_id, &lines).await?;
+        stock_movements::record_sale(&mut transaction, order_id).await?;
+        audit::record(
+            &mut transaction,
+            waiter,
+            AuditAction::CreateCounterOrder,
+            AuditTarget::Order(order_id),
+            Value::Null,
+            json!({ "payment_method": payment_method, "total_price_ttc": total_price }),
+        )
+        .await?;
+        transaction.commit().await.map_err(ServerError::Sqlx)?;
+        monitoring::count_order(OrderStage::Created);
+        monitoring::count_order(OrderStage::Paid);
+        order_events.publish(OrderEvent::Paid { order_id });
+
+        let order = sqlx::query_as!(
+            Order,
+            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders WHERE id = ?",
+            order_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(ServerError::Sqlx)?;
+        Ok(order)
     }
+
+    /// `None` for orders paid at the counter
     pub async fn get_payment_intent(
         &mut self,
         pool: &SqlitePool,
-        mail_manager: Arc<Box<dyn MailManager>>,
-    ) -> Result<PaymentIntent, ServerError> {
-        let intent = stripe::api::fetch_payment_intent(&self.payment_intent_id).await?;
+        payment_provider: Arc<Box<dyn PaymentProvider>>,
+        order_events: &OrderEvents,
+    ) -> Result<Option<PaymentIntent>, ServerError> {
+        let Some(payment_intent_id) = &self.payment_intent_id else {
+            return Ok(None);
+        };
+        let intent = payment_provider
+            .fetch_payment_intent(payment_intent_id)
+            .await?;
         if intent.status == PaymentIntentStatus::Succeeded {
-            let receipt = sqlx::query!("SELECT receipt FROM Orders WHERE id = ?", self.id)
-                .fetch_one(pool)
-                .await?
-                .receipt;
-            if receipt.is_none() {
-                self.mark_as_paid(pool, mail_manager).await?;
-            }
+            self.mark_as_paid(pool, order_events).await?;
         }
-        Ok(intent)
+        Ok(Some(intent))
     }
-    pub async fn get_full_price_ht(&self, pool: &SqlitePool) -> Result<i32, ServerError> {
-        let total = sqlx::query!(
-            "SELECT cast(SUM(unit_price_ht * quantity) as int) as result
-            FROM OrderDetails WHERE order_id = ?;",
-            self.id
-        )
-        .fetch_one(pool)
-        .await
-        .map_err(ServerError::Sqlx)?;
 
-        let res = total
-            .result
-            .ok_or(ServerError::Sqlx(sqlx::Error::RowNotFound))?;
-        Ok(res as i32)
+    pub async fn mark_as_canceled(&mut self, pool: &SqlitePool) -> Result<(), ServerError> {
+        if cancel_and_release_stock(pool, self.id).await? {
+            monitoring::count_order(OrderStage::Canceled);
+        }
+        Ok(())
     }
-    pub async fn get_full_price_ttc(&self, pool: &SqlitePool) -> Result<i32, ServerError> {
-        let total = sqlx::query!(
-            "SELECT cast(SUM(unit_price_ht * (1 + tva) * quantity) as int) as result
-            FROM OrderDetails WHERE order_id = ?;",
-            self.id
-        )
-        .fetch_one(pool)
-        .await
-        .map_err(ServerError::Sqlx)?;
+}
+
+/// the lines of several orders with a single query, orders without lines are left out
+pub async fn get_details_of_orders(
+    pool: &SqlitePool,
+    order_ids: &[OrderId],
+) -> Result<HashMap<OrderId, Vec<OrderDetailElement>>, ServerError> {
+    // sqlite cannot bind a list, the ids are sent as a json array instead
+    let order_ids = serde_json::to_string(order_ids).expect("ids are always serializable");
+    let rows = sqlx::query!(
+        "SELECT
+            id as \"id: u32\",
+            order_id as \"order_id: u32\",
+            item_name,
+            quantity as \"quantity: u32\",
+            refunded_quantity as \"refunded_quantity: u32\",
+            served_quantity as \"served_quantity: u32\",
+            tva as \"tva: f32\",
+            unit_price_ht as \"unit_price_ht: i64\"
+        FROM OrderDetails
+        WHERE order_id IN (SELECT value FROM json_each(?))
+            AND quantity != 0
+        ORDER BY id",
+        order_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut details: HashMap<OrderId, Vec<OrderDetailElement>> = HashMap::new();
+    for e in rows {
+        let unit_price_ht = Money::from_cents(e.unit_price_ht);
+        let tva = VatRate::from_fraction(e.tva);
+        let amounts = Amounts::for_line(unit_price_ht, e.quantity, tva);
+        details
+            .entry(e.order_id)
+            .or_default()
+            .push(OrderDetailElement {
+                detail_id: e.id,
+                item_name: e.item_name,
+                quantity: e.quantity,
+                refunded_quantity: e.refunded_quantity,
+                served_quantity: e.served_quantity,
+                remaining_quantity: e
+                    .quantity
+                    .saturating_sub(e.refunded_quantity + e.served_quantity),
+                unit_price_ht,
+                tva,
+                subtotal_ht: amounts.ht,
+                subtotal_vat: amounts.vat,
+                subtotal_ttc: amounts.ttc,
+            });
+    }
+    Ok(details)
+}
+
+/// `canceled` includes the orders canceled because they expired
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    #[default]
+    Paid,
+    /// waiting for the customer to pay
+    Pending,
+    Canceled,
+    Expired,
+}
+
+/// orders are created in the order of their ids, which is the order of their timestamps
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSort {
+    #[default]
+    Newest,
+    Oldest,
+    AmountDesc,
+    AmountAsc,
+}
 
-        let res = total
-            .result
-            .ok_or(ServerError::Sqlx(sqlx::Error::RowNotFound))?;
-        Ok(res as i32)
+/// what `search_orders` looks for, only paid orders by default
+#[derive(Default)]
+pub struct OrderFilters<'a> {
+    /// part of the address
+    pub email: Option<&'a str>,
+    pub date_begin: Option<OffsetDateTime>,
+    pub date_end: Option<OffsetDateTime>,
+    /// beginning of the receipt
+    pub receipt: Option<&'a str>,
+    /// part of the number
+    pub invoice_number: Option<&'a str>,
+    pub status: OrderStatus,
+    pub served: Option<bool>,
+    pub needs_review: Option<bool>,
+    /// bounds of the TTC total, included
+    pub min_amount: Option<Money>,
+    pub max_amount: Option<Money>,
+    /// orders with at least one line of this product
+    pub product_id: Option<u32>,
+}
+
+/// where a page of search results stopped, the next page starts right after it. Sent to
+/// the client as `<total_ttc>_<id>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchCursor {
+    total_ttc: i64,
+    id: OrderId,
+}
+impl fmt::Display for SearchCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.total_ttc, self.id)
+    }
+}
+impl FromStr for SearchCursor {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (total_ttc, id) = s.split_once('_').ok_or(())?;
+        Ok(SearchCursor {
+            total_ttc: total_ttc.parse().map_err(|_| ())?,
+            id: id.parse().map_err(|_| ())?,
+        })
     }
 }
 
+pub struct SearchPage {
+    pub orders: Vec<Order>,
+    /// None on the last page
+    pub next_cursor: Option<SearchCursor>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    order: Order,
+    total_ttc: i64,
+}
+
+/// `%` and `_` typed by the user are matched literally
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// the dates are bound as RFC 3339 strings, `datetime` brings them to the format of
+/// `CURRENT_TIMESTAMP` so that they can be compared with the stored timestamps. Both bounds
+/// are included, with a one second precision. At most `limit` orders are returned, starting
+/// after `after`
 pub async fn search_orders(
     pool: &SqlitePool,
-    email: Option<&str>,
-    date_begin: Option<OffsetDateTime>,
-    date_end: Option<OffsetDateTime>,
-    receipt: Option<&str>,
-) -> Result<Vec<Order>, ServerError> {
-    let email = email.unwrap_or("");
-    let receipt = receipt.unwrap_or("");
-    let date_begin = date_begin.unwrap_or(OffsetDateTime::UNIX_EPOCH);
-    let orders = if let Some(date_end) = date_end {
-        sqlx::query_as!(
-            Order,
-            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", payment_intent_id, served as \"served!: bool\"  from Orders
-            WHERE receipt IS NOT NULL AND user_email LIKE CONCAT('%', ?, '%') AND receipt LIKE CONCAT('%', ?, '%') AND timestamp > ? AND timestamp < ? ORDER BY timestamp DESC",
-            email,
-            receipt,
-            date_begin,
-            date_end
-        ).fetch_all(pool).await?
+    filters: &OrderFilters<'_>,
+    sort: OrderSort,
+    after: Option<SearchCursor>,
+    limit: u32,
+) -> Result<SearchPage, ServerError> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT id, timestamp, user_email, receipt, invoice_number, payment_intent_id,
+            payment_method, served, needs_review, total_ttc
+        FROM Orders WHERE ",
+    );
+    query.push(match filters.status {
+        OrderStatus::Paid => "receipt IS NOT NULL",
+        OrderStatus::Pending => {
+            "receipt IS NULL AND canceled = FALSE AND datetime(expires) > CURRENT_TIMESTAMP"
+        }
+        OrderStatus::Canceled => "receipt IS NULL AND canceled = TRUE",
+        OrderStatus::Expired => "receipt IS NULL AND datetime(expires) <= CURRENT_TIMESTAMP",
+    });
+    if let Some(email) = filters.email {
+        query
+            .push(" AND user_email LIKE ")
+            .push_bind(format!("%{}%", escape_like(email)))
+            .push(" ESCAPE '\\'");
+    }
+    if let Some(receipt) = filters.receipt {
+        query
+            .push(" AND receipt LIKE ")
+            .push_bind(format!("{}%", escape_like(receipt)))
+            .push(" ESCAPE '\\'");
+    }
+    if let Some(invoice_number) = filters.invoice_number {
+        query
+            .push(" AND invoice_number LIKE ")
+            .push_bind(format!("%{}%", escape_like(invoice_number)))
+            .push(" ESCAPE '\\'");
+    }
+    if let Some(date_begin) = filters.date_begin {
+        query
+            .push(" AND timestamp >= datetime(")
+            .push_bind(date_begin)
+            .push(")");
+    }
+    if let Some(date_end) = filters.date_end {
+        query
+            .push(" AND timestamp <= datetime(")
+            .push_bind(date_end)
+            .push(")");
+    }
+    if let Some(served) = filters.served {
+        query.push(" AND served = ").push_bind(served);
+    }
+    if let Some(needs_review) = filters.needs_review {
+        query.push(" AND needs_review = ").push_bind(needs_review);
+    }
+    if let Some(min_amount) = filters.min_amount {
+        query
+            .push(" AND total_ttc >= ")
+            .push_bind(min_amount.cents());
+    }
+    if let Some(max_amount) = filters.max_amount {
+        query
+            .push(" AND total_ttc <= ")
+            .push_bind(max_amount.cents());
+    }
+    if let Some(product_id) = filters.product_id {
+        query
+            .push(" AND EXISTS (SELECT 1 FROM OrderDetails WHERE order_id = Orders.id AND product_id = ")
+            .push_bind(product_id)
+            .push(")");
+    }
+    if let Some(after) = after {
+        match sort {
+            OrderSort::Newest => query.push(" AND id < ").push_bind(after.id),
+            OrderSort::Oldest => query.push(" AND id > ").push_bind(after.id),
+            OrderSort::AmountDesc => query
+                .push(" AND (total_ttc < ")
+                .push_bind(after.total_ttc)
+                .push(" OR (total_ttc = ")
+                .push_bind(after.total_ttc)
+                .push(" AND id < ")
+                .push_bind(after.id)
+                .push("))"),
+            OrderSort::AmountAsc => query
+                .push(" AND (total_ttc > ")
+                .push_bind(after.total_ttc)
+                .push(" OR (total_ttc = ")
+                .push_bind(after.total_ttc)
+                .push(" AND id > ")
+                .push_bind(after.id)
+                .push("))"),
+        };
+    }
+    query.push(match sort {
+        OrderSort::Newest => " ORDER BY id DESC",
+        OrderSort::Oldest => " ORDER BY id ASC",
+        OrderSort::AmountDesc => " ORDER BY total_ttc DESC, id DESC",
+        OrderSort::AmountAsc => " ORDER BY total_ttc ASC, id ASC",
+    });
+    // one more row tells whether there is a next page
+    query.push(" LIMIT ").push_bind(limit + 1);
+
+    let mut rows: Vec<SearchRow> = query.build_query_as().fetch_all(pool).await?;
+    let next_cursor = if rows.len() > limit as usize {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| SearchCursor {
+            total_ttc: row.total_ttc,
+            id: row.order.id,
+        })
     } else {
-        sqlx::query_as!(
-            Order,
-            "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", payment_intent_id, served as \"served!: bool\" from Orders
-            WHERE user_email LIKE CONCAT('%', ?, '%') AND receipt LIKE CONCAT('%', ?, '%') AND timestamp > ? ORDER BY timestamp DESC",
-            email,
-            receipt,
-            date_begin,
-        ).fetch_all(pool).await?
+        None
     };
+    Ok(SearchPage {
+        orders: rows.into_iter().map(|row| row.order).collect(),
+        next_cursor,
+    })
+}
+
+/// paid orders of the last day that still have something to hand over, oldest first
+pub async fn get_preparation_queue(pool: &SqlitePool) -> Result<Vec<Order>, ServerError> {
+    let orders = sqlx::query_as!(
+        Order,
+        "SELECT id as \"id: u32\", timestamp, user_email, receipt as \"receipt: Receipt\", invoice_number, payment_intent_id, payment_method as \"payment_method: PaymentMethod\", served as \"served!: bool\", needs_review as \"needs_review!: bool\" from Orders
+        WHERE receipt IS NOT NULL AND served = FALSE AND timestamp > datetime('now', '-1 day') ORDER BY timestamp ASC"
+    )
+    .fetch_all(pool)
+    .await?;
     Ok(orders)
 }
 
-pub fn cancel_expired_orders(pool: &SqlitePool) {
-    let pool = pool.to_owned();
-    tokio::spawn(async move {
-        let expired_payment_intents =
-            sqlx::query!("SELECT payment_intent_id from Orders WHERE expires < CURRENT_TIMESTAMP AND canceled = FALSE AND receipt IS NULL")
-                .fetch_all(&pool)
-                .await
-                .unwrap();
+type CartLine<'a> = (&'a Product, &'a Variation, u32);
+
+/// checks that every element exists and is in stock, and computes the total price (ttc) the
+/// same way the order lines are computed in `Order::get_details`
+fn resolve_cart<'a>(
+    products: &'a [Product],
+    variations: &'a [Variation],
+    cart: &Cart,
+) -> Result<(Vec<CartLine<'a>>, Money), OrderProcessError> {
+    let mut total_price = Money::ZERO;
+    let mut lines: Vec<CartLine> = vec![];
+    for cart_element in cart.elements.iter().filter(|e| e.quantity > 0) {
+        let variation = variations
+            .iter()
+            .find(|e| e.id == cart_element.variation_id)
+            .ok_or(OrderProcessError::VariationNotFound(
+                cart_element.variation_id,
+            ))?;
+        let product = products
+            .iter()
+            .find(|e| e.id == variation.product_id)
+            .ok_or(OrderProcessError::ProductNotFound(variation.product_id))?;
+
+        // early check to avoid creating a payment intent for nothing, the update made in a
+        // transaction when inserting the order is the one that counts
+        let already_in_cart: f32 = lines
+            .iter()
+            .filter(|(p, _, _)| p.id == product.id)
+            .map(|(_, v, quantity)| *quantity as f32 * v.volume)
+            .sum();
+        if product.stock_quantity - product.reserved_quantity
+            < already_in_cart + cart_element.quantity as f32 * variation.volume
+        {
+            return Err(OrderProcessError::NotEnoughStock(
+                product.name.clone(),
+                product.id,
+            ));
+        }
+        total_price += Amounts::for_line(
+            Money::from_cents(variation.price_ht as i64),
+            cart_element.quantity,
+            VatRate::from_fraction(variation.tva),
+        )
+        .ttc;
+        lines.push((product, variation, cart_element.quantity));
+    }
+    Ok((lines, total_price))
+}
+
+fn item_name(product: &Product, variation: &Variation) -> String {
+    if variation.name.is_empty() {
+        product.name.clone()
+    } else {
+        format!("{} ({})", product.name, variation.name)
+    }
+}
+
+async fn insert_details(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+    lines: &[CartLine<'_>],
+) -> Result<(), ServerError> {
+    let mut total = Money::ZERO;
+    for (product, variation, quantity) in lines {
+        let item_name = item_name(product, variation);
+        total += Amounts::for_line(
+            Money::from_cents(variation.price_ht as i64),
+            *quantity,
+            VatRate::from_fraction(variation.tva),
+        )
+        .ttc;
+        sqlx::query!(
+            "INSERT INTO OrderDetails(
+                order_id,
+                product_id,
+                item_name,
+                unit_price_ht,
+                tva,
+                quantity,
+                variation_volume
+                ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            order_id,
+            variation.product_id,
+            item_name,
+            variation.price_ht,
+            variation.tva,
+            quantity,
+            variation.volume
+        )
+        .execute(&mut **transaction)
+        .await?;
+    }
+    let total = total.cents();
+    sqlx::query!(
+        "UPDATE Orders SET total_ttc = ? WHERE id = ?",
+        total,
+        order_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// every statement runs in one transaction: if any line is out of stock nothing is written
+async fn insert_order_reserving_stock(
+    pool: &SqlitePool,
+    payment_intent: &PaymentIntent,
+    lines: &[CartLine<'_>],
+) -> Result<OrderId, OrderProcessError> {
+    let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+    for (product, variation, quantity) in lines {
+        let volume = *quantity as f32 * variation.volume;
+        let reserved = sqlx::query!(
+            "UPDATE Products SET reserved_quantity = reserved_quantity + ?
+            WHERE id = ? AND stock_quantity - reserved_quantity >= ?",
+            volume,
+            product.id,
+            volume
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?
+        .rows_affected();
+        if reserved == 0 {
+            return Err(OrderProcessError::NotEnoughStock(
+                product.name.clone(),
+                product.id,
+            ));
+        }
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let expires = now + ORDER_DURATION;
+    let reserved_until = now + RESERVATION_DURATION;
+    let order_id = sqlx::query!(
+        "INSERT INTO Orders (expires, reserved_until, payment_intent_id, client_secret)
+        VALUES (?, ?, ?, ?)",
+        expires,
+        reserved_until,
+        payment_intent.id,
+        payment_intent.client_secret
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::Sqlx)?
+    .last_insert_rowid() as u32;
+    insert_details(&mut transaction, order_id, lines).await?;
+    let mut metadata = vec![("order_id".to_owned(), order_id.to_string())];
+    for (product, variation, quantity) in lines {
+        metadata.push((
+            format!("produit: {}", item_name(product, variation)),
+            format!("quantité : {quantity}"),
+        ));
+    }
+    for (key, value) in metadata {
+        let message = OutboxMessage::StripeMetadata {
+            payment_intent_id: payment_intent.id.clone(),
+            key,
+            value,
+        };
+        outbox::enqueue(&mut transaction, &message).await?;
+    }
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
+    Ok(order_id)
+}
+
+/// keeps `Orders.served` and `Orders.served_at` in sync with the served quantities of the
+/// lines, returns the new value
+pub(super) async fn update_served_flag(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+) -> Result<bool, ServerError> {
+    let served = sqlx::query!(
+        "UPDATE Orders SET served = (
+            SELECT COALESCE(SUM(MAX(quantity - refunded_quantity - served_quantity, 0)), 0) = 0
+            FROM OrderDetails WHERE order_id = ?
+        )
+        WHERE id = ?
+        RETURNING served as \"served!: bool\"",
+        order_id,
+        order_id
+    )
+    .fetch_one(&mut **transaction)
+    .await?
+    .served;
+    sqlx::query!(
+        "UPDATE Orders SET served_at = CASE WHEN served THEN COALESCE(served_at, CURRENT_TIMESTAMP) END
+        WHERE id = ?",
+        order_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(served)
+}
+
+/// claims the order in `transaction`, or tells who is already serving it
+async fn claim(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+    waiter: &User,
+) -> Result<(), OrderManagementError> {
+    let expired_before = OffsetDateTime::now_utc() - CLAIM_DURATION;
+    let claimed = sqlx::query!(
+        "UPDATE Orders SET claimed_by = ?, claimed_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND receipt IS NOT NULL AND NOT served
+            AND (claimed_by IS NULL OR claimed_by = ? OR claimed_at <= datetime(?))",
+        waiter.id,
+        order_id,
+        waiter.id,
+        expired_before
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::Sqlx)?
+    .rows_affected();
+    if claimed != 0 {
+        return Ok(());
+    }
+    let holder = sqlx::query!(
+        "SELECT
+            receipt IS NOT NULL as \"paid!: bool\",
+            served as \"served!: bool\",
+            COALESCE(served_at, paid_at, timestamp) as \"served_at!: OffsetDateTime\",
+            Servers.email as \"served_by: String\",
+            COALESCE(claimed_at, timestamp) as \"claimed_at!: OffsetDateTime\",
+            Claimers.email as \"claimed_by: String\"
+        FROM Orders
+            LEFT JOIN Users AS Servers ON Servers.id = Orders.served_by
+            LEFT JOIN Users AS Claimers ON Claimers.id = Orders.claimed_by
+        WHERE Orders.id = ?",
+        order_id
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServerError::Sqlx)?
+    .ok_or(OrderManagementError::OrderNotFound)?;
+    // the waiter may have been deleted since, and older orders have no waiter nor serving time
+    let unknown = || "unknown waiter".to_owned();
+    Err(if !holder.paid {
+        OrderManagementError::OrderNotPaid
+    } else if holder.served {
+        OrderManagementError::AlreadyServed {
+            at: holder.served_at,
+            by: holder.served_by.unwrap_or_else(unknown),
+        }
+    } else {
+        OrderManagementError::AlreadyClaimed {
+            at: holder.claimed_at,
+            by: holder.claimed_by.unwrap_or_else(unknown),
+        }
+    })
+}
+
+/// whether the unreserved stock still covers every product of the order
+async fn is_still_in_stock(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+) -> Result<bool, ServerError> {
+    let missing = sqlx::query!(
+        "SELECT COUNT(*) as \"missing!: i64\" FROM Products
+        INNER JOIN (
+            SELECT product_id, SUM(quantity * variation_volume) as volume
+            FROM OrderDetails WHERE order_id = ? GROUP BY product_id
+        ) AS ordered ON Products.id = ordered.product_id
+        WHERE Products.stock_quantity - Products.reserved_quantity < ordered.volume",
+        order_id
+    )
+    .fetch_one(&mut **transaction)
+    .await?
+    .missing;
+    Ok(missing == 0)
+}
+
+/// gives the reserved volume back, only once even if the order is canceled several times.
+/// Returns whether the order was canceled by this call
+async fn cancel_and_release_stock(
+    pool: &SqlitePool,
+    order_id: OrderId,
+) -> Result<bool, ServerError> {
+    let mut transaction = pool.begin().await?;
+    let canceled = sqlx::query!(
+        "UPDATE Orders SET canceled = TRUE WHERE id = ? AND canceled = FALSE AND receipt IS NULL",
+        order_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    if canceled == 1 {
+        release_reservation(&mut transaction, order_id).await?;
+    }
+    transaction.commit().await?;
+    Ok(canceled == 1)
+}
+
+/// gives the reserved volume back if the order still holds it
+async fn release_reservation(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+) -> Result<(), ServerError> {
+    let released = sqlx::query!(
+        "UPDATE Orders SET reserved_until = NULL WHERE id = ? AND reserved_until IS NOT NULL",
+        order_id
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+    if released == 1 {
+        sqlx::query!(
+            "UPDATE Products SET reserved_quantity = reserved_quantity - ordered.volume
+            FROM (
+                SELECT product_id, SUM(quantity * variation_volume) as volume
+                FROM OrderDetails WHERE order_id = ? GROUP BY product_id
+            ) AS ordered
+            WHERE Products.id = ordered.product_id",
+            order_id
+        )
+        .execute(&mut **transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+/// unpaid orders stay payable until they expire, but their stock is given back once their
+/// reservation is over
+pub async fn release_expired_reservations(pool: &SqlitePool) -> Result<(), ServerError> {
+    let mut transaction = pool.begin().await?;
+    let expired = sqlx::query!(
+        "SELECT id as \"id: u32\" FROM Orders
+        WHERE reserved_until < CURRENT_TIMESTAMP AND receipt IS NULL"
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    for order in expired {
+        release_reservation(&mut transaction, order.id).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// when the intent of an expired order cannot be canceled, its status tells why: a paid
+/// order is marked as paid, a payment still being processed is left for the next run. Any
+/// other order keeps its reservation and is retried on the next run, the first error is
+/// returned once every other order has been handled
+pub async fn cancel_expired_orders(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    order_events: &OrderEvents,
+) -> Result<(), ServerError> {
+    let expired_orders =
+        sqlx::query!("SELECT id as \"id: u32\", payment_intent_id from Orders WHERE expires < CURRENT_TIMESTAMP AND canceled = FALSE AND receipt IS NULL")
+            .fetch_all(pool)
+            .await?;
 
-        for payment_intent in expired_payment_intents {
-            stripe::api::mark_as_canceled(&payment_intent.payment_intent_id)
+    let mut first_error = None;
+    for order in expired_orders {
+        if let Some(payment_intent_id) = &order.payment_intent_id {
+            if let Err(e) = payment_provider
+                .cancel_payment_intent(payment_intent_id)
                 .await
-                .unwrap();
+            {
+                let status = payment_provider
+                    .fetch_payment_intent(payment_intent_id)
+                    .await
+                    .map(|intent| intent.status);
+                match status {
+                    Ok(PaymentIntentStatus::Succeeded) => {
+                        // `Order::get` leaves the expired orders out
+                        let paid =
+                            Order::get_from_payment_intent_id(pool, payment_intent_id).await?;
+                        if let Some(mut paid) = paid {
+                            if let Err(e) = paid.mark_as_paid(pool, order_events).await {
+                                first_error.get_or_insert(e);
+                            }
+                        }
+                        continue;
+                    }
+                    Ok(PaymentIntentStatus::Processing) => continue,
+                    // canceled on the provider side, the order can be canceled as well
+                    Ok(PaymentIntentStatus::Canceled) => {}
+                    _ => {
+                        tracing::error!(
+                            "could not cancel payment intent of expired order {} : {e:?}",
+                            order.id
+                        );
+                        first_error.get_or_insert(e);
+                        continue;
+                    }
+                }
+            }
+        }
+        match cancel_and_release_stock(pool, order.id).await {
+            Ok(true) => monitoring::count_order(OrderStage::Expired),
+            Ok(false) => {}
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[sqlx::test]
+async fn test_order_flow(pool: SqlitePool) {
+    use crate::{
+        mail_manager::{MailManager, TestMailManager},
+        payment_provider::TestPaymentProvider,
+    };
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let mail_manager: Arc<Box<dyn MailManager>> = Arc::new(Box::new(TestMailManager::default()));
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 2,
+        }],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart)
+        .await
+        .unwrap();
+    let mut order = Order::get(&pool, order_id).await.unwrap().unwrap();
+    outbox::deliver_pending(&pool, payment_provider.clone(), mail_manager.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        provider.metadata.read().await[order.payment_intent_id.as_ref().unwrap()]["order_id"],
+        order_id.to_string()
+    );
+
+    let intent = order
+        .get_payment_intent(&pool, payment_provider.clone(), &OrderEvents::new())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(intent.amount, 2 * 984);
+    assert!(order.receipt.is_none());
+
+    provider
+        .set_status(
+            order.payment_intent_id.as_ref().unwrap(),
+            PaymentIntentStatus::Succeeded,
+        )
+        .await;
+    order
+        .get_payment_intent(&pool, payment_provider.clone(), &OrderEvents::new())
+        .await
+        .unwrap();
+    assert!(order.receipt.is_some());
+    let product = products::Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 99.0);
+
+    outbox::deliver_pending(&pool, payment_provider, mail_manager)
+        .await
+        .unwrap();
+    assert_eq!(
+        &provider.metadata.read().await[order.payment_intent_id.as_ref().unwrap()]["reçu"],
+        order.receipt.as_deref().unwrap()
+    );
+    assert!(
+        outbox::get_entries(&pool, Some(outbox::OutboxStatus::Pending), None, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[sqlx::test]
+async fn test_mark_as_paid_is_idempotent(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 4,
+            quantity: 1,
+        }],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart)
+        .await
+        .unwrap();
+    let mut order = Order::get(&pool, order_id).await.unwrap().unwrap();
+    order
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    let receipt = order.receipt.clone().unwrap();
+    let invoice_number = order.invoice_number.clone().unwrap();
+    order
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    assert_eq!(*order.receipt.unwrap(), *receipt);
+    // the second call does not take a new number
+    assert_eq!(order.invoice_number.unwrap(), invoice_number);
+    let last_number = sqlx::query!("SELECT last_number FROM InvoiceSequences")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .last_number;
+    assert_eq!(last_number, 1);
+    let product = products::Product::get(&pool, 2).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 49.5);
+    assert_eq!(product.reserved_quantity, 0.0);
+}
+
+#[sqlx::test]
+async fn test_generate_from_cart_not_enough_stock(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 5,
+            quantity: 1,
+        }],
+    };
+    let res = Order::generate_from_cart(&pool, payment_provider, cart).await;
+    assert!(matches!(res, Err(OrderProcessError::NotEnoughStock(_, 3))));
+}
+
+#[sqlx::test]
+async fn test_stock_reservation(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    // 10 litres of wine, 0.125 per glass
+    let cart = |quantity| Cart {
+        elements: vec![CartElement {
+            variation_id: 6,
+            quantity,
+        }],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart(60))
+        .await
+        .unwrap();
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 10.0);
+    assert_eq!(product.reserved_quantity, 7.5);
+
+    let res = Order::generate_from_cart(&pool, payment_provider.clone(), cart(21)).await;
+    assert!(matches!(res, Err(OrderProcessError::NotEnoughStock(_, 4))));
+    assert_eq!(provider.payment_intents.read().await.len(), 1);
+
+    let mut order = Order::get(&pool, order_id).await.unwrap().unwrap();
+    order.mark_as_canceled(&pool).await.unwrap();
+    order.mark_as_canceled(&pool).await.unwrap();
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.reserved_quantity, 0.0);
+
+    Order::generate_from_cart(&pool, payment_provider, cart(80))
+        .await
+        .unwrap();
+}
+
+#[sqlx::test]
+async fn test_same_product_in_several_lines(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    sqlx::query!("UPDATE Products SET stock_quantity = 1 WHERE id = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+    // a pint and a pitcher are 2 litres of ipa, only 1 is left
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id: 1,
+                quantity: 1,
+            },
+            CartElement {
+                variation_id: 3,
+                quantity: 1,
+            },
+        ],
+    };
+    let res = Order::generate_from_cart(&pool, payment_provider, cart).await;
+    assert!(matches!(res, Err(OrderProcessError::NotEnoughStock(_, 1))));
+    assert!(provider.payment_intents.read().await.is_empty());
+    let orders = sqlx::query!("SELECT COUNT(*) as count FROM Orders")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(orders, 0);
+}
+
+#[sqlx::test]
+async fn test_counter_order(pool: SqlitePool) {
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 7,
+            quantity: 3,
+        }],
+    };
+    let res = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart.clone(),
+        PaymentMethod::Stripe,
+    )
+    .await;
+    assert!(matches!(res, Err(OrderProcessError::InvalidPaymentMethod)));
+
+    let order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    assert!(order.receipt.is_some());
+    assert!(order.payment_intent_id.is_none());
+    assert_eq!(order.payment_method, PaymentMethod::Cash);
+    assert_eq!(
+        order
+            .get_details(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|d| d.subtotal_ht)
+            .sum::<Money>()
+            .cents(),
+        3 * 650
+    );
+    let product = products::Product::get(&pool, 5).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 22.0);
+    assert_eq!(product.reserved_quantity, 0.0);
+
+    let too_much = Cart {
+        elements: vec![CartElement {
+            variation_id: 7,
+            quantity: 23,
+        }],
+    };
+    let res = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        too_much,
+        PaymentMethod::ExternalCard,
+    )
+    .await;
+    assert!(matches!(res, Err(OrderProcessError::NotEnoughStock(_, 5))));
+
+    // only the order that went through is in the audit log
+    let entries = audit::get_entries(&pool, &Default::default(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, AuditAction::CreateCounterOrder);
+    assert_eq!(entries[0].actor_email, waiter.email);
+    assert_eq!(entries[0].target_id, Some(order.id.to_string()));
+    assert_eq!(
+        entries[0].after["total_price_ttc"],
+        json!(Money::from_cents(3 * 780))
+    );
+}
+
+#[sqlx::test]
+async fn test_partial_serving(pool: SqlitePool) {
+    use crate::{app::refunds, payment_provider::TestPaymentProvider};
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id: 1,
+                quantity: 2,
+            },
+            CartElement {
+                variation_id: 7,
+                quantity: 1,
+            },
+        ],
+    };
+    let mut order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let details = order.get_details(&pool).await.unwrap();
+    let (beers, saucisson) = (details[0].detail_id, details[1].detail_id);
+    assert_eq!(
+        ServingStatus::of(&order.get_details(&pool).await.unwrap()),
+        ServingStatus::Unserved
+    );
+
+    order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, beers, 2)
+        .await
+        .unwrap();
+    assert_eq!(
+        ServingStatus::of(&order.get_details(&pool).await.unwrap()),
+        ServingStatus::PartiallyServed
+    );
+    assert!(!order.served);
+    let res = order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, beers, 1)
+        .await;
+    assert!(matches!(
+        res,
+        Err(OrderManagementError::InvalidServeQuantity(_))
+    ));
+    let res = order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, 1000, 1)
+        .await;
+    assert!(matches!(
+        res,
+        Err(OrderManagementError::OrderDetailNotFound(1000))
+    ));
+
+    order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, saucisson, 1)
+        .await
+        .unwrap();
+    assert!(order.served);
+    assert_eq!(
+        ServingStatus::of(&order.get_details(&pool).await.unwrap()),
+        ServingStatus::Served
+    );
+
+    order
+        .set_served(&pool, &OrderEvents::new(), &waiter, false)
+        .await
+        .unwrap();
+    let details = order.get_details(&pool).await.unwrap();
+    assert!(details.iter().all(|d| d.remaining_quantity == d.quantity));
+
+    // refunding what is left to hand over completes the order
+    order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, beers, 2)
+        .await
+        .unwrap();
+    let lines = vec![refunds::RefundLine {
+        detail_id: saucisson,
+        quantity: 1,
+    }];
+    refunds::refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        Some(lines),
+        "",
+    )
+    .await
+    .unwrap();
+    let order = Order::get_by_receipt(&pool, order.receipt.as_ref().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(order.served);
+}
+
+#[sqlx::test]
+async fn test_claim_for_serving(pool: SqlitePool) {
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 1,
+        }],
+    };
+    let mut order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let first = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let second = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+
+    order.claim_for_serving(&pool, &first).await.unwrap();
+    order.claim_for_serving(&pool, &first).await.unwrap();
+    let res = order.claim_for_serving(&pool, &second).await;
+    assert!(
+        matches!(res, Err(OrderManagementError::AlreadyClaimed { ref by, .. }) if by == &first.email)
+    );
+    let res = order
+        .set_served(&pool, &OrderEvents::new(), &second, true)
+        .await;
+    assert!(matches!(
+        res,
+        Err(OrderManagementError::AlreadyClaimed { .. })
+    ));
+
+    // an old claim no longer holds the order
+    sqlx::query!(
+        "UPDATE Orders SET claimed_at = datetime('now', '-1 hour') WHERE id = ?",
+        order.id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    order
+        .set_served(&pool, &OrderEvents::new(), &second, true)
+        .await
+        .unwrap();
+    for waiter in [&first, &second] {
+        let res = order.claim_for_serving(&pool, waiter).await;
+        let Err(OrderManagementError::AlreadyServed { by, .. }) = res else {
+            panic!("served order claimed again : {res:?}");
+        };
+        assert_eq!(by, second.email);
+    }
+    let err = order
+        .set_served(&pool, &OrderEvents::new(), &first, true)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().starts_with("order already served at "));
+    assert!(err
+        .to_string()
+        .ends_with(&format!(" UTC by {}", second.email)));
+
+    // marking as not served gives the order back
+    order
+        .set_served(&pool, &OrderEvents::new(), &second, false)
+        .await
+        .unwrap();
+    order.claim_for_serving(&pool, &first).await.unwrap();
+}
+
+#[sqlx::test]
+async fn test_order_events(pool: SqlitePool) {
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let order_events = OrderEvents::new();
+    let mut receiver = order_events.subscribe();
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 7,
+            quantity: 2,
+        }],
+    };
+    let mut order =
+        Order::generate_from_counter(&pool, &order_events, &waiter, cart, PaymentMethod::Cash)
+            .await
+            .unwrap();
+    assert_eq!(
+        receiver.recv().await.unwrap(),
+        OrderEvent::Paid { order_id: order.id }
+    );
+    assert_eq!(get_preparation_queue(&pool).await.unwrap().len(), 1);
+
+    let detail_id = order.get_details(&pool).await.unwrap()[0].detail_id;
+    order
+        .serve_detail(&pool, &order_events, &waiter, detail_id, 2)
+        .await
+        .unwrap();
+    assert_eq!(
+        receiver.recv().await.unwrap(),
+        OrderEvent::ServingChanged {
+            order_id: order.id,
+            served: true
+        }
+    );
+    assert!(get_preparation_queue(&pool).await.unwrap().is_empty());
+}
+
+#[sqlx::test]
+async fn test_cancel_expired_orders(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 7,
+            quantity: 2,
+        }],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart)
+        .await
+        .unwrap();
+    let payment_intent_id = Order::get(&pool, order_id)
+        .await
+        .unwrap()
+        .unwrap()
+        .payment_intent_id
+        .unwrap();
+    sqlx::query!(
+        "UPDATE Orders SET expires = datetime(CURRENT_TIMESTAMP, '-1 minute') WHERE id = ?",
+        order_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    cancel_expired_orders(&pool, payment_provider, &OrderEvents::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        provider.payment_intents.read().await[&payment_intent_id].status,
+        PaymentIntentStatus::Canceled
+    );
+    let product = products::Product::get(&pool, 5).await.unwrap().unwrap();
+    assert_eq!(product.reserved_quantity, 0.0);
+    assert!(Order::get(&pool, order_id).await.unwrap().is_none());
+}
+
+#[sqlx::test]
+async fn test_release_expired_reservations(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    // 10 litres of wine, 0.125 per glass
+    let cart = |quantity| Cart {
+        elements: vec![CartElement {
+            variation_id: 6,
+            quantity,
+        }],
+    };
+    let released_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart(40))
+        .await
+        .unwrap();
+    let kept_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart(8))
+        .await
+        .unwrap();
+    sqlx::query!(
+        "UPDATE Orders SET reserved_until = datetime(CURRENT_TIMESTAMP, '-1 minute') WHERE id = ?",
+        released_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    release_expired_reservations(&pool).await.unwrap();
+    release_expired_reservations(&pool).await.unwrap();
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.reserved_quantity, 1.0);
+
+    // the order itself is still payable
+    let mut released = Order::get(&pool, released_id).await.unwrap().unwrap();
+    released
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    assert!(!released.needs_review);
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 5.0);
+    assert_eq!(product.reserved_quantity, 1.0);
+
+    let mut kept = Order::get(&pool, kept_id).await.unwrap().unwrap();
+    kept.mark_as_canceled(&pool).await.unwrap();
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.reserved_quantity, 0.0);
+}
+
+#[sqlx::test]
+async fn test_cancel_expired_orders_failure(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let cart = || Cart {
+        elements: vec![CartElement {
+            variation_id: 7,
+            quantity: 1,
+        }],
+    };
+    let mut ids = vec![];
+    for _ in 0..3 {
+        let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart())
+            .await
+            .unwrap();
+        sqlx::query!(
+            "UPDATE Orders SET expires = datetime(CURRENT_TIMESTAMP, '-1 minute') WHERE id = ?",
+            order_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        ids.push(order_id);
+    }
+    provider
+        .set_status(&"pi_test_0".to_owned(), PaymentIntentStatus::Succeeded)
+        .await;
+    provider
+        .set_status(&"pi_test_1".to_owned(), PaymentIntentStatus::Processing)
+        .await;
+    *provider.cancel_error.write().await = Some(axum::http::StatusCode::BAD_REQUEST);
+
+    // the third intent could still be canceled later, it is the only error
+    let res = cancel_expired_orders(&pool, payment_provider.clone(), &OrderEvents::new()).await;
+    assert!(matches!(res, Err(ServerError::StripeApi(..))));
+    let paid = Order::get_from_payment_intent_id(&pool, "pi_test_0")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(paid.id, ids[0]);
+    assert!(paid.receipt.is_some());
+    let processing = Order::get_from_payment_intent_id(&pool, "pi_test_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(processing.receipt.is_none());
+
+    provider
+        .set_status(&"pi_test_2".to_owned(), PaymentIntentStatus::Canceled)
+        .await;
+    cancel_expired_orders(&pool, payment_provider, &OrderEvents::new())
+        .await
+        .unwrap();
+    let canceled = sqlx::query!(
+        "SELECT canceled as \"canceled: bool\" FROM Orders WHERE id IN (?, ?) ORDER BY id",
+        ids[1],
+        ids[2]
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert!(!canceled[0].canceled);
+    assert!(canceled[1].canceled);
+    // the paid order took its stock, the processing one still holds its reservation
+    let product = products::Product::get(&pool, 5).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 24.0);
+    assert_eq!(product.reserved_quantity, 1.0);
+}
+
+#[sqlx::test]
+async fn test_payment_after_expiry(pool: SqlitePool) {
+    use crate::{app::refunds, payment_provider::TestPaymentProvider};
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    // 10 litres of wine, 0.125 per glass
+    let cart = |quantity| Cart {
+        elements: vec![CartElement {
+            variation_id: 6,
+            quantity,
+        }],
+    };
+    let expire = |order_id: OrderId| {
+        let pool = pool.clone();
+        async move {
             sqlx::query!(
-                "UPDATE Orders SET canceled = TRUE WHERE payment_intent_id = ?",
-                payment_intent.payment_intent_id
+                "UPDATE Orders SET expires = datetime(CURRENT_TIMESTAMP, '-1 minute') WHERE id = ?",
+                order_id
             )
             .execute(&pool)
             .await
             .unwrap();
         }
-    });
+    };
+    let late_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart(60))
+        .await
+        .unwrap();
+    let covered_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart(8))
+        .await
+        .unwrap();
+    expire(late_id).await;
+    expire(covered_id).await;
+    cancel_expired_orders(&pool, payment_provider.clone(), &OrderEvents::new())
+        .await
+        .unwrap();
+    // the released stock is sold at the counter
+    Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart(60),
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+
+    let get = |payment_intent_id| {
+        let pool = pool.clone();
+        async move {
+            Order::get_from_payment_intent_id(&pool, payment_intent_id)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+    let mut late = get("pi_test_0").await;
+    assert_eq!(late.id, late_id);
+    late.mark_as_paid(&pool, &OrderEvents::new()).await.unwrap();
+    assert!(late.receipt.is_some());
+    assert!(late.needs_review);
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 2.5);
+    assert_eq!(product.reserved_quantity, 0.0);
+
+    // there is still enough for this one
+    let mut covered = get("pi_test_1").await;
+    covered
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    assert!(!covered.needs_review);
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 1.5);
+
+    let filters = OrderFilters {
+        needs_review: Some(true),
+        ..Default::default()
+    };
+    let found = search_orders(&pool, &filters, Default::default(), None, 10)
+        .await
+        .unwrap()
+        .orders;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, late_id);
+
+    // refunding gives nothing back to the stock, and clears the flag
+    refunds::refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &late,
+        None,
+        "rupture",
+    )
+    .await
+    .unwrap();
+    let product = products::Product::get(&pool, 4).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 1.5);
+    assert!(!get("pi_test_0").await.needs_review);
+}
+
+#[sqlx::test]
+async fn test_details_of_several_orders(pool: SqlitePool) {
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = |variation_id, quantity| Cart {
+        elements: vec![CartElement {
+            variation_id,
+            quantity,
+        }],
+    };
+    let events = OrderEvents::new();
+    let first =
+        Order::generate_from_counter(&pool, &events, &waiter, cart(1, 2), PaymentMethod::Cash)
+            .await
+            .unwrap();
+    let second =
+        Order::generate_from_counter(&pool, &events, &waiter, cart(4, 1), PaymentMethod::Cash)
+            .await
+            .unwrap();
+    let details = get_details_of_orders(&pool, &[first.id, second.id, 42])
+        .await
+        .unwrap();
+    assert_eq!(details.len(), 2);
+    assert_eq!(details[&first.id].len(), 1);
+    assert_eq!(details[&first.id][0].quantity, 2);
+    assert_eq!(details[&second.id][0].item_name, "blonde (pinte)");
+    assert!(get_details_of_orders(&pool, &[]).await.unwrap().is_empty());
+}
+
+#[sqlx::test]
+async fn test_search_orders(pool: SqlitePool) {
+    use crate::payment_provider::TestPaymentProvider;
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let cart = |variation_id, quantity| Cart {
+        elements: vec![CartElement {
+            variation_id,
+            quantity,
+        }],
+    };
+    let events = OrderEvents::new();
+    // 9,84 / 19,68 / 7,68 / 29,52
+    let mut ids = vec![];
+    for (variation_id, quantity) in [(1, 1), (1, 2), (4, 1), (1, 3)] {
+        let order = Order::generate_from_counter(
+            &pool,
+            &events,
+            &waiter,
+            cart(variation_id, quantity),
+            PaymentMethod::Cash,
+        )
+        .await
+        .unwrap();
+        ids.push(order.id);
+    }
+    let mut served = Order::get(&pool, ids[1]).await.unwrap().unwrap();
+    served
+        .set_served(&pool, &events, &waiter, true)
+        .await
+        .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let pending = Order::generate_from_cart(&pool, payment_provider, cart(1, 1))
+        .await
+        .unwrap();
+
+    let search = |filters: OrderFilters<'static>, sort, after, limit| {
+        let pool = pool.clone();
+        async move {
+            search_orders(&pool, &filters, sort, after, limit)
+                .await
+                .unwrap()
+        }
+    };
+    let found_ids = |page: &SearchPage| page.orders.iter().map(|o| o.id).collect::<Vec<_>>();
+
+    // paid orders only, newest first, two per page
+    let first = search(OrderFilters::default(), OrderSort::Newest, None, 2).await;
+    assert_eq!(found_ids(&first), vec![ids[3], ids[2]]);
+    let cursor = first.next_cursor.unwrap();
+    assert_eq!(cursor.to_string().parse::<SearchCursor>(), Ok(cursor));
+    let second = search(OrderFilters::default(), OrderSort::Newest, Some(cursor), 2).await;
+    assert_eq!(found_ids(&second), vec![ids[1], ids[0]]);
+    assert!(second.next_cursor.is_none());
+
+    // the most expensive first, ties are broken by id
+    let first = search(OrderFilters::default(), OrderSort::AmountDesc, None, 3).await;
+    assert_eq!(found_ids(&first), vec![ids[3], ids[1], ids[0]]);
+    let second = search(
+        OrderFilters::default(),
+        OrderSort::AmountDesc,
+        first.next_cursor,
+        3,
+    )
+    .await;
+    assert_eq!(found_ids(&second), vec![ids[2]]);
+
+    let filters = OrderFilters {
+        min_amount: Some(Money::from_cents(984)),
+        max_amount: Some(Money::from_cents(1968)),
+        ..Default::default()
+    };
+    let page = search(filters, OrderSort::Oldest, None, 10).await;
+    assert_eq!(found_ids(&page), vec![ids[0], ids[1]]);
+
+    let filters = OrderFilters {
+        served: Some(false),
+        product_id: Some(1),
+        ..Default::default()
+    };
+    let page = search(filters, OrderSort::Oldest, None, 10).await;
+    assert_eq!(found_ids(&page), vec![ids[0], ids[3]]);
+
+    let filters = OrderFilters {
+        status: OrderStatus::Pending,
+        ..Default::default()
+    };
+    let page = search(filters, OrderSort::Newest, None, 10).await;
+    assert_eq!(found_ids(&page), vec![pending]);
+
+    // wildcards are matched literally
+    let filters = OrderFilters {
+        invoice_number: Some("%"),
+        ..Default::default()
+    };
+    assert!(search(filters, OrderSort::Newest, None, 10)
+        .await
+        .orders
+        .is_empty());
 }
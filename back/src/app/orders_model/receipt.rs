@@ -1,15 +1,20 @@
-use std::ops::Deref;
+use std::{env, ops::Deref};
 
+use hmac::{Hmac, Mac};
 use qrcode::QrCode;
 use serde::Serialize;
+use sha2::Sha256;
 
-use crate::errors::ServerError;
-#[derive(Serialize, Debug, Clone, sqlx::Decode)]
+use crate::{app::orders::OrderId, errors::ServerError};
+#[derive(Serialize, Debug, Clone, sqlx::Type)]
+#[sqlx(transparent)]
 pub struct Receipt(pub String);
 
 impl Receipt {
-    pub fn get_qr_code(&self) -> Result<QrCode, ServerError> {
-        let qr = QrCode::with_version(self, qrcode::Version::Normal(5), qrcode::EcLevel::H)
+    /// the QR code carries the signed payload, not the bare receipt
+    pub fn get_qr_code(&self, order_id: OrderId) -> Result<QrCode, ServerError> {
+        let payload = ReceiptKeys::from_env()?.sign(order_id, self);
+        let qr = QrCode::with_error_correction_level(payload, qrcode::EcLevel::H)
             .map_err(ServerError::QrCode)?;
         Ok(qr)
     }
@@ -30,3 +35,137 @@ impl Deref for Receipt {
         &self.0
     }
 }
+
+const PAYLOAD_PREFIX: &str = "BNC1";
+/// the signature is truncated to keep the QR code small enough to be scanned from a phone
+const SIGNATURE_BYTES: usize = 16;
+/// the secret of older `.env.template`s, anyone could forge codes with it
+const PLACEHOLDER_SECRET: &str = "change-me-in-production";
+
+/// keys signing the QR codes, from `RECEIPT_SIGNING_KEYS` (`id:secret,id:secret`). The first
+/// key signs the new codes, the other ones are still accepted so that the codes already
+/// mailed stay valid while a key is rotated
+pub struct ReceiptKeys {
+    keys: Vec<(String, String)>,
+}
+
+impl ReceiptKeys {
+    /// also called at startup, so that a missing or placeholder key stops the server
+    pub fn from_env() -> Result<ReceiptKeys, ServerError> {
+        let keys = match env::var("RECEIPT_SIGNING_KEYS") {
+            Ok(keys) if !keys.is_empty() => keys,
+            #[cfg(test)]
+            Ok(_) => "test:test-secret".to_owned(),
+            #[cfg(not(test))]
+            Ok(_) => {
+                return Err(ServerError::MissingEnv(
+                    "RECEIPT_SIGNING_KEYS".into(),
+                    env::VarError::NotPresent,
+                ))
+            }
+            #[cfg(test)]
+            Err(_) => "test:test-secret".to_owned(),
+            #[cfg(not(test))]
+            Err(e) => return Err(ServerError::MissingEnv("RECEIPT_SIGNING_KEYS".into(), e)),
+        };
+        ReceiptKeys::parse(&keys)
+    }
+
+    fn parse(keys: &str) -> Result<ReceiptKeys, ServerError> {
+        let keys: Vec<(String, String)> = keys
+            .split(',')
+            .filter_map(|key| key.trim().split_once(':'))
+            .filter(|(id, secret)| !id.is_empty() && !id.contains('.') && !secret.is_empty())
+            .map(|(id, secret)| (id.to_owned(), secret.to_owned()))
+            .collect();
+        if keys.is_empty() {
+            return Err(ServerError::InvalidReceiptKeys);
+        }
+        if keys.iter().any(|(_, secret)| secret == PLACEHOLDER_SECRET) {
+            return Err(ServerError::PlaceholderReceiptKey);
+        }
+        Ok(ReceiptKeys { keys })
+    }
+
+    fn mac(secret: &str, key_id: &str, order_id: OrderId, receipt: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(format!("{PAYLOAD_PREFIX}.{key_id}.{order_id}.{receipt}").as_bytes());
+        mac
+    }
+
+    /// `BNC1.<key id>.<order id>.<receipt>.<signature>`
+    pub fn sign(&self, order_id: OrderId, receipt: &Receipt) -> String {
+        let (key_id, secret) = &self.keys[0];
+        let signature = Self::mac(secret, key_id, order_id, receipt).finalize();
+        format!(
+            "{PAYLOAD_PREFIX}.{key_id}.{order_id}.{}.{}",
+            receipt.0,
+            hex::encode(&signature.into_bytes()[..SIGNATURE_BYTES])
+        )
+    }
+
+    /// None if the payload was not signed by one of the keys
+    pub fn verify(&self, payload: &str) -> Option<(OrderId, Receipt)> {
+        let mut parts = payload.trim().split('.');
+        let (
+            Some(PAYLOAD_PREFIX),
+            Some(key_id),
+            Some(order_id),
+            Some(receipt),
+            Some(signature),
+            None,
+        ) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        )
+        else {
+            return None;
+        };
+        let order_id: OrderId = order_id.parse().ok()?;
+        let signature = hex::decode(signature).ok()?;
+        if signature.len() != SIGNATURE_BYTES {
+            return None;
+        }
+        let (_, secret) = self.keys.iter().find(|(id, _)| id == key_id)?;
+        Self::mac(secret, key_id, order_id, receipt)
+            .verify_truncated_left(&signature)
+            .ok()?;
+        Some((order_id, Receipt(receipt.to_owned())))
+    }
+}
+
+#[test]
+fn test_signed_payload() {
+    let receipt = Receipt("0b9a3c1e-7c8e-4a53-b1c4-5f3f2a8d6e01".to_owned());
+    let keys = ReceiptKeys::parse("k1:first-secret").unwrap();
+    let payload = keys.sign(42, &receipt);
+    assert!(payload.starts_with("BNC1.k1.42.0b9a3c1e-"));
+    let (order_id, verified) = keys.verify(&payload).unwrap();
+    assert_eq!((order_id, verified.0.as_str()), (42, receipt.0.as_str()));
+
+    // another order, another receipt or a hand-typed receipt are rejected
+    assert!(keys.verify(&payload.replace(".42.", ".43.")).is_none());
+    assert!(keys.verify(&payload.replace("0b9a", "0b9b")).is_none());
+    assert!(keys.verify(&receipt.0).is_none());
+
+    // after a rotation the old codes stay valid as long as the old key is listed
+    let rotated = ReceiptKeys::parse("k2:second-secret, k1:first-secret").unwrap();
+    assert!(rotated.verify(&payload).is_some());
+    assert!(rotated.sign(42, &receipt).starts_with("BNC1.k2.42."));
+    let retired = ReceiptKeys::parse("k2:second-secret").unwrap();
+    assert!(retired.verify(&payload).is_none());
+    // same key id with another secret
+    let forged = ReceiptKeys::parse("k1:guessed").unwrap();
+    assert!(keys.verify(&forged.sign(42, &receipt)).is_none());
+
+    assert!(ReceiptKeys::parse("").is_err());
+    assert!(matches!(
+        ReceiptKeys::parse("dev:change-me-in-production"),
+        Err(ServerError::PlaceholderReceiptKey)
+    ));
+}
@@ -0,0 +1,802 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use axum::http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+
+use crate::{
+    admin::{
+        audit::{self, AuditAction, AuditTarget},
+        user::User,
+    },
+    app::{
+        money::{Amounts, Money, VatRate},
+        order_events::{OrderEvent, OrderEvents},
+        orders::{update_served_flag, Order, OrderDetailId, OrderId},
+        stock_movements::{self, StockMovementReason},
+        stripe::refunds::{RefundId, RefundStatus},
+    },
+    errors::{OrderManagementError, ServerError},
+    payment_provider::PaymentProvider,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RefundLine {
+    pub detail_id: OrderDetailId,
+    pub quantity: u32,
+}
+
+/// refunds of orders paid at the counter are handed over by the waiter and succeed right away
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(rename_all = "snake_case")]
+pub enum RefundState {
+    /// written, not confirmed by the payment provider yet
+    Pending,
+    Succeeded,
+    /// rejected by the payment provider, its lines can be refunded again
+    Failed,
+}
+
+/// refunds the given lines of a paid order, or everything that was not refunded yet when
+/// `lines` is None. The refund is written before the payment provider is called, so that
+/// no write transaction is held during the call, and the refunded volume goes back to the
+/// stock once it succeeded. When the provider cannot be reached, the refund stays pending
+/// and is sent again by `retry_pending_refunds`.
+/// Returns the refunded amount (ttc)
+pub async fn refund_order(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    order_events: &OrderEvents,
+    admin: &User,
+    order: &Order,
+    lines: Option<Vec<RefundLine>>,
+    reason: &str,
+) -> Result<Money, OrderManagementError> {
+    if order.receipt.is_none() {
+        return Err(OrderManagementError::OrderNotPaid);
+    }
+    let lines = match lines {
+        Some(lines) => lines,
+        None => sqlx::query!(
+            "SELECT id as \"id: u32\", quantity - refunded_quantity as \"remaining!: u32\"
+            FROM OrderDetails WHERE order_id = ? AND quantity > refunded_quantity",
+            order.id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(ServerError::Sqlx)?
+        .into_iter()
+        .map(|e| RefundLine {
+            detail_id: e.id,
+            quantity: e.remaining,
+        })
+        .collect(),
+    };
+    if lines.is_empty() {
+        return Err(OrderManagementError::NothingToRefund);
+    }
+    let mut seen = HashSet::new();
+    if let Some(line) = lines.iter().find(|line| !seen.insert(line.detail_id)) {
+        return Err(OrderManagementError::DuplicateRefundLine(line.detail_id));
+    }
+
+    let mut transaction = pool.begin().await.map_err(ServerError::Sqlx)?;
+    let mut amount = Money::ZERO;
+    for line in &lines {
+        if line.quantity == 0 {
+            return Err(OrderManagementError::InvalidRefundQuantity(line.detail_id));
+        }
+        let detail = sqlx::query!(
+            "SELECT
+                unit_price_ht as \"unit_price_ht: i64\",
+                tva as \"tva: f32\",
+                quantity as \"quantity: u32\",
+                refunded_quantity as \"refunded_quantity: u32\"
+            FROM OrderDetails WHERE id = ? AND order_id = ?",
+            line.detail_id,
+            order.id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?
+        .ok_or(OrderManagementError::OrderDetailNotFound(line.detail_id))?;
+
+        let refunded = sqlx::query!(
+            "UPDATE OrderDetails SET refunded_quantity = refunded_quantity + ?
+            WHERE id = ? AND quantity - refunded_quantity >= ?",
+            line.quantity,
+            line.detail_id,
+            line.quantity
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?
+        .rows_affected();
+        if refunded == 0 {
+            return Err(OrderManagementError::InvalidRefundQuantity(line.detail_id));
+        }
+        // the difference between the net amounts of the line before and after the refund:
+        // whatever the rounding, the refunds of a line add up exactly to what was charged
+        // minus the net amount that is reported
+        let unit_price_ht = Money::from_cents(detail.unit_price_ht);
+        let tva = VatRate::from_fraction(detail.tva);
+        let net_before = detail.quantity - detail.refunded_quantity;
+        let net_after = net_before - line.quantity;
+        amount += Amounts::for_line(unit_price_ht, net_before, tva).ttc
+            - Amounts::for_line(unit_price_ht, net_after, tva).ttc;
+    }
+
+    let refunded_before = sqlx::query!(
+        "SELECT cast(COALESCE(SUM(amount), 0) as int) as \"amount!: i64\"
+        FROM Refunds WHERE order_id = ? AND status != ?",
+        order.id,
+        RefundState::Failed
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(ServerError::Sqlx)?
+    .amount;
+    let refunded_before = Money::from_cents(refunded_before);
+    let amount_cents = amount.cents();
+    let refund_id = sqlx::query!(
+        "INSERT INTO Refunds (order_id, amount, reason, status) VALUES (?, ?, ?, ?)",
+        order.id,
+        amount_cents,
+        reason,
+        RefundState::Pending
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::Sqlx)?
+    .last_insert_rowid() as u32;
+    for line in &lines {
+        sqlx::query!(
+            "INSERT INTO RefundLines (refund_id, detail_id, quantity) VALUES (?, ?, ?)",
+            refund_id,
+            line.detail_id,
+            line.quantity
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::Sqlx)?;
+    }
+    if order.payment_intent_id.is_none() {
+        settle_succeeded(&mut transaction, refund_id, None).await?;
+    }
+    audit::record(
+        &mut transaction,
+        admin,
+        AuditAction::RefundOrder,
+        AuditTarget::Order(order.id),
+        json!({ "refunded_amount": refunded_before }),
+        json!({ "refunded_amount": refunded_before + amount, "reason": reason }),
+    )
+    .await?;
+    // refunding what was still to be handed over may complete the order
+    let served = update_served_flag(&mut transaction, order.id).await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
+    order_events.publish(OrderEvent::ServingChanged {
+        order_id: order.id,
+        served,
+    });
+
+    if order.payment_intent_id.is_some() {
+        match send_refund(pool, payment_provider, order_events, refund_id).await {
+            Ok(RefundState::Failed) => {
+                return Err(OrderManagementError::RefundRejected(refund_id));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(
+                refund_id,
+                "refund left pending, it will be sent again : {e:?}"
+            ),
+        }
+    }
+    Ok(amount)
+}
+
+/// sends a pending refund to the payment provider and settles it with the answer. The
+/// idempotency key comes from the refund id, so sending it again cannot refund twice.
+/// Errors are left for a later try: the provider may or may not have refunded
+async fn send_refund(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    order_events: &OrderEvents,
+    refund_id: u32,
+) -> Result<RefundState, ServerError> {
+    let refund = sqlx::query!(
+        "SELECT Refunds.order_id as \"order_id: u32\", amount, reason,
+            Orders.payment_intent_id as \"payment_intent_id!\"
+        FROM Refunds INNER JOIN Orders ON Orders.id = Refunds.order_id
+        WHERE Refunds.id = ? AND Refunds.status = ?",
+        refund_id,
+        RefundState::Pending
+    )
+    .fetch_optional(pool)
+    .await?;
+    // settled in the meantime
+    let Some(refund) = refund else {
+        return Ok(RefundState::Succeeded);
+    };
+    let idempotency_key = format!("refund-{refund_id}");
+    let answer = payment_provider
+        .refund(
+            &refund.payment_intent_id,
+            refund.amount,
+            &refund.reason,
+            &idempotency_key,
+        )
+        .await;
+    let provider_refund_id = match answer {
+        Ok(provider_refund)
+            if !matches!(
+                provider_refund.status,
+                RefundStatus::Failed | RefundStatus::Canceled
+            ) =>
+        {
+            Some(provider_refund.id)
+        }
+        Ok(provider_refund) => {
+            tracing::error!(
+                refund_id,
+                "refund {} was not accepted ({:?})",
+                provider_refund.id,
+                provider_refund.status
+            );
+            None
+        }
+        // a conflict means that the same key is still being processed
+        Err(ServerError::StripeApi(status, body))
+            if status.is_client_error() && status != StatusCode::CONFLICT =>
+        {
+            tracing::error!(refund_id, "refund rejected ({status}) : {body}");
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut transaction = pool.begin().await?;
+    let state = match provider_refund_id {
+        Some(provider_refund_id) => {
+            settle_succeeded(&mut transaction, refund_id, Some(provider_refund_id)).await?;
+            RefundState::Succeeded
+        }
+        None => {
+            settle_failed(&mut transaction, refund_id).await?;
+            RefundState::Failed
+        }
+    };
+    let served = update_served_flag(&mut transaction, refund.order_id).await?;
+    transaction.commit().await?;
+    if state == RefundState::Failed {
+        order_events.publish(OrderEvent::ServingChanged {
+            order_id: refund.order_id,
+            served,
+        });
+    }
+    Ok(state)
+}
+
+/// the refunded units that were not served go back to the stock, unless the order never
+/// took it. Served units are refunded without being restocked
+async fn settle_succeeded(
+    transaction: &mut Transaction<'_, Sqlite>,
+    refund_id: u32,
+    provider_refund_id: Option<RefundId>,
+) -> Result<(), ServerError> {
+    let settled = sqlx::query!(
+        "UPDATE Refunds SET status = ?, provider_refund_id = ? WHERE id = ? AND status = ?",
+        RefundState::Succeeded,
+        provider_refund_id,
+        refund_id,
+        RefundState::Pending
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+    if settled == 0 {
+        return Ok(());
+    }
+    let lines = sqlx::query!(
+        "SELECT OrderDetails.id as \"detail_id: u32\",
+            OrderDetails.order_id as \"order_id: u32\",
+            OrderDetails.product_id as \"product_id: u32\",
+            OrderDetails.variation_volume as \"variation_volume: f32\",
+            RefundLines.quantity as \"quantity: u32\",
+            MAX(OrderDetails.quantity - OrderDetails.served_quantity
+                - OrderDetails.restocked_quantity, 0) as \"restockable!: u32\",
+            Orders.needs_review as \"needs_review: bool\"
+        FROM RefundLines
+        INNER JOIN OrderDetails ON OrderDetails.id = RefundLines.detail_id
+        INNER JOIN Orders ON Orders.id = OrderDetails.order_id
+        WHERE RefundLines.refund_id = ?",
+        refund_id
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+    for line in &lines {
+        // the stock of an order paid after running out was never taken
+        if line.needs_review {
+            continue;
+        }
+        let restocked = line.quantity.min(line.restockable);
+        if restocked == 0 {
+            continue;
+        }
+        sqlx::query!(
+            "UPDATE OrderDetails SET restocked_quantity = restocked_quantity + ? WHERE id = ?",
+            restocked,
+            line.detail_id
+        )
+        .execute(&mut **transaction)
+        .await?;
+        let volume = restocked as f32 * line.variation_volume;
+        sqlx::query!(
+            "UPDATE Products SET stock_quantity = stock_quantity + ? WHERE id = ?",
+            volume,
+            line.product_id
+        )
+        .execute(&mut **transaction)
+        .await?;
+        stock_movements::record(
+            transaction,
+            line.product_id,
+            Some(line.order_id),
+            StockMovementReason::Refund,
+            volume,
+        )
+        .await?;
+    }
+    // once fully refunded, there is nothing left to review
+    sqlx::query!(
+        "UPDATE Orders SET needs_review = FALSE
+        WHERE id = (SELECT order_id FROM Refunds WHERE id = ?) AND NOT EXISTS (
+            SELECT 1 FROM OrderDetails WHERE order_id = Orders.id AND quantity > refunded_quantity
+        )",
+        refund_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// the lines of the refund can be refunded again
+async fn settle_failed(
+    transaction: &mut Transaction<'_, Sqlite>,
+    refund_id: u32,
+) -> Result<(), ServerError> {
+    let settled = sqlx::query!(
+        "UPDATE Refunds SET status = ? WHERE id = ? AND status = ?",
+        RefundState::Failed,
+        refund_id,
+        RefundState::Pending
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+    if settled == 0 {
+        return Ok(());
+    }
+    sqlx::query!(
+        "UPDATE OrderDetails SET refunded_quantity = refunded_quantity - RefundLines.quantity
+        FROM RefundLines
+        WHERE RefundLines.refund_id = ? AND OrderDetails.id = RefundLines.detail_id",
+        refund_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// refunds whose first try got no answer, the first error is returned once every refund
+/// has been tried
+pub async fn retry_pending_refunds(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    order_events: &OrderEvents,
+) -> Result<(), ServerError> {
+    let pending = sqlx::query!(
+        "SELECT id as \"id: u32\" FROM Refunds WHERE status = ? ORDER BY id",
+        RefundState::Pending
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut first_error = None;
+    for refund in pending {
+        if let Err(e) = send_refund(pool, payment_provider.clone(), order_events, refund.id).await {
+            tracing::warn!(refund_id = refund.id, "refund still pending : {e:?}");
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// ttc, pending refunds included, orders that were never refunded are left out
+pub async fn get_refunded_amounts(
+    pool: &SqlitePool,
+    order_ids: &[OrderId],
+) -> Result<HashMap<OrderId, Money>, ServerError> {
+    let order_ids = serde_json::to_string(order_ids).expect("ids are always serializable");
+    let amounts = sqlx::query!(
+        "SELECT order_id as \"order_id: u32\", cast(SUM(amount) as int) as \"amount!: i64\"
+        FROM Refunds WHERE order_id IN (SELECT value FROM json_each(?)) AND status != ?
+        GROUP BY order_id",
+        order_ids,
+        RefundState::Failed
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| (r.order_id, Money::from_cents(r.amount)))
+    .collect();
+    Ok(amounts)
+}
+
+#[sqlx::test]
+async fn test_partial_then_full_refund(pool: SqlitePool) {
+    use crate::{
+        app::orders::{Cart, CartElement, PaymentMethod},
+        app::products::Product,
+        app::stripe::payment_intents::PaymentIntentStatus,
+        payment_provider::TestPaymentProvider,
+    };
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let cart = Cart {
+        elements: vec![
+            CartElement {
+                variation_id: 1,
+                quantity: 2,
+            },
+            CartElement {
+                variation_id: 7,
+                quantity: 1,
+            },
+        ],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart)
+        .await
+        .unwrap();
+    let mut order = Order::get(&pool, order_id).await.unwrap().unwrap();
+    let res = refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        None,
+        "test",
+    )
+    .await;
+    assert!(matches!(res, Err(OrderManagementError::OrderNotPaid)));
+
+    let payment_intent_id = order.payment_intent_id.clone().unwrap();
+    provider
+        .set_status(&payment_intent_id, PaymentIntentStatus::Succeeded)
+        .await;
+    order
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    let details = order.get_details(&pool).await.unwrap();
+    let ipa = details.iter().find(|d| d.quantity == 2).unwrap().detail_id;
+
+    let lines = vec![RefundLine {
+        detail_id: ipa,
+        quantity: 1,
+    }];
+    let amount = refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        Some(lines),
+        "renversée",
+    )
+    .await
+    .unwrap();
+    assert_eq!(amount.cents(), 984);
+    let product = Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 99.5);
+
+    let too_much = vec![RefundLine {
+        detail_id: ipa,
+        quantity: 2,
+    }];
+    let res = refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        Some(too_much),
+        "",
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(OrderManagementError::InvalidRefundQuantity(_))
+    ));
+
+    let amount = refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        None,
+        "bar fermé",
+    )
+    .await
+    .unwrap();
+    assert_eq!(amount.cents(), 984 + 780);
+    assert_eq!(
+        get_refunded_amounts(&pool, &[order.id]).await.unwrap()[&order.id].cents(),
+        2 * 984 + 780
+    );
+    let product = Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 100.0);
+    let refunds = provider.refunds.read().await.clone();
+    let sent: Vec<i64> = refunds.iter().map(|r| r.amount).collect();
+    assert_eq!(sent, [984, 984 + 780]);
+    assert!(refunds
+        .iter()
+        .all(|r| r.payment_intent_id == payment_intent_id));
+
+    let res = refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        None,
+        "",
+    )
+    .await;
+    assert!(matches!(res, Err(OrderManagementError::NothingToRefund)));
+
+    // counter orders are refunded by the waiter, only the stock and the amount are recorded
+    let counter_order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        Cart {
+            elements: vec![CartElement {
+                variation_id: 7,
+                quantity: 1,
+            }],
+        },
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &counter_order,
+        None,
+        "",
+    )
+    .await
+    .unwrap();
+    assert_eq!(provider.refunds.read().await.len(), 2);
+}
+
+#[sqlx::test]
+async fn test_refund_served_units(pool: SqlitePool) {
+    use crate::{
+        app::orders::{Cart, CartElement, PaymentMethod},
+        app::products::Product,
+        payment_provider::TestPaymentProvider,
+    };
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+    let waiter = User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(TestPaymentProvider::default()));
+    let mut order = Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        Cart {
+            elements: vec![CartElement {
+                variation_id: 1,
+                quantity: 3,
+            }],
+        },
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    let detail_id = order.get_details(&pool).await.unwrap()[0].detail_id;
+    order
+        .serve_detail(&pool, &OrderEvents::new(), &waiter, detail_id, 2)
+        .await
+        .unwrap();
+    let product = Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 98.5);
+
+    let line = |quantity| RefundLine {
+        detail_id,
+        quantity,
+    };
+    let res = refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        Some(vec![line(1), line(1)]),
+        "",
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(OrderManagementError::DuplicateRefundLine(id)) if id == detail_id
+    ));
+
+    // only the unserved pint goes back to the stock
+    refund_order(
+        &pool,
+        payment_provider.clone(),
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        Some(vec![line(2)]),
+        "",
+    )
+    .await
+    .unwrap();
+    let product = Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 99.0);
+    refund_order(
+        &pool,
+        payment_provider,
+        &OrderEvents::new(),
+        &admin,
+        &order,
+        None,
+        "",
+    )
+    .await
+    .unwrap();
+    let product = Product::get(&pool, 1).await.unwrap().unwrap();
+    assert_eq!(product.stock_quantity, 99.0);
+}
+
+#[sqlx::test]
+async fn test_refund_failures(pool: SqlitePool) {
+    use crate::{
+        app::orders::{Cart, CartElement},
+        app::products::Product,
+        payment_provider::TestPaymentProvider,
+    };
+    let admin = User::get_from_email(&pool, "elicolh@gmail.com")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 2,
+        }],
+    };
+    let order_id = Order::generate_from_cart(&pool, payment_provider.clone(), cart)
+        .await
+        .unwrap();
+    let mut order = Order::get(&pool, order_id).await.unwrap().unwrap();
+    order
+        .mark_as_paid(&pool, &OrderEvents::new())
+        .await
+        .unwrap();
+    let refunded_quantity = |pool: SqlitePool| async move {
+        sqlx::query!(
+            "SELECT refunded_quantity as \"refunded_quantity: u32\" FROM OrderDetails WHERE order_id = ?",
+            order_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .refunded_quantity
+    };
+    let order_events = OrderEvents::new();
+    let refund = || {
+        refund_order(
+            &pool,
+            payment_provider.clone(),
+            &order_events,
+            &admin,
+            &order,
+            None,
+            "",
+        )
+    };
+
+    // rejected: the lines can be refunded again
+    *provider.refund_error.write().await = Some(StatusCode::BAD_REQUEST);
+    let res = refund().await;
+    assert!(matches!(res, Err(OrderManagementError::RefundRejected(_))));
+    assert_eq!(refunded_quantity(pool.clone()).await, 0);
+    assert!(get_refunded_amounts(&pool, &[order_id])
+        .await
+        .unwrap()
+        .is_empty());
+
+    // no answer: written as pending, the stock waits for the refund to succeed
+    *provider.refund_error.write().await = Some(StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(refund().await.unwrap().cents(), 2 * 984);
+    assert_eq!(refunded_quantity(pool.clone()).await, 2);
+    assert_eq!(
+        get_refunded_amounts(&pool, &[order_id]).await.unwrap()[&order_id].cents(),
+        2 * 984
+    );
+    assert_eq!(
+        Product::get(&pool, 1)
+            .await
+            .unwrap()
+            .unwrap()
+            .stock_quantity,
+        99.0
+    );
+    assert!(
+        retry_pending_refunds(&pool, payment_provider.clone(), &OrderEvents::new())
+            .await
+            .is_err()
+    );
+
+    *provider.refund_error.write().await = None;
+    retry_pending_refunds(&pool, payment_provider.clone(), &OrderEvents::new())
+        .await
+        .unwrap();
+    retry_pending_refunds(&pool, payment_provider.clone(), &OrderEvents::new())
+        .await
+        .unwrap();
+    let refunds = provider.refunds.read().await.clone();
+    assert_eq!(refunds.len(), 1);
+    assert_eq!(refunds[0].amount, 2 * 984);
+    let statuses = sqlx::query!(
+        "SELECT status FROM Refunds WHERE order_id = ? ORDER BY id",
+        order_id
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    let statuses: Vec<&str> = statuses.iter().map(|r| r.status.as_str()).collect();
+    assert_eq!(statuses, ["failed", "succeeded"]);
+    assert_eq!(
+        Product::get(&pool, 1)
+            .await
+            .unwrap()
+            .unwrap()
+            .stock_quantity,
+        100.0
+    );
+}
@@ -1,2 +1,3 @@
 pub(crate) mod product_variations;
 pub(crate) mod products;
+pub(crate) mod stock_movements;
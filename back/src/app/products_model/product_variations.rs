@@ -1,6 +1,6 @@
 use crate::errors::ServerError;
 use serde::Serialize;
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
 
 #[derive(Debug, Serialize)]
 pub struct Variation {
@@ -46,16 +46,16 @@ impl Variation {
         Ok(res.collect())
     }
 
-    pub async fn delete(self, pool: &SqlitePool) -> Result<(), ServerError> {
+    pub async fn delete(self, conn: &mut SqliteConnection) -> Result<(), ServerError> {
         sqlx::query!("DELETE FROM ProductVariations WHERE id = ?", self.id)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         Ok(())
     }
 
     pub async fn set_price_ht(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_price_ht: i32,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -63,19 +63,23 @@ impl Variation {
             new_price_ht,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.price_ht = new_price_ht;
         Ok(())
     }
 
-    pub async fn set_tva(&mut self, pool: &SqlitePool, new_tva: f32) -> Result<(), ServerError> {
+    pub async fn set_tva(
+        &mut self,
+        conn: &mut SqliteConnection,
+        new_tva: f32,
+    ) -> Result<(), ServerError> {
         sqlx::query!(
             "UPDATE ProductVariations SET tva = ? WHERE id = ?",
             new_tva,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.tva = new_tva;
         Ok(())
@@ -83,7 +87,7 @@ impl Variation {
 
     pub async fn set_name(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_name: String,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -91,14 +95,14 @@ impl Variation {
             new_name,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.name = new_name;
         Ok(())
     }
     pub async fn set_volume(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_volume: f32,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -106,14 +110,14 @@ impl Variation {
             new_volume,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.volume = new_volume;
         Ok(())
     }
     pub async fn set_available_to_order(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_available_to_order: bool,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -121,7 +125,7 @@ impl Variation {
             new_available_to_order,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.available_to_order = new_available_to_order;
         Ok(())
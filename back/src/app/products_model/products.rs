@@ -1,6 +1,6 @@
 use crate::errors::ServerError;
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
 
 use crate::app::product_variations::Variation;
 
@@ -10,19 +10,21 @@ pub struct Product {
     pub name: String,
     pub description: String,
     pub stock_quantity: f32,
+    /// part of `stock_quantity` held by orders waiting for their payment
+    pub reserved_quantity: f32,
     pub variations: Vec<Variation>,
 }
 
 impl Product {
     pub async fn create(
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         name: String,
         description: String,
         stock_quantity: f32,
     ) -> Result<Product, ServerError> {
         //shift every product down
         sqlx::query!("UPDATE Products set position = position + 1")
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
 
         let id = sqlx::query!(
@@ -33,7 +35,7 @@ impl Product {
             description,
             stock_quantity,
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?
         .last_insert_rowid();
 
@@ -42,12 +44,13 @@ impl Product {
             name,
             description,
             stock_quantity,
+            reserved_quantity: 0.0,
             variations: vec![],
         })
     }
     pub async fn get(pool: &SqlitePool, id: u32) -> Result<Option<Product>, ServerError> {
         let res_prod = sqlx::query!(
-            "SELECT id, name, description, stock_quantity FROM Products WHERE id = ?",
+            "SELECT id, name, description, stock_quantity, reserved_quantity FROM Products WHERE id = ?",
             id
         )
         .fetch_optional(pool)
@@ -74,6 +77,7 @@ impl Product {
                 name: prod.name,
                 description: prod.description,
                 stock_quantity: prod.stock_quantity as f32,
+                reserved_quantity: prod.reserved_quantity as f32,
                 variations: variations.collect(),
             }))
         } else {
@@ -81,26 +85,26 @@ impl Product {
         }
     }
 
-    pub async fn delete(self, pool: &SqlitePool) -> Result<(), ServerError> {
+    pub async fn delete(self, conn: &mut SqliteConnection) -> Result<(), ServerError> {
         for variation in self.variations {
-            variation.delete(pool).await?;
+            variation.delete(&mut *conn).await?;
         }
         sqlx::query!("DELETE FROM Products WHERE id = ?", self.id)
-            .execute(pool)
+            .execute(&mut *conn)
             .await?;
         Ok(())
     }
 
-    pub async fn get_position(&self, pool: &SqlitePool) -> Result<i64, ServerError> {
+    pub async fn get_position(&self, conn: &mut SqliteConnection) -> Result<i64, ServerError> {
         let pos = sqlx::query!("SELECT position FROM Products WHERE id = ?", self.id)
-            .fetch_one(pool)
+            .fetch_one(&mut *conn)
             .await?
             .position;
         Ok(pos.unwrap_or(0))
     }
     pub async fn set_name(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_name: String,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -108,14 +112,14 @@ impl Product {
             new_name,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.name = new_name;
         Ok(())
     }
     pub async fn set_description(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_description: String,
     ) -> Result<(), ServerError> {
         sqlx::query!(
@@ -123,30 +127,42 @@ impl Product {
             new_description,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
         self.description = new_description;
         Ok(())
     }
     pub async fn set_stock_quantity(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         new_stock_quantity: f32,
     ) -> Result<(), ServerError> {
+        let mut transaction = conn.begin().await?;
+        sqlx::query!(
+            "INSERT INTO StockMovements (product_id, reason, volume)
+            SELECT id, 'adjustment', ? - stock_quantity FROM Products
+            WHERE id = ? AND stock_quantity != ?",
+            new_stock_quantity,
+            self.id,
+            new_stock_quantity
+        )
+        .execute(&mut *transaction)
+        .await?;
         sqlx::query!(
             "UPDATE Products SET stock_quantity = ? WHERE id = ?",
             new_stock_quantity,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *transaction)
         .await?;
+        transaction.commit().await?;
         self.stock_quantity = new_stock_quantity;
         Ok(())
     }
 
     pub async fn add_variation(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         name: String,
         price_ht: i32,
         tva: f32,
@@ -163,7 +179,7 @@ impl Product {
             volume,
             available_to_order
         )
-        .execute(pool)
+        .execute(&mut *conn)
         .await?
         .last_insert_rowid();
 
@@ -182,13 +198,13 @@ impl Product {
 
     pub async fn delete_variation(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         variation_id: u32,
     ) -> Result<(), ServerError> {
         if let Some(variation_index) = self.variations.iter().position(|v| v.id == variation_id) {
             let variation = self.variations.remove(variation_index);
             sqlx::query!("DELETE FROM ProductVariations WHERE id = ?", variation.id)
-                .execute(pool)
+                .execute(&mut *conn)
                 .await?;
         }
 
@@ -206,15 +222,16 @@ pub enum MoveDirection {
 impl Product {
     pub async fn move_product(
         &mut self,
-        pool: &SqlitePool,
+        conn: &mut SqliteConnection,
         direction: MoveDirection,
     ) -> Result<(), ServerError> {
+        let mut transaction = conn.begin().await?;
         let max_pos = sqlx::query!("SELECT MAX(position) as max_pos FROM Products",)
-            .fetch_one(pool)
+            .fetch_one(&mut *transaction)
             .await?
             .max_pos
             .unwrap_or(0);
-        let current_position = self.get_position(pool).await?;
+        let current_position = self.get_position(&mut transaction).await?;
         let new_pos = match (current_position, direction) {
             (0, MoveDirection::Up) => current_position,
             (pos, MoveDirection::Down) if pos == max_pos => current_position,
@@ -222,7 +239,6 @@ impl Product {
             (_, MoveDirection::Down) => current_position + 1,
         };
 
-        let mut transaction = pool.begin().await?;
         sqlx::query!("UPDATE Products SET position = NULL WHERE id = ?", self.id)
             .execute(&mut *transaction)
             .await?;
@@ -248,7 +264,7 @@ impl Product {
 
 pub async fn get_all(pool: &SqlitePool) -> Result<Vec<Product>, ServerError> {
     let prods = sqlx::query!(
-        "SELECT id, name, description, stock_quantity
+        "SELECT id, name, description, stock_quantity, reserved_quantity
         FROM Products ORDER BY position"
     )
     .fetch_all(pool)
@@ -276,6 +292,7 @@ pub async fn get_all(pool: &SqlitePool) -> Result<Vec<Product>, ServerError> {
             name: prod.name,
             description: prod.description,
             stock_quantity: prod.stock_quantity as f32,
+            reserved_quantity: prod.reserved_quantity as f32,
             variations: variations.collect(),
         });
     }
@@ -0,0 +1,52 @@
+use serde::Serialize;
+use sqlx::{Sqlite, Transaction};
+
+use crate::{app::orders::OrderId, errors::ServerError};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum StockMovementReason {
+    Sale,
+    /// refunded units are put back in stock
+    Refund,
+    /// the stock was set by hand
+    Adjustment,
+}
+
+/// to be called in the transaction that changes the stock, `volume` is negative when the
+/// stock decreases
+pub async fn record(
+    transaction: &mut Transaction<'_, Sqlite>,
+    product_id: u32,
+    order_id: Option<OrderId>,
+    reason: StockMovementReason,
+    volume: f32,
+) -> Result<(), ServerError> {
+    sqlx::query!(
+        "INSERT INTO StockMovements (product_id, order_id, reason, volume) VALUES (?, ?, ?, ?)",
+        product_id,
+        order_id,
+        reason,
+        volume
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// what a paid order took out of the stock, one movement per product
+pub async fn record_sale(
+    transaction: &mut Transaction<'_, Sqlite>,
+    order_id: OrderId,
+) -> Result<(), ServerError> {
+    sqlx::query!(
+        "INSERT INTO StockMovements (product_id, order_id, reason, volume)
+        SELECT product_id, order_id, 'sale', -SUM(quantity * variation_volume)
+        FROM OrderDetails WHERE order_id = ? GROUP BY product_id",
+        order_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
@@ -1,34 +1,75 @@
 use std::{collections::HashMap, env};
 
-use crate::{app::stripe::payment_intents::PaymentIntent, errors::ServerError};
-use reqwest::Client;
+use crate::{
+    app::stripe::payment_intents::PaymentIntent, errors::ServerError, monitoring, telemetry,
+};
+use reqwest::{Client, Method, RequestBuilder, Response};
 
-use super::payment_intents::PaymentIntentId;
+use super::{payment_intents::PaymentIntentId, refunds::Refund};
 
-pub type SecretKey = String;
-fn get_secret_key() -> Result<SecretKey, ServerError> {
-    let key = env::var("STRIPE_SECRET_KEY")
-        .map_err(|e| ServerError::MissingEnv("STRIPE_SECRET_KEY".into(), e))?;
-    Ok(key)
-}
+const DEFAULT_API_URL: &str = "https://api.stripe.com";
 
-pub async fn create_payment_intent(amount: i64) -> Result<PaymentIntent, ServerError> {
-    let url = "https://api.stripe.com/v1/payment_intents";
-    let client = Client::new();
+pub type SecretKey = String;
 
-    let amount = amount.to_string();
-    let mut params = HashMap::new();
-    params.insert("amount", amount.as_str()); // Amount in the smallest currency unit (e.g., cents for USD)
-    params.insert("currency", "eur"); // Currency code
-    params.insert("automatic_payment_methods[enabled]", "true"); // Payment method types
+#[derive(Clone)]
+pub struct StripeConfig {
+    pub base_url: String,
+    pub secret_key: SecretKey,
+    client: Client,
+}
+impl StripeConfig {
+    pub fn new(base_url: String, secret_key: SecretKey) -> StripeConfig {
+        StripeConfig {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            secret_key,
+            client: Client::new(),
+        }
+    }
+    /// `STRIPE_API_URL` is optional and only meant to point to a mock server
+    pub fn from_env() -> Result<StripeConfig, ServerError> {
+        let secret_key = env::var("STRIPE_SECRET_KEY")
+            .map_err(|e| ServerError::MissingEnv("STRIPE_SECRET_KEY".into(), e))?;
+        let base_url = env::var("STRIPE_API_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| DEFAULT_API_URL.to_owned());
+        Ok(StripeConfig::new(base_url, secret_key))
+    }
+    fn payment_intents_url(&self) -> String {
+        format!("{}/v1/payment_intents", self.base_url)
+    }
+    /// authenticated with the secret key, and tagged with the id of the request being handled
+    fn request(&self, method: Method, url: String) -> RequestBuilder {
+        let builder = self
+            .client
+            .request(method, url)
+            .basic_auth(&self.secret_key, Some(""));
+        match telemetry::current_request_id() {
+            Some(request_id) => builder.header(telemetry::REQUEST_ID_HEADER, request_id),
+            None => builder,
+        }
+    }
+}
 
-    let response = client
-        .post(url)
-        .basic_auth(get_secret_key()?, Some("")) // Basic auth with the secret key
-        .form(&params) // Send the parameters as a form
-        .send()
-        .await?;
+/// stripe answers with its own request id, logged to find the call in the stripe dashboard.
+/// Failures are counted per `operation`
+async fn send(operation: &'static str, builder: RequestBuilder) -> Result<Response, ServerError> {
+    let response = builder.send().await.inspect_err(|e| {
+        monitoring::count_stripe_error(operation, e.status());
+    })?;
+    if !response.status().is_success() {
+        monitoring::count_stripe_error(operation, Some(response.status()));
+    }
+    tracing::debug!(
+        url = %response.url().path(),
+        status = %response.status(),
+        stripe_request_id = ?response.headers().get("request-id"),
+        "stripe api call"
+    );
+    Ok(response)
+}
 
+async fn parse_payment_intent(response: Response) -> Result<PaymentIntent, ServerError> {
     // Check if the request was successful
     if response.status().is_success() {
         // Deserialize the response into the PaymentIntent struct
@@ -41,70 +82,73 @@ pub async fn create_payment_intent(amount: i64) -> Result<PaymentIntent, ServerE
         Err(ServerError::StripeApi(status, body))
     }
 }
+
+pub async fn create_payment_intent(
+    config: &StripeConfig,
+    amount: i64,
+) -> Result<PaymentIntent, ServerError> {
+    let amount = amount.to_string();
+    let mut params = HashMap::new();
+    params.insert("amount", amount.as_str()); // Amount in the smallest currency unit (e.g., cents for USD)
+    params.insert("currency", "eur"); // Currency code
+    params.insert("automatic_payment_methods[enabled]", "true"); // Payment method types
+
+    let response = send(
+        "create_payment_intent",
+        config
+            .request(Method::POST, config.payment_intents_url())
+            .form(&params), // Send the parameters as a form
+    )
+    .await?;
+
+    parse_payment_intent(response).await
+}
 pub async fn fetch_payment_intent(
+    config: &StripeConfig,
     payment_intent_id: &PaymentIntentId,
 ) -> Result<PaymentIntent, ServerError> {
-    let url = format!(
-        "https://api.stripe.com/v1/payment_intents/{}",
-        payment_intent_id
-    );
-    let client = Client::new();
+    let url = format!("{}/{}", config.payment_intents_url(), payment_intent_id);
 
-    let response = client
-        .get(url)
-        .basic_auth(get_secret_key()?, Some("")) // Basic auth with the secret key
-        .send()
-        .await?;
+    let response = send("fetch_payment_intent", config.request(Method::GET, url)).await?;
 
-    // Check if the request was successful
-    if response.status().is_success() {
-        // Deserialize the response into the PaymentIntent struct
-        let payment_intent: PaymentIntent = response.json().await?;
-        Ok(payment_intent)
-    } else {
-        // If the request failed, print the status and body
-        let status = response.status();
-        let body = response.text().await?;
-        Err(ServerError::StripeApi(status, body))
-    }
+    parse_payment_intent(response).await
 }
 
 pub async fn push_metadata(
+    config: &StripeConfig,
     payment_intent_id: &PaymentIntentId,
     key: &str,
     value: &str,
 ) -> Result<(), ServerError> {
-    let (payment_intent_id, key, value) =
-        (payment_intent_id.clone(), key.to_owned(), value.to_owned());
-    tokio::spawn(async move {
-        let url = format!(
-            "https://api.stripe.com/v1/payment_intents/{}?metadata[{}]={}",
-            payment_intent_id, key, value
-        );
-        let client = Client::new();
-
-        client
-            .post(url)
-            .basic_auth(get_secret_key().unwrap(), Some("")) // Basic auth with the secret key
-            .send()
-            .await
-            .unwrap();
-    });
+    let url = format!("{}/{}", config.payment_intents_url(), payment_intent_id);
+    let params = HashMap::from([(format!("metadata[{key}]"), value.to_owned())]);
+
+    let response = send(
+        "push_metadata",
+        config.request(Method::POST, url).form(&params),
+    )
+    .await?;
+    parse_payment_intent(response).await?;
     Ok(())
 }
 
-pub async fn mark_as_canceled(payment_intent_id: &PaymentIntentId) -> Result<(), ServerError> {
+pub async fn mark_as_canceled(
+    config: &StripeConfig,
+    payment_intent_id: &PaymentIntentId,
+) -> Result<(), ServerError> {
     let url = format!(
-        "https://api.stripe.com/v1/payment_intents/{}/cancel?cancellation_reason=abandoned",
+        "{}/{}/cancel",
+        config.payment_intents_url(),
         payment_intent_id
     );
-    let client = Client::new();
 
-    let response = client
-        .post(url)
-        .basic_auth(get_secret_key()?, Some("")) // Basic auth with the secret key
-        .send()
-        .await?;
+    let response = send(
+        "cancel_payment_intent",
+        config
+            .request(Method::POST, url)
+            .form(&[("cancellation_reason", "abandoned")]),
+    )
+    .await?;
     if response.status().is_success() {
         Ok(())
     } else {
@@ -114,3 +158,38 @@ pub async fn mark_as_canceled(payment_intent_id: &PaymentIntentId) -> Result<(),
         Err(ServerError::StripeApi(status, body))
     }
 }
+
+/// `amount` is in cents, stripe refuses it if it exceeds what is left to refund
+pub async fn create_refund(
+    config: &StripeConfig,
+    payment_intent_id: &PaymentIntentId,
+    amount: i64,
+    reason: &str,
+    idempotency_key: &str,
+) -> Result<Refund, ServerError> {
+    let url = format!("{}/v1/refunds", config.base_url);
+    let amount = amount.to_string();
+    let params = [
+        ("payment_intent", payment_intent_id.as_str()),
+        ("amount", amount.as_str()),
+        ("metadata[raison]", reason),
+    ];
+
+    let response = send(
+        "create_refund",
+        config
+            .request(Method::POST, url)
+            .header("Idempotency-Key", idempotency_key)
+            .form(&params),
+    )
+    .await?;
+    if response.status().is_success() {
+        let refund: Refund = response.json().await?;
+        Ok(refund)
+    } else {
+        // If the request failed, print the status and body
+        let status = response.status();
+        let body = response.text().await?;
+        Err(ServerError::StripeApi(status, body))
+    }
+}
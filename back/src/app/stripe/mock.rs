@@ -0,0 +1,174 @@
+//! minimal in-process imitation of the stripe payment_intents and refunds endpoints, used by the tests
+//! through `StripeConfig::new(mock.base_url.clone(), ..)`
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use serde_json::json;
+use tokio::{net::TcpListener, sync::RwLock};
+
+use super::payment_intents::{PaymentIntentId, PaymentIntentStatus};
+
+#[derive(Clone)]
+pub struct MockPaymentIntent {
+    pub amount: i64,
+    pub status: PaymentIntentStatus,
+    pub metadata: HashMap<String, String>,
+    pub amount_refunded: i64,
+}
+
+type MockState = Arc<RwLock<HashMap<PaymentIntentId, MockPaymentIntent>>>;
+
+pub struct StripeMock {
+    pub base_url: String,
+    pub payment_intents: MockState,
+}
+
+impl StripeMock {
+    pub async fn start() -> StripeMock {
+        let payment_intents: MockState = Default::default();
+        let app = Router::new()
+            .route("/v1/payment_intents", post(create))
+            .route("/v1/payment_intents/:id", get(retrieve).post(update))
+            .route("/v1/payment_intents/:id/cancel", post(cancel))
+            .route("/v1/refunds", post(refund))
+            .with_state(payment_intents.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        StripeMock {
+            base_url,
+            payment_intents,
+        }
+    }
+
+    /// simulates the customer going through the stripe checkout
+    pub async fn set_status(&self, id: &str, status: PaymentIntentStatus) {
+        if let Some(intent) = self.payment_intents.write().await.get_mut(id) {
+            intent.status = status;
+        }
+    }
+}
+
+fn to_json(id: &str, intent: &MockPaymentIntent) -> Response {
+    Json(json!({
+        "id": id,
+        "object": "payment_intent",
+        "client_secret": format!("{id}_secret_mock"),
+        "amount": intent.amount,
+        "currency": "eur",
+        "status": intent.status,
+        "metadata": intent.metadata,
+    }))
+    .into_response()
+}
+
+fn not_found(id: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": {"message": format!("No such payment_intent: '{id}'")}})),
+    )
+        .into_response()
+}
+
+async fn create(
+    State(state): State<MockState>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Response {
+    let Some(amount) = params.get("amount").and_then(|a| a.parse().ok()) else {
+        return (StatusCode::BAD_REQUEST, "missing amount").into_response();
+    };
+    let mut intents = state.write().await;
+    let id = format!("pi_mock_{}", intents.len() + 1);
+    let intent = MockPaymentIntent {
+        amount,
+        status: PaymentIntentStatus::RequiresPaymentMethod,
+        metadata: HashMap::new(),
+        amount_refunded: 0,
+    };
+    let res = to_json(&id, &intent);
+    intents.insert(id, intent);
+    res
+}
+
+async fn retrieve(State(state): State<MockState>, Path(id): Path<String>) -> Response {
+    match state.read().await.get(&id) {
+        Some(intent) => to_json(&id, intent),
+        None => not_found(&id),
+    }
+}
+
+async fn update(
+    State(state): State<MockState>,
+    Path(id): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Response {
+    let mut intents = state.write().await;
+    let Some(intent) = intents.get_mut(&id) else {
+        return not_found(&id);
+    };
+    for (key, value) in params {
+        if let Some(key) = key
+            .strip_prefix("metadata[")
+            .and_then(|k| k.strip_suffix(']'))
+        {
+            intent.metadata.insert(key.to_owned(), value);
+        }
+    }
+    to_json(&id, intent)
+}
+
+async fn cancel(State(state): State<MockState>, Path(id): Path<String>) -> Response {
+    let mut intents = state.write().await;
+    let Some(intent) = intents.get_mut(&id) else {
+        return not_found(&id);
+    };
+    if intent.status == PaymentIntentStatus::Succeeded {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": {"code": "payment_intent_unexpected_state"}})),
+        )
+            .into_response();
+    }
+    intent.status = PaymentIntentStatus::Canceled;
+    to_json(&id, intent)
+}
+
+async fn refund(
+    State(state): State<MockState>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Response {
+    let id = params.get("payment_intent").cloned().unwrap_or_default();
+    let mut intents = state.write().await;
+    let Some(intent) = intents.get_mut(&id) else {
+        return not_found(&id);
+    };
+    let amount = params
+        .get("amount")
+        .and_then(|a| a.parse().ok())
+        .unwrap_or(intent.amount - intent.amount_refunded);
+    if intent.status != PaymentIntentStatus::Succeeded
+        || intent.amount_refunded + amount > intent.amount
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": {"code": "charge_already_refunded"}})),
+        )
+            .into_response();
+    }
+    intent.amount_refunded += amount;
+    Json(json!({
+        "id": format!("re_mock_{id}_{}", intent.amount_refunded),
+        "object": "refund",
+        "amount": amount,
+        "payment_intent": id,
+        "status": "succeeded",
+    }))
+    .into_response()
+}
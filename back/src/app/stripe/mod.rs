@@ -1,2 +1,6 @@
 pub(crate) mod api;
+#[cfg(test)]
+pub(crate) mod mock;
 pub(crate) mod payment_intents;
+pub(crate) mod refunds;
+pub(crate) mod webhooks;
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 pub type PaymentIntentId = String;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum PaymentIntentStatus {
     Canceled,
@@ -14,7 +14,7 @@ pub enum PaymentIntentStatus {
     Succeeded,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct PaymentIntent {
     pub id: PaymentIntentId,
     pub client_secret: String,
@@ -0,0 +1,19 @@
+use serde::Deserialize;
+
+pub type RefundId = String;
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundStatus {
+    Canceled,
+    Failed,
+    Pending,
+    RequiresAction,
+    Succeeded,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Refund {
+    pub id: RefundId,
+    pub status: RefundStatus,
+}
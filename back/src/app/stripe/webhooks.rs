@@ -0,0 +1,154 @@
+use std::env::{self, VarError};
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::types::time::OffsetDateTime;
+
+use crate::errors::{ServerError, WebhookError};
+
+use super::payment_intents::PaymentIntent;
+
+/// maximum age of a signed event, same default as the official stripe libraries
+const SIGNATURE_TOLERANCE_SECS: i64 = 5 * 60;
+
+/// an empty secret would let anyone sign events, it is refused like a missing one
+pub fn get_webhook_secret() -> Result<String, ServerError> {
+    env::var("STRIPE_WEBHOOK_SECRET")
+        .and_then(|secret| {
+            if secret.is_empty() {
+                Err(VarError::NotPresent)
+            } else {
+                Ok(secret)
+            }
+        })
+        .map_err(|e| ServerError::MissingEnv("STRIPE_WEBHOOK_SECRET".into(), e))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventData {
+    pub object: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub data: EventData,
+}
+
+/// checks the `Stripe-Signature` header (`t=...,v1=...`) against the raw request body
+/// and parses the event
+pub fn construct_event(payload: &str, signature_header: &str) -> Result<Event, WebhookError> {
+    let secret = get_webhook_secret()?;
+    verify_signature(
+        payload,
+        signature_header,
+        &secret,
+        OffsetDateTime::now_utc().unix_timestamp(),
+    )?;
+    serde_json::from_str(payload).map_err(WebhookError::InvalidPayload)
+}
+
+impl Event {
+    pub fn payment_intent(self) -> Result<PaymentIntent, WebhookError> {
+        serde_json::from_value(self.data.object).map_err(WebhookError::InvalidPayload)
+    }
+}
+
+fn verify_signature(
+    payload: &str,
+    signature_header: &str,
+    secret: &str,
+    now: i64,
+) -> Result<(), WebhookError> {
+    if secret.is_empty() {
+        return Err(WebhookError::InvalidSignature);
+    }
+    let mut timestamp: Option<i64> = None;
+    let mut signatures: Vec<Vec<u8>> = vec![];
+    for part in signature_header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", t)) => timestamp = t.parse().ok(),
+            Some(("v1", sig)) => {
+                if let Ok(sig) = hex::decode(sig) {
+                    signatures.push(sig);
+                }
+            }
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or(WebhookError::InvalidSignatureHeader)?;
+    if signatures.is_empty() {
+        return Err(WebhookError::InvalidSignatureHeader);
+    }
+    if (now - timestamp).abs() > SIGNATURE_TOLERANCE_SECS {
+        return Err(WebhookError::TimestampOutOfTolerance);
+    }
+
+    let signed_payload = format!("{timestamp}.{payload}");
+    let matches = signatures.iter().any(|sig| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("hmac accepts keys of any size");
+        mac.update(signed_payload.as_bytes());
+        mac.verify_slice(sig).is_ok()
+    });
+    if matches {
+        Ok(())
+    } else {
+        Err(WebhookError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+fn sign(payload: &str, secret: &str, timestamp: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    format!(
+        "t={timestamp},v1={}",
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+#[test]
+fn test_verify_signature() {
+    let payload = r#"{"id": "evt_1"}"#;
+    let header = sign(payload, "whsec_test", 1_700_000_000);
+    assert!(verify_signature(payload, &header, "whsec_test", 1_700_000_010).is_ok());
+
+    assert!(matches!(
+        verify_signature(payload, &header, "whsec_other", 1_700_000_010),
+        Err(WebhookError::InvalidSignature)
+    ));
+    assert!(matches!(
+        verify_signature(r#"{"id": "evt_2"}"#, &header, "whsec_test", 1_700_000_010),
+        Err(WebhookError::InvalidSignature)
+    ));
+    assert!(matches!(
+        verify_signature(payload, &header, "whsec_test", 1_700_001_000),
+        Err(WebhookError::TimestampOutOfTolerance)
+    ));
+    assert!(matches!(
+        verify_signature(payload, "v1=abcd", "whsec_test", 1_700_000_010),
+        Err(WebhookError::InvalidSignatureHeader)
+    ));
+}
+
+#[test]
+fn test_verify_signature_multiple_v1() {
+    let payload = r#"{"id": "evt_1"}"#;
+    let header = sign(payload, "whsec_test", 1_700_000_000);
+    let header = format!("{header},v1=0000,v0=ffff");
+    assert!(verify_signature(payload, &header, "whsec_test", 1_700_000_000).is_ok());
+}
+
+#[test]
+fn test_verify_signature_empty_secret() {
+    let payload = r#"{"id": "evt_1", "type": "payment_intent.succeeded"}"#;
+    let header = sign(payload, "", 1_700_000_000);
+    assert!(matches!(
+        verify_signature(payload, &header, "", 1_700_000_000),
+        Err(WebhookError::InvalidSignature)
+    ));
+}
@@ -0,0 +1,25 @@
+use axum::{http::StatusCode, response::IntoResponse};
+use thiserror::Error;
+
+use super::{ErrorResponse, ServerError};
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("invalid date provided")]
+    InvalidDate,
+    #[error("server error")]
+    ServerError(#[from] ServerError),
+}
+impl IntoResponse for AuditError {
+    fn into_response(self) -> axum::response::Response {
+        if let Self::ServerError(e) = self {
+            e.into_response()
+        } else {
+            let status = match self {
+                Self::InvalidDate => StatusCode::BAD_REQUEST,
+                Self::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, ErrorResponse::json(self.to_string())).into_response()
+        }
+    }
+}
@@ -16,8 +16,15 @@ pub use order_errors::SendReceiptEmailError;
 mod stock_management_errors;
 pub use stock_management_errors::ManageStockError;
 
+mod outbox_errors;
+pub use outbox_errors::OutboxError;
+
+mod audit_errors;
+pub use audit_errors::AuditError;
+
 mod payment_errors;
 pub use payment_errors::PaymentIntentError;
+pub use payment_errors::WebhookError;
 
 #[derive(Serialize)]
 pub struct ErrorResponse {
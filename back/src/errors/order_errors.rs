@@ -1,6 +1,9 @@
 use axum::{http::StatusCode, response::IntoResponse};
+use sqlx::types::time::OffsetDateTime;
 use thiserror::Error;
 
+use crate::utils::format_time;
+
 use super::{ErrorResponse, ServerError};
 
 #[derive(Error, Debug)]
@@ -15,6 +18,8 @@ pub enum OrderProcessError {
     VariationNotFound(u32),
     #[error("la commande est vide")]
     EmptyOrder,
+    #[error("ce moyen de paiement n'est pas accepté au comptoir")]
+    InvalidPaymentMethod,
     #[error("server error")]
     ServerError(#[from] ServerError),
 }
@@ -27,7 +32,8 @@ impl IntoResponse for OrderProcessError {
                 Self::NotEnoughStock(_, _)
                 | Self::ProductNotFound(_)
                 | Self::VariationNotFound(_)
-                | Self::EmptyOrder => StatusCode::BAD_REQUEST,
+                | Self::EmptyOrder
+                | Self::InvalidPaymentMethod => StatusCode::BAD_REQUEST,
                 Self::BarIsClosed => StatusCode::SERVICE_UNAVAILABLE,
                 Self::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             };
@@ -43,6 +49,30 @@ pub enum OrderManagementError {
     InvalidDate,
     #[error("order not found")]
     OrderNotFound,
+    #[error("order has not been paid")]
+    OrderNotPaid,
+    #[error("order has nothing left to refund")]
+    NothingToRefund,
+    #[error("order detail not found (id = {0})")]
+    OrderDetailNotFound(u32),
+    #[error("invalid quantity to refund for order detail {0}")]
+    InvalidRefundQuantity(u32),
+    #[error("order detail {0} appears several times in the refund")]
+    DuplicateRefundLine(u32),
+    #[error("refund {0} was rejected by the payment provider")]
+    RefundRejected(u32),
+    #[error("invalid quantity to serve for order detail {0}")]
+    InvalidServeQuantity(u32),
+    #[error("bar opening not found (id = {0})")]
+    BarOpeningNotFound(u32),
+    #[error("invalid search cursor")]
+    InvalidCursor,
+    #[error("invalid or forged QR code")]
+    InvalidQrCode,
+    #[error("order already served at {} by {by}", format_time(*at))]
+    AlreadyServed { at: OffsetDateTime, by: String },
+    #[error("order already claimed at {} by {by}", format_time(*at))]
+    AlreadyClaimed { at: OffsetDateTime, by: String },
     #[error("server error")]
     ServerError(#[from] ServerError),
 }
@@ -52,7 +82,19 @@ impl IntoResponse for OrderManagementError {
             e.into_response()
         } else {
             let status = match self {
-                Self::InvalidDate | Self::OrderNotFound => StatusCode::BAD_REQUEST,
+                Self::InvalidDate
+                | Self::OrderNotFound
+                | Self::OrderNotPaid
+                | Self::NothingToRefund
+                | Self::OrderDetailNotFound(_)
+                | Self::InvalidRefundQuantity(_)
+                | Self::DuplicateRefundLine(_)
+                | Self::InvalidServeQuantity(_)
+                | Self::BarOpeningNotFound(_)
+                | Self::InvalidCursor
+                | Self::InvalidQrCode => StatusCode::BAD_REQUEST,
+                Self::AlreadyServed { .. } | Self::AlreadyClaimed { .. } => StatusCode::CONFLICT,
+                Self::RefundRejected(_) => StatusCode::BAD_GATEWAY,
                 Self::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             };
             (status, ErrorResponse::json(self.to_string())).into_response()
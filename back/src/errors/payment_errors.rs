@@ -38,3 +38,36 @@ impl IntoResponse for PaymentIntentError {
         }
     }
 }
+
+#[derive(Error, Debug)]
+pub enum WebhookError {
+    #[error("missing Stripe-Signature header")]
+    MissingSignature,
+    #[error("malformed Stripe-Signature header")]
+    InvalidSignatureHeader,
+    #[error("the webhook signature does not match the payload")]
+    InvalidSignature,
+    #[error("the webhook timestamp is too old")]
+    TimestampOutOfTolerance,
+    #[error("invalid webhook payload: {0}")]
+    InvalidPayload(serde_json::Error),
+    #[error("server error")]
+    ServerError(#[from] ServerError),
+}
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> axum::response::Response {
+        if let WebhookError::ServerError(e) = self {
+            e.into_response()
+        } else {
+            let status = match self {
+                Self::MissingSignature
+                | Self::InvalidSignatureHeader
+                | Self::InvalidSignature
+                | Self::TimestampOutOfTolerance
+                | Self::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+                Self::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, Json(json!({"error": self.to_string()}))).into_response()
+        }
+    }
+}
@@ -28,16 +28,23 @@ pub enum ServerError {
     EmailBuild(#[from] lettre::error::Error),
     #[error("email send error")]
     EmailSend(#[from] lettre::transport::smtp::Error),
+    #[error("RECEIPT_SIGNING_KEYS must be a list of `id:secret` separated by commas")]
+    InvalidReceiptKeys,
+    #[error("RECEIPT_SIGNING_KEYS still holds the placeholder secret of .env.template")]
+    PlaceholderReceiptKey,
+    #[error("invalid LOG_LEVEL or LOG_FORMAT : {0}")]
+    InvalidLogConfig(String),
     #[error("could not generate qr code")]
     QrCode(#[from] qrcode::types::QrError),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("csv error")]
+    Csv(#[from] csv::Error),
 }
 
 impl IntoResponse for ServerError {
     fn into_response(self) -> axum::response::Response {
-        eprintln!("----");
-        eprintln!("Internal server error : {self:?}");
-        eprintln!("{self}");
-        eprintln!("----");
+        tracing::error!(error = ?self, "internal server error : {self}");
 
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -2,11 +2,15 @@ use std::env;
 
 use axum::async_trait;
 use lettre::{
-    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
-    AsyncTransport, Message, Tokio1Executor,
+    message::{
+        header::{HeaderName, HeaderValue},
+        Mailbox,
+    },
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
 };
 
-use crate::errors::ServerError;
+use crate::{errors::ServerError, monitoring, telemetry};
 
 #[async_trait]
 pub trait MailManager: Send + Sync {
@@ -18,7 +22,14 @@ pub struct GmailManager {}
 
 #[async_trait]
 impl MailManager for GmailManager {
-    async fn send_mail(&self, message: Message) -> Result<(), ServerError> {
+    async fn send_mail(&self, mut message: Message) -> Result<(), ServerError> {
+        let request_id = telemetry::current_request_id();
+        if let Some(request_id) = &request_id {
+            message.headers_mut().insert_raw(HeaderValue::new(
+                HeaderName::new_from_ascii_str("X-Request-Id"),
+                request_id.clone(),
+            ));
+        }
         let mailer: AsyncSmtpTransport<Tokio1Executor>;
         #[cfg(not(feature = "local-smtp-testing"))]
         {
@@ -46,8 +57,17 @@ impl MailManager for GmailManager {
         }
 
         // Send the email
-        mailer.send(message).await?;
-        println!("sent");
+        let to: Vec<String> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        mailer
+            .send(message)
+            .await
+            .inspect_err(|_| monitoring::count_mail_failure())?;
+        tracing::info!(?request_id, ?to, "mail sent");
         Ok(())
     }
     fn get_sender(&self) -> Result<Mailbox, ServerError> {
@@ -3,29 +3,81 @@ mod app;
 mod utils;
 
 use admin::challenge::ChallengeManager;
+use app::{
+    receipt::ReceiptKeys,
+    stripe::{api::StripeConfig, webhooks},
+};
 mod errors;
 mod mail_manager;
+mod monitoring;
+mod outbox;
+mod payment_provider;
+mod pdf;
 mod routes;
+mod scheduler;
+mod telemetry;
 
 use axum::{middleware, Router};
 use errors::ServerError;
 use mail_manager::{GmailManager, MailManager};
+use payment_provider::{PaymentProvider, StripeProvider};
 use routes::generate_app_state;
+use scheduler::{
+    CancelExpiredOrders, DeliverOutbox, PurgeDeliveredOutbox, PurgeSessions,
+    ReleaseExpiredReservations, RetryPendingRefunds, RetryPolicy, Scheduler,
+};
 use std::sync::Arc;
 use tokio::signal;
-use tower_http::services::{ServeDir, ServeFile};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::Level;
 
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
     dotenvy::dotenv().expect("could not load env from .env file");
+    telemetry::init()?;
+    monitoring::install();
     let pool = utils::setup_db_and_migrate().await;
     let challenge_manager = ChallengeManager::new();
     let mail_manager: Arc<Box<dyn MailManager>> = Arc::new(Box::new(GmailManager {}));
-    let state = generate_app_state(challenge_manager, pool, mail_manager);
+    let payment_provider: Arc<Box<dyn PaymentProvider>> =
+        Arc::new(Box::new(StripeProvider::new(StripeConfig::from_env()?)));
+    webhooks::get_webhook_secret()?;
+    ReceiptKeys::from_env()?;
+    let state = generate_app_state(
+        challenge_manager,
+        pool.clone(),
+        mail_manager.clone(),
+        payment_provider.clone(),
+        routes::metrics::token_from_env(),
+    );
+    Scheduler::new(RetryPolicy::default())
+        .add_job(CancelExpiredOrders {
+            pool: pool.clone(),
+            payment_provider: payment_provider.clone(),
+            order_events: state.order_events.clone(),
+        })
+        .add_job(ReleaseExpiredReservations { pool: pool.clone() })
+        .add_job(PurgeSessions { pool: pool.clone() })
+        .add_job(DeliverOutbox {
+            pool: pool.clone(),
+            payment_provider: payment_provider.clone(),
+            mail_manager,
+        })
+        .add_job(PurgeDeliveredOutbox { pool: pool.clone() })
+        .add_job(RetryPendingRefunds {
+            pool,
+            payment_provider,
+            order_events: state.order_events.clone(),
+        })
+        .start();
 
     let app = Router::new()
         .nest("/api", routes::customer::get_router())
         .nest("/api/admin", routes::admin::get_router())
+        .nest("/metrics", routes::metrics::get_router())
         .nest_service("/", ServeDir::new("dist"))
         .nest_service("/login", ServeFile::new("dist/index.html"))
         .nest_service("/checkout", ServeFile::new("dist/index.html"))
@@ -34,10 +86,17 @@ async fn main() -> Result<(), ServerError> {
         .nest_service("/admin", ServeFile::new("dist/index.html"))
         .fallback(routes::reponders::handler_404)
         .with_state(state)
+        .layer(middleware::from_fn(monitoring::track_http))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(telemetry::make_request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(middleware::from_fn(telemetry::request_id))
         .layer(middleware::from_fn(routes::cors::cors));
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    println!("listening on port 8000");
+    tracing::info!("listening on port 8000");
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await
@@ -61,5 +120,5 @@ async fn shutdown_signal() {
         () = ctrl_c => {},
         () = terminate => {},
     }
-    println!("Terminate signal received");
+    tracing::info!("terminate signal received");
 }
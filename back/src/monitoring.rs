@@ -0,0 +1,181 @@
+//! metrics in the prometheus format, served on `/metrics`. Counters are incremented where
+//! things happen, the gauges describing the bar are computed when the endpoint is scraped
+
+use std::{sync::OnceLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::{types::time::OffsetDateTime, SqlitePool};
+
+use crate::{
+    admin::{bar_management::Bar, report::Report},
+    app::{money::Money, orders},
+    errors::ServerError,
+};
+
+const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+const ORDERS: &str = "orders_total";
+const STRIPE_API_ERRORS: &str = "stripe_api_errors_total";
+const MAIL_SEND_FAILURES: &str = "mail_send_failures_total";
+const BAR_OPEN: &str = "bar_open";
+const SESSION_REVENUE: &str = "bar_session_revenue_euros";
+const UNSERVED_ORDERS: &str = "orders_unserved";
+
+/// from 5ms to 10s
+const HTTP_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// sets the global recorder on the first call, every metric recorded before is lost
+pub fn install() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(HTTP_REQUEST_DURATION.to_owned()),
+                &HTTP_BUCKETS,
+            )
+            .expect("the buckets are not empty")
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::set_global_recorder(recorder).expect("no other metrics recorder is installed");
+        handle
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderStage {
+    Created,
+    Paid,
+    /// by the customer, before paying
+    Canceled,
+    /// never paid and canceled by the scheduler
+    Expired,
+}
+impl OrderStage {
+    fn as_str(self) -> &'static str {
+        match self {
+            OrderStage::Created => "created",
+            OrderStage::Paid => "paid",
+            OrderStage::Canceled => "canceled",
+            OrderStage::Expired => "expired",
+        }
+    }
+}
+
+pub fn count_order(stage: OrderStage) {
+    counter!(ORDERS, "stage" => stage.as_str()).increment(1);
+}
+
+/// `status` is `None` when stripe could not be reached
+pub fn count_stripe_error(operation: &'static str, status: Option<StatusCode>) {
+    let status = status.map_or_else(|| "unreachable".to_owned(), |s| s.as_u16().to_string());
+    counter!(STRIPE_API_ERRORS, "operation" => operation, "status" => status).increment(1);
+}
+
+pub fn count_mail_failure() {
+    counter!(MAIL_SEND_FAILURES).increment(1);
+}
+
+/// labelled with the route rather than the path, so that ids in paths do not make a new
+/// series each. Requests that matched no route are grouped together
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    histogram!(
+        HTTP_REQUEST_DURATION,
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    )
+    .record(start.elapsed().as_secs_f64());
+    response
+}
+
+/// the revenue is what was cashed since the bar opened, refunds deducted
+pub async fn update_bar_gauges(pool: &SqlitePool) -> Result<(), ServerError> {
+    let bar = Bar::get(pool).await?;
+    let revenue = if bar.is_open {
+        Report::for_period(pool, bar.open_since, OffsetDateTime::now_utc())
+            .await?
+            .takings()
+            .ttc
+    } else {
+        Money::ZERO
+    };
+    let unserved = orders::get_preparation_queue(pool).await?.len();
+    gauge!(BAR_OPEN).set(if bar.is_open { 1.0 } else { 0.0 });
+    gauge!(SESSION_REVENUE).set(revenue.cents() as f64 / 100.0);
+    gauge!(UNSERVED_ORDERS).set(unserved as f64);
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_metrics(pool: SqlitePool) {
+    use crate::app::{
+        order_events::OrderEvents,
+        orders::{Cart, CartElement, Order, PaymentMethod},
+    };
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+    let mut conn = pool.acquire().await.unwrap();
+    let waiter = crate::admin::user::User::get_from_email(&pool, "eli.sauvage@utt.fr")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let handle = install();
+    let mut bar = Bar::get(&pool).await.unwrap();
+    bar.open(&mut conn).await.unwrap();
+    let cart = Cart {
+        elements: vec![CartElement {
+            variation_id: 1,
+            quantity: 2,
+        }],
+    };
+    Order::generate_from_counter(
+        &pool,
+        &OrderEvents::new(),
+        &waiter,
+        cart,
+        PaymentMethod::Cash,
+    )
+    .await
+    .unwrap();
+    update_bar_gauges(&pool).await.unwrap();
+
+    let app = Router::new()
+        .nest(
+            "/api",
+            Router::new().route("/orders/:id", get(|| async { "ok" })),
+        )
+        .layer(middleware::from_fn(track_http));
+    let request = Request::builder()
+        .uri("/api/orders/42")
+        .body(Body::empty())
+        .unwrap();
+    app.oneshot(request).await.unwrap();
+
+    let metrics = handle.render();
+    assert!(metrics.contains("bar_open 1\n"));
+    assert!(metrics.contains("bar_session_revenue_euros 19.68\n"));
+    assert!(metrics.contains("orders_unserved 1\n"));
+    // other tests create orders at the same time, only their presence can be checked
+    assert!(metrics.contains("orders_total{stage=\"created\"}"));
+    assert!(metrics.contains("orders_total{stage=\"paid\"}"));
+    assert!(metrics.contains(
+        "http_request_duration_seconds_count{method=\"GET\",route=\"/api/orders/:id\",status=\"200\"} 1\n"
+    ));
+}
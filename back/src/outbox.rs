@@ -0,0 +1,401 @@
+use std::{path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{types::time::OffsetDateTime, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use tracing::Instrument;
+
+use crate::{
+    admin::{
+        bar_management::BarOpeningId,
+        report_archive::{self, REPORTS_DIR_PATH},
+    },
+    app::{
+        mail,
+        orders::{Order, OrderId},
+        stripe::payment_intents::PaymentIntentId,
+    },
+    errors::{SendReceiptEmailError, ServerError},
+    mail_manager::MailManager,
+    payment_provider::PaymentProvider,
+    telemetry,
+    utils::serialize_time,
+};
+
+/// a message is given up after this many failed deliveries and waits for an admin to replay it
+const MAX_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF_SECS: u32 = 10;
+const BATCH_SIZE: u32 = 50;
+/// delivered messages are kept this long, to look into what was sent
+const DELIVERED_RETENTION_DAYS: u32 = 30;
+
+/// side effects that must happen once the state change they belong to is committed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMessage {
+    StripeMetadata {
+        payment_intent_id: PaymentIntentId,
+        key: String,
+        value: String,
+    },
+    ReceiptMail {
+        order_id: OrderId,
+    },
+    ArchiveReport {
+        bar_opening_id: BarOpeningId,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    Pending,
+    Delivered,
+    Dead,
+}
+
+pub type OutboxMessageId = u32;
+
+#[derive(Serialize)]
+pub struct OutboxEntry {
+    pub id: OutboxMessageId,
+    pub message: OutboxMessage,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub created_at: OffsetDateTime,
+}
+
+/// the message is only written if the transaction is committed
+pub async fn enqueue(
+    transaction: &mut Transaction<'_, Sqlite>,
+    message: &OutboxMessage,
+) -> Result<(), ServerError> {
+    let payload = serde_json::to_string(message)?;
+    let request_id = telemetry::current_request_id();
+    sqlx::query!(
+        "INSERT INTO Outbox (payload, request_id) VALUES (?, ?)",
+        payload,
+        request_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// most recent first, `before` is the id of the last entry of the previous page. Without a
+/// status, only the messages that are not delivered yet are listed
+pub async fn get_entries(
+    pool: &SqlitePool,
+    status: Option<OutboxStatus>,
+    before: Option<OutboxMessageId>,
+    limit: u32,
+) -> Result<Vec<OutboxEntry>, ServerError> {
+    let rows = sqlx::query!(
+        "SELECT
+            id as \"id: u32\",
+            payload,
+            status as \"status: OutboxStatus\",
+            attempts as \"attempts: u32\",
+            last_error,
+            created_at
+        FROM Outbox
+        WHERE ((? IS NULL AND status != 'delivered') OR status = ?) AND (? IS NULL OR id < ?)
+        ORDER BY id DESC LIMIT ?",
+        status,
+        status,
+        before,
+        before,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut entries = vec![];
+    for row in rows {
+        entries.push(OutboxEntry {
+            id: row.id,
+            message: serde_json::from_str(&row.payload)?,
+            status: row.status,
+            attempts: row.attempts,
+            last_error: row.last_error,
+            created_at: row.created_at,
+        });
+    }
+    Ok(entries)
+}
+
+/// puts a dead message back in the queue, returns false if there is no such dead message
+pub async fn replay(conn: &mut SqliteConnection, id: OutboxMessageId) -> Result<bool, ServerError> {
+    let updated = sqlx::query!(
+        "UPDATE Outbox SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND status = 'dead'",
+        id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    Ok(updated == 1)
+}
+
+/// returns how many messages were deleted
+pub async fn purge_delivered(pool: &SqlitePool) -> Result<u64, ServerError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM Outbox
+        WHERE status = 'delivered' AND delivered_at < datetime('now', '-' || ? || ' days')",
+        DELIVERED_RETENTION_DAYS
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// delivers every message that is due, a failure is recorded on the message itself so a
+/// single broken message does not block the others
+pub async fn deliver_pending(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    mail_manager: Arc<Box<dyn MailManager>>,
+) -> Result<(), ServerError> {
+    let due = sqlx::query!(
+        "SELECT id as \"id: u32\", payload, attempts as \"attempts: u32\", request_id FROM Outbox
+        WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+        ORDER BY id LIMIT ?",
+        BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for entry in due {
+        let span = tracing::info_span!(
+            "outbox",
+            message_id = entry.id,
+            request_id = entry.request_id.as_deref().unwrap_or_default()
+        );
+        let res = match serde_json::from_str::<OutboxMessage>(&entry.payload) {
+            Ok(message) => {
+                let delivery = deliver(
+                    pool,
+                    payment_provider.clone(),
+                    mail_manager.clone(),
+                    message,
+                );
+                telemetry::with_request_id(entry.request_id, delivery.instrument(span.clone()))
+                    .await
+            }
+            Err(e) => Err(format!("invalid payload : {e}")),
+        };
+        match res {
+            Ok(()) => {
+                sqlx::query!(
+                    "UPDATE Outbox SET status = 'delivered', attempts = attempts + 1, last_error = NULL, delivered_at = CURRENT_TIMESTAMP WHERE id = ?",
+                    entry.id
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(error) => {
+                let attempts = entry.attempts + 1;
+                let status = if attempts >= MAX_ATTEMPTS {
+                    tracing::error!(parent: &span, "outbox message {} is dead : {error}", entry.id);
+                    OutboxStatus::Dead
+                } else {
+                    OutboxStatus::Pending
+                };
+                let backoff = INITIAL_BACKOFF_SECS << entry.attempts.min(10);
+                sqlx::query!(
+                    "UPDATE Outbox SET
+                        status = ?,
+                        attempts = ?,
+                        last_error = ?,
+                        next_attempt_at = datetime(CURRENT_TIMESTAMP, '+' || ? || ' seconds')
+                    WHERE id = ?",
+                    status,
+                    attempts,
+                    error,
+                    backoff,
+                    entry.id
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn deliver(
+    pool: &SqlitePool,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    mail_manager: Arc<Box<dyn MailManager>>,
+    message: OutboxMessage,
+) -> Result<(), String> {
+    match message {
+        OutboxMessage::StripeMetadata {
+            payment_intent_id,
+            key,
+            value,
+        } => payment_provider
+            .push_metadata(&payment_intent_id, &key, &value)
+            .await
+            .map_err(|e| format!("{e:?}")),
+        OutboxMessage::ReceiptMail { order_id } => {
+            let order = Order::get(pool, order_id)
+                .await
+                .map_err(|e| format!("{e:?}"))?
+                .ok_or_else(|| format!("order {order_id} not found"))?;
+            match mail::send_qr(pool, mail_manager, &order).await {
+                // the customer did not leave an address, there is nothing to send
+                Ok(()) | Err(SendReceiptEmailError::NoEmailAddress) => Ok(()),
+                Err(e) => Err(format!("{e:?}")),
+            }
+        }
+        OutboxMessage::ArchiveReport { bar_opening_id } => {
+            report_archive::archive_report(pool, Path::new(REPORTS_DIR_PATH), bar_opening_id)
+                .await
+                .map_err(|e| format!("{e:?}"))
+        }
+    }
+}
+
+#[sqlx::test]
+async fn test_outbox_retries_and_replay(pool: SqlitePool) {
+    use crate::{mail_manager::TestMailManager, payment_provider::TestPaymentProvider};
+    let mut conn = pool.acquire().await.unwrap();
+    let provider = TestPaymentProvider::default();
+    let payment_provider: Arc<Box<dyn PaymentProvider>> = Arc::new(Box::new(provider.clone()));
+    let mail_manager: Arc<Box<dyn MailManager>> = Arc::new(Box::new(TestMailManager::default()));
+
+    let mut transaction = pool.begin().await.unwrap();
+    let message = OutboxMessage::StripeMetadata {
+        payment_intent_id: "pi_test".into(),
+        key: "email".into(),
+        value: "test@example.com".into(),
+    };
+    enqueue(&mut transaction, &message).await.unwrap();
+    enqueue(
+        &mut transaction,
+        &OutboxMessage::ReceiptMail { order_id: 1000 },
+    )
+    .await
+    .unwrap();
+    // a rolled back state change does not leave anything to deliver
+    transaction.rollback().await.unwrap();
+    assert!(get_entries(&pool, None, None, 10).await.unwrap().is_empty());
+
+    let mut transaction = pool.begin().await.unwrap();
+    enqueue(&mut transaction, &message).await.unwrap();
+    enqueue(
+        &mut transaction,
+        &OutboxMessage::ReceiptMail { order_id: 1000 },
+    )
+    .await
+    .unwrap();
+    transaction.commit().await.unwrap();
+
+    deliver_pending(&pool, payment_provider.clone(), mail_manager.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        provider.metadata.read().await["pi_test"]["email"],
+        "test@example.com"
+    );
+    let delivered = get_entries(&pool, Some(OutboxStatus::Delivered), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].message, message);
+    let pending = get_entries(&pool, Some(OutboxStatus::Pending), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].attempts, 1);
+    assert!(pending[0].last_error.is_some());
+
+    // not due yet
+    deliver_pending(&pool, payment_provider.clone(), mail_manager.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        get_entries(&pool, Some(OutboxStatus::Pending), None, 10)
+            .await
+            .unwrap()[0]
+            .attempts,
+        1
+    );
+
+    let dead_id = pending[0].id;
+    assert!(!replay(&mut conn, dead_id).await.unwrap());
+    sqlx::query!(
+        "UPDATE Outbox SET attempts = ?, next_attempt_at = CURRENT_TIMESTAMP WHERE id = ?",
+        MAX_ATTEMPTS - 1,
+        dead_id
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    deliver_pending(&pool, payment_provider, mail_manager)
+        .await
+        .unwrap();
+    let dead = get_entries(&pool, Some(OutboxStatus::Dead), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(dead.len(), 1);
+    assert!(replay(&mut conn, dead_id).await.unwrap());
+    assert_eq!(
+        get_entries(&pool, Some(OutboxStatus::Pending), None, 10)
+            .await
+            .unwrap()[0]
+            .attempts,
+        0
+    );
+}
+
+#[sqlx::test]
+async fn test_outbox_listing_and_purge(pool: SqlitePool) {
+    let mut transaction = pool.begin().await.unwrap();
+    for order_id in 0..5 {
+        enqueue(&mut transaction, &OutboxMessage::ReceiptMail { order_id })
+            .await
+            .unwrap();
+    }
+    transaction.commit().await.unwrap();
+    sqlx::query!(
+        "UPDATE Outbox SET status = 'delivered', delivered_at = CURRENT_TIMESTAMP WHERE id <= 2"
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    // delivered messages are only listed when asked for
+    let entries = get_entries(&pool, None, None, 2).await.unwrap();
+    let ids: Vec<OutboxMessageId> = entries.iter().map(|e| e.id).collect();
+    assert_eq!(ids, [5, 4]);
+    let entries = get_entries(&pool, None, Some(4), 2).await.unwrap();
+    let ids: Vec<OutboxMessageId> = entries.iter().map(|e| e.id).collect();
+    assert_eq!(ids, [3]);
+    let delivered = get_entries(&pool, Some(OutboxStatus::Delivered), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(delivered.len(), 2);
+
+    assert_eq!(purge_delivered(&pool).await.unwrap(), 0);
+    sqlx::query!(
+        "UPDATE Outbox SET delivered_at = datetime('now', '-31 days'), created_at = datetime('now', '-31 days') WHERE id = 1"
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    // old messages that were not delivered are kept
+    sqlx::query!("UPDATE Outbox SET created_at = datetime('now', '-31 days') WHERE id = 3")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(purge_delivered(&pool).await.unwrap(), 1);
+    let delivered = get_entries(&pool, Some(OutboxStatus::Delivered), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(get_entries(&pool, None, None, 10).await.unwrap().len(), 3);
+}
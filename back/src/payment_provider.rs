@@ -0,0 +1,231 @@
+use axum::async_trait;
+
+use crate::{
+    app::stripe::{
+        self,
+        api::StripeConfig,
+        payment_intents::{PaymentIntent, PaymentIntentId},
+        refunds::Refund,
+    },
+    errors::ServerError,
+};
+
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    async fn create_payment_intent(&self, amount: i64) -> Result<PaymentIntent, ServerError>;
+    async fn fetch_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<PaymentIntent, ServerError>;
+    async fn push_metadata(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), ServerError>;
+    async fn cancel_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<(), ServerError>;
+    /// asking again with the same `idempotency_key` gives back the same refund
+    async fn refund(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        amount: i64,
+        reason: &str,
+        idempotency_key: &str,
+    ) -> Result<Refund, ServerError>;
+}
+
+pub struct StripeProvider {
+    pub config: StripeConfig,
+}
+impl StripeProvider {
+    pub fn new(config: StripeConfig) -> StripeProvider {
+        StripeProvider { config }
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for StripeProvider {
+    async fn create_payment_intent(&self, amount: i64) -> Result<PaymentIntent, ServerError> {
+        stripe::api::create_payment_intent(&self.config, amount).await
+    }
+    async fn fetch_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<PaymentIntent, ServerError> {
+        stripe::api::fetch_payment_intent(&self.config, payment_intent_id).await
+    }
+    async fn push_metadata(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), ServerError> {
+        stripe::api::push_metadata(&self.config, payment_intent_id, key, value).await
+    }
+    async fn cancel_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<(), ServerError> {
+        stripe::api::mark_as_canceled(&self.config, payment_intent_id).await
+    }
+    async fn refund(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        amount: i64,
+        reason: &str,
+        idempotency_key: &str,
+    ) -> Result<Refund, ServerError> {
+        stripe::api::create_refund(
+            &self.config,
+            payment_intent_id,
+            amount,
+            reason,
+            idempotency_key,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+use crate::app::stripe::{payment_intents::PaymentIntentStatus, refunds::RefundStatus};
+#[cfg(test)]
+use std::{collections::HashMap, sync::Arc};
+#[cfg(test)]
+use tokio::sync::RwLock;
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct TestRefund {
+    pub payment_intent_id: PaymentIntentId,
+    pub amount: i64,
+    pub idempotency_key: String,
+    pub refund: Refund,
+}
+
+/// clones share the same intents, so a test can keep a handle on the provider given to the state
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct TestPaymentProvider {
+    pub payment_intents: Arc<RwLock<HashMap<PaymentIntentId, PaymentIntent>>>,
+    pub metadata: Arc<RwLock<HashMap<PaymentIntentId, HashMap<String, String>>>>,
+    pub refunds: Arc<RwLock<Vec<TestRefund>>>,
+    /// when set, refunds are answered with this status instead
+    pub refund_error: Arc<RwLock<Option<axum::http::StatusCode>>>,
+    /// when set, cancellations are answered with this status instead
+    pub cancel_error: Arc<RwLock<Option<axum::http::StatusCode>>>,
+}
+
+#[cfg(test)]
+impl TestPaymentProvider {
+    /// simulates the customer going through the stripe checkout
+    pub async fn set_status(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        status: PaymentIntentStatus,
+    ) {
+        if let Some(intent) = self
+            .payment_intents
+            .write()
+            .await
+            .get_mut(payment_intent_id)
+        {
+            intent.status = status;
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl PaymentProvider for TestPaymentProvider {
+    async fn create_payment_intent(&self, amount: i64) -> Result<PaymentIntent, ServerError> {
+        let mut intents = self.payment_intents.write().await;
+        let id = format!("pi_test_{}", intents.len());
+        let intent = PaymentIntent {
+            id: id.clone(),
+            client_secret: format!("{id}_secret"),
+            status: PaymentIntentStatus::RequiresPaymentMethod,
+            amount: amount as i32,
+        };
+        intents.insert(id, intent.clone());
+        Ok(intent)
+    }
+    async fn fetch_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<PaymentIntent, ServerError> {
+        self.payment_intents
+            .read()
+            .await
+            .get(payment_intent_id)
+            .cloned()
+            .ok_or_else(|| {
+                ServerError::StripeApi(
+                    axum::http::StatusCode::NOT_FOUND,
+                    format!("no such payment_intent: {payment_intent_id}"),
+                )
+            })
+    }
+    async fn push_metadata(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), ServerError> {
+        self.metadata
+            .write()
+            .await
+            .entry(payment_intent_id.clone())
+            .or_default()
+            .insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+    async fn cancel_payment_intent(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+    ) -> Result<(), ServerError> {
+        if let Some(status) = *self.cancel_error.read().await {
+            return Err(ServerError::StripeApi(
+                status,
+                "test cancel error".to_owned(),
+            ));
+        }
+        self.set_status(payment_intent_id, PaymentIntentStatus::Canceled)
+            .await;
+        Ok(())
+    }
+    async fn refund(
+        &self,
+        payment_intent_id: &PaymentIntentId,
+        amount: i64,
+        _reason: &str,
+        idempotency_key: &str,
+    ) -> Result<Refund, ServerError> {
+        if let Some(status) = *self.refund_error.read().await {
+            return Err(ServerError::StripeApi(
+                status,
+                "test refund error".to_owned(),
+            ));
+        }
+        let mut refunds = self.refunds.write().await;
+        if let Some(known) = refunds
+            .iter()
+            .find(|r| r.idempotency_key == idempotency_key)
+        {
+            return Ok(known.refund.clone());
+        }
+        let refund = Refund {
+            id: format!("re_test_{}", refunds.len()),
+            status: RefundStatus::Succeeded,
+        };
+        refunds.push(TestRefund {
+            payment_intent_id: payment_intent_id.clone(),
+            amount,
+            idempotency_key: idempotency_key.to_owned(),
+            refund: refund.clone(),
+        });
+        Ok(refund)
+    }
+}
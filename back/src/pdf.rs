@@ -0,0 +1,107 @@
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str};
+
+/// A4, in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+pub const MARGIN: f32 = 50.0;
+const LINE_HEIGHT: f32 = 16.0;
+
+pub const REGULAR: Name = Name(b"F1");
+pub const BOLD: Name = Name(b"F2");
+
+/// lays text out from top to bottom, starting a new page when the current one is full
+pub struct PageWriter {
+    pages: Vec<Content>,
+    y: f32,
+}
+impl PageWriter {
+    pub fn new() -> PageWriter {
+        PageWriter {
+            pages: vec![Content::new()],
+            y: PAGE_HEIGHT - MARGIN,
+        }
+    }
+    fn next_line(&mut self) {
+        self.y -= LINE_HEIGHT;
+        if self.y < MARGIN {
+            self.pages.push(Content::new());
+            self.y = PAGE_HEIGHT - MARGIN - LINE_HEIGHT;
+        }
+    }
+    pub fn skip(&mut self, lines: f32) {
+        self.y -= LINE_HEIGHT * lines;
+    }
+    fn show(&mut self, font: Name, size: f32, x: f32, text: &str) {
+        let content = self.pages.last_mut().unwrap();
+        content
+            .begin_text()
+            .set_font(font, size)
+            .next_line(x, self.y)
+            .show(Str(&win_ansi(text)))
+            .end_text();
+    }
+    pub fn text(&mut self, font: Name, size: f32, x: f32, text: &str) {
+        self.next_line();
+        self.show(font, size, x, text);
+    }
+    pub fn row(&mut self, font: Name, columns: &[f32], cells: &[&str]) {
+        self.next_line();
+        for (x, cell) in columns.iter().zip(cells) {
+            self.show(font, 10.0, *x, cell);
+        }
+    }
+    pub fn finish(self) -> Vec<u8> {
+        let catalog_id = Ref::new(1);
+        let page_tree_id = Ref::new(2);
+        let regular_id = Ref::new(3);
+        let bold_id = Ref::new(4);
+        let mut next_id = Ref::new(5);
+
+        let mut pdf = Pdf::new();
+        let mut page_ids = vec![];
+        for content in self.pages {
+            let page_id = next_id.bump();
+            let content_id = next_id.bump();
+            page_ids.push(page_id);
+            let mut page = pdf.page(page_id);
+            page.parent(page_tree_id)
+                .media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT))
+                .contents(content_id);
+            page.resources()
+                .fonts()
+                .pair(REGULAR, regular_id)
+                .pair(BOLD, bold_id);
+            page.finish();
+            pdf.stream(content_id, &content.finish());
+        }
+        pdf.catalog(catalog_id).pages(page_tree_id);
+        pdf.pages(page_tree_id)
+            .count(page_ids.len() as i32)
+            .kids(page_ids);
+        // the standard fonts need no embedding, WinAnsi covers french accents and the euro sign
+        pdf.type1_font(regular_id)
+            .base_font(Name(b"Helvetica"))
+            .encoding_predefined(Name(b"WinAnsiEncoding"));
+        pdf.type1_font(bold_id)
+            .base_font(Name(b"Helvetica-Bold"))
+            .encoding_predefined(Name(b"WinAnsiEncoding"));
+        pdf.finish()
+    }
+}
+
+/// latin-1 maps to the same bytes in WinAnsi, apart from the euro sign the rest is replaced
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '€' => 0x80,
+            '\u{20}'..='\u{7e}' | '\u{a0}'..='\u{ff}' => c as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[test]
+fn test_win_ansi() {
+    assert_eq!(win_ansi("Qté 5,00€"), b"Qt\xe9 5,00\x80");
+    assert_eq!(win_ansi("✓"), b"?");
+}
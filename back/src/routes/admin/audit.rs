@@ -0,0 +1,64 @@
+use axum::{extract::State, routing::get, Json, Router};
+use serde::Deserialize;
+use sqlx::types::time::OffsetDateTime;
+
+use crate::{
+    admin::{
+        audit::{self, AuditAction, AuditEntry, AuditEntryId, AuditFilters},
+        user::AdminUser,
+    },
+    errors::AuditError,
+    routes::{extractors::CustomQuery as Query, AppState},
+    utils::deserialize_empty_as_none,
+};
+
+pub fn get_router() -> Router<AppState> {
+    Router::new().route("/", get(get_entries))
+}
+
+/// entries returned when no limit is given, and the most that can be asked for
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(Deserialize)]
+struct GetEntriesParams {
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    actor_email: Option<String>,
+    #[serde(default)]
+    action: Option<AuditAction>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    target_kind: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    target_id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    date_begin: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    date_end: Option<i64>,
+    /// id of the last entry of the previous page
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    before: Option<AuditEntryId>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    limit: Option<u32>,
+}
+async fn get_entries(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<GetEntriesParams>,
+) -> Result<Json<Vec<AuditEntry>>, AuditError> {
+    let timestamp = |ts: Option<i64>| {
+        ts.map(|ts| OffsetDateTime::from_unix_timestamp(ts / 1000))
+            .transpose()
+            .map_err(|_| AuditError::InvalidDate)
+    };
+    let filters = AuditFilters {
+        actor_email: params.actor_email.as_deref(),
+        action: params.action,
+        target_kind: params.target_kind.as_deref(),
+        target_id: params.target_id.as_deref(),
+        date_begin: timestamp(params.date_begin)?,
+        date_end: timestamp(params.date_end)?,
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let entries = audit::get_entries(&state.pool, &filters, params.before, limit).await?;
+    Ok(Json(entries))
+}
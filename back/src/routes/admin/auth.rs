@@ -5,13 +5,15 @@ use axum::{
 };
 use axum_extra::extract::{cookie::Cookie, CookieJar};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::{
     admin::{
+        audit::{self, AuditAction, AuditTarget},
         auth::Session,
         user::{Role, User},
     },
-    errors::SessionError,
+    errors::{ServerError, SessionError},
     routes::{reponders::OkEmptyResponse, AppState},
 };
 
@@ -49,7 +51,7 @@ async fn get_auth(State(state): State<AppState>, request: Request) -> Json<Auth>
 }
 
 async fn delete_current(
-    _user: User,
+    user: User,
     State(state): State<AppState>,
     cookie_jar: CookieJar,
 ) -> Result<OkEmptyResponse, SessionError> {
@@ -60,7 +62,18 @@ async fn delete_current(
 
     let cookie_jar = cookie_jar.remove(Cookie::build("session").path("/"));
 
-    Session::delete_if_exists(&state.pool, &session).await?;
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    Session::delete_if_exists(&mut transaction, &session).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::Logout,
+        AuditTarget::User(user.email.clone()),
+        Value::Null,
+        Value::Null,
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new_with_cookies(cookie_jar))
 }
@@ -82,8 +95,10 @@ async fn create_challenge(
         Ok(()) => {}
         Err(e) if cfg!(not(debug_assertions)) => return Err(e.into()),
         Err(e) => {
-            eprintln!("{e:?}");
-            println!("could not send auth email, discarding error because we are in debug mode");
+            tracing::warn!(
+                error = ?e,
+                "could not send auth email, discarding error because we are in debug mode"
+            );
         }
     }
     Ok(OkEmptyResponse::new())
@@ -105,7 +120,21 @@ async fn verify_challenge(
         .await?;
 
     if challenge_succedeed {
-        let session = Session::new(&state.pool, params.email.clone()).await?;
+        let user = User::get_from_email(&state.pool, &params.email).await?;
+        let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+        let session = Session::new(&mut transaction, params.email.clone()).await?;
+        if let Some(user) = user {
+            audit::record(
+                &mut transaction,
+                &user,
+                AuditAction::Login,
+                AuditTarget::User(user.email.clone()),
+                Value::Null,
+                Value::Null,
+            )
+            .await?;
+        }
+        transaction.commit().await.map_err(ServerError::Sqlx)?;
         let cookie = Cookie::build(("session", session.uuid))
             .expires(session.expires)
             .path("/")
@@ -4,16 +4,18 @@ use axum::{
     Json, Router,
 };
 use serde::Deserialize;
+use serde_json::json;
 use tower_http::services::ServeDir;
 
 use crate::{
+    admin::audit::{self, AuditAction, AuditTarget},
     admin::bar_management::Bar,
+    admin::report_archive::{self, ArchivedReport, REPORTS_DIR_PATH},
     admin::user::AdminUser,
     errors::ServerError,
     routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse, AppState},
 };
 
-const REPORTS_DIR_PATH: &str = "./reports";
 pub fn get_router() -> Router<AppState> {
     std::fs::create_dir_all(REPORTS_DIR_PATH).expect("could not create the reports directory");
 
@@ -25,7 +27,7 @@ pub fn get_router() -> Router<AppState> {
         .route("/list_reports", get(list_reports))
         .nest_service(
             "/reports",
-            ServeDir::new("reports").append_index_html_on_directories(false),
+            ServeDir::new(REPORTS_DIR_PATH).append_index_html_on_directories(false),
         )
 }
 
@@ -39,18 +41,42 @@ async fn get_bar(
 
 async fn open_bar(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
 ) -> Result<OkEmptyResponse, ServerError> {
     let mut bar = Bar::get(&state.pool).await?;
-    bar.open(&state.pool).await?;
+    let before = json!(bar);
+    let mut transaction = state.pool.begin().await?;
+    bar.open(&mut transaction).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::OpenBar,
+        AuditTarget::Bar,
+        before,
+        json!(bar),
+    )
+    .await?;
+    transaction.commit().await?;
     Ok(OkEmptyResponse::new())
 }
 async fn close_bar(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
 ) -> Result<OkEmptyResponse, ServerError> {
     let mut bar = Bar::get(&state.pool).await?;
-    bar.close(&state.pool).await?;
+    let before = json!(bar);
+    let mut transaction = state.pool.begin().await?;
+    let bar_opening_id = bar.close(&mut transaction).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::CloseBar,
+        AuditTarget::Bar,
+        before,
+        json!({ "bar": bar, "bar_opening_id": bar_opening_id }),
+    )
+    .await?;
+    transaction.commit().await?;
     Ok(OkEmptyResponse::new())
 }
 
@@ -60,20 +86,31 @@ struct SetClosingMessageParams {
 }
 async fn set_closing_message(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<SetClosingMessageParams>,
 ) -> Result<OkEmptyResponse, ServerError> {
     let mut bar = Bar::get(&state.pool).await?;
-    bar.set_closing_message(&state.pool, params.closing_message.clone())
+    let before = json!({ "closing_message": bar.closing_message });
+    let mut transaction = state.pool.begin().await?;
+    bar.set_closing_message(&mut transaction, params.closing_message.clone())
         .await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::SetClosingMessage,
+        AuditTarget::Bar,
+        before,
+        json!({ "closing_message": bar.closing_message }),
+    )
+    .await?;
+    transaction.commit().await?;
     Ok(OkEmptyResponse::new())
 }
 
-async fn list_reports(_user: AdminUser) -> Result<Json<Vec<String>>, ServerError> {
-    let dir = std::fs::read_dir(REPORTS_DIR_PATH).unwrap();
-    let names: Vec<String> = dir
-        .map(|f| f.unwrap().file_name().to_string_lossy().to_string())
-        .collect();
-
-    Ok(Json(names))
+async fn list_reports(
+    State(state): State<AppState>,
+    _user: AdminUser,
+) -> Result<Json<Vec<ArchivedReport>>, ServerError> {
+    let reports = report_archive::get_archived_reports(&state.pool).await?;
+    Ok(Json(reports))
 }
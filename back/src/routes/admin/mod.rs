@@ -2,9 +2,11 @@ use axum::Router;
 
 use super::AppState;
 
+mod audit;
 mod auth;
 mod bar_management;
 mod order_management;
+mod outbox;
 mod reports;
 mod stock;
 mod user_management;
@@ -17,4 +19,6 @@ pub fn get_router() -> Router<AppState> {
         .nest("/orders", order_management::get_router())
         .nest("/bar", bar_management::get_router())
         .nest("/reports", reports::get_router())
+        .nest("/outbox", outbox::get_router())
+        .nest("/audit", audit::get_router())
 }
@@ -1,20 +1,34 @@
 use crate::{
-    app::orders::{Order, OrderId},
-    errors::ServerError,
-    routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse},
+    admin::bar_management::Bar,
+    app::{
+        money::{Amounts, Money},
+        order_events::OrderEvent,
+        orders::{Cart, Order, OrderDetailId, OrderId, PaymentMethod, ServingStatus},
+        receipt::ReceiptKeys,
+        refunds::{self, RefundLine},
+    },
+    errors::{OrderProcessError, ServerError},
+    routes::{
+        extractors::{CustomJsonExtractor as JsonExtractor, CustomQuery as Query},
+        reponders::OkEmptyResponse,
+    },
     utils::{deserialize_empty_as_none, serialize_time},
 };
 use axum::{
     extract::State,
-    routing::{get, patch},
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{get, patch, post},
     Json, Router,
 };
+use futures_util::{stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use sqlx::{types::time::OffsetDateTime, SqlitePool};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 
 use crate::{
-    admin::user::User,
-    app::orders::{self, OrderDetailElement},
+    admin::user::{AdminUser, User},
+    app::orders::{self, OrderDetailElement, OrderSort, OrderStatus},
     errors::OrderManagementError,
     routes::AppState,
 };
@@ -22,38 +36,72 @@ use crate::{
 pub fn get_router() -> Router<AppState> {
     Router::new()
         .route("/", get(get_by_id))
-        .route("/by_receipt", get(get_by_receipt))
+        .route("/by_qr_code", get(get_by_qr_code))
         .route("/search", get(search_orders))
+        .route("/queue", get(order_queue))
+        .route("/claim", patch(claim_for_serving))
         .route("/set_served", patch(set_served))
+        .route("/serve_detail", patch(serve_detail))
+        .route("/counter", post(create_counter_order))
+        .route("/refund", post(refund_order))
 }
 
 #[derive(Serialize)]
 pub struct OrderResponse {
     id: OrderId,
     receipt: Option<String>,
+    invoice_number: Option<String>,
+    payment_method: PaymentMethod,
     served: bool,
+    serving_status: ServingStatus,
+    needs_review: bool,
     #[serde(serialize_with = "serialize_time")]
     timestamp: OffsetDateTime,
     user_email: Option<String>,
     detail: Vec<OrderDetailElement>,
-    total_price_ht: i32,
-    total_price_ttc: i32,
+    total_price_ht: Money,
+    total_price_ttc: Money,
+    refunded_amount: Money,
 }
 impl OrderResponse {
     pub async fn from_order(pool: &SqlitePool, order: Order) -> Result<Self, ServerError> {
-        let details = order.get_details(pool).await?;
-        let total_price_ht = order.get_full_price_ht(pool).await?;
-        let total_price_ttc = order.get_full_price_ttc(pool).await?;
-        let res = OrderResponse {
-            id: order.id,
-            receipt: order.receipt.as_deref().cloned(),
-            served: order.served,
-            timestamp: order.timestamp,
-            user_email: order.user_email,
-            detail: details,
-            total_price_ht,
-            total_price_ttc,
-        };
+        let mut res = Self::from_orders(pool, vec![order]).await?;
+        Ok(res.remove(0))
+    }
+
+    /// the lines and refunds of every order are fetched with one query each
+    pub async fn from_orders(
+        pool: &SqlitePool,
+        orders: Vec<Order>,
+    ) -> Result<Vec<Self>, ServerError> {
+        let ids: Vec<OrderId> = orders.iter().map(|o| o.id).collect();
+        let mut details = orders::get_details_of_orders(pool, &ids).await?;
+        let refunded_amounts = refunds::get_refunded_amounts(pool, &ids).await?;
+        let res = orders
+            .into_iter()
+            .map(|order| {
+                let details = details.remove(&order.id).unwrap_or_default();
+                let amounts: Amounts = details.iter().map(OrderDetailElement::amounts).sum();
+                OrderResponse {
+                    id: order.id,
+                    receipt: order.receipt.as_deref().cloned(),
+                    invoice_number: order.invoice_number,
+                    payment_method: order.payment_method,
+                    served: order.served,
+                    serving_status: ServingStatus::of(&details),
+                    needs_review: order.needs_review,
+                    timestamp: order.timestamp,
+                    user_email: order.user_email,
+                    total_price_ht: amounts.ht,
+                    total_price_ttc: amounts.ttc,
+                    refunded_amount: refunded_amounts
+                        .get(&order.id)
+                        .copied()
+                        .unwrap_or(Money::ZERO),
+                    detail: details,
+                }
+            })
+            .collect();
         Ok(res)
     }
 }
@@ -68,13 +116,44 @@ struct GetOrderParams {
     date_end: Option<i64>,
     #[serde(default, deserialize_with = "deserialize_empty_as_none")]
     receipt: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    invoice_number: Option<String>,
+    #[serde(default)]
+    status: OrderStatus,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    served: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    needs_review: Option<bool>,
+    /// cents
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    min_amount: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    max_amount: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    product_id: Option<u32>,
+    #[serde(default)]
+    sort: OrderSort,
+    /// `next_cursor` of the previous page
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    cursor: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    limit: Option<u32>,
+}
+/// orders returned by a search when no limit is given, and the most that can be asked for
+const SEARCH_DEFAULT_LIMIT: u32 = 100;
+const SEARCH_MAX_LIMIT: u32 = 500;
+
+#[derive(Serialize)]
+struct SearchResponse {
+    orders: Vec<OrderResponse>,
+    next_cursor: Option<String>,
 }
 
 async fn search_orders(
     State(state): State<AppState>,
     _user: User,
     params: Query<GetOrderParams>,
-) -> Result<Json<Vec<OrderResponse>>, OrderManagementError> {
+) -> Result<Json<SearchResponse>, OrderManagementError> {
     let date_begin = params
         .date_begin
         .map(|ts| OffsetDateTime::from_unix_timestamp(ts / 1000))
@@ -85,32 +164,53 @@ async fn search_orders(
         .map(|ts| OffsetDateTime::from_unix_timestamp(ts / 1000))
         .transpose()
         .map_err(|_| OrderManagementError::InvalidDate)?;
-    let orders = orders::search_orders(
-        &state.pool,
-        params.email.as_deref(),
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|_| OrderManagementError::InvalidCursor)?;
+    let filters = orders::OrderFilters {
+        email: params.email.as_deref(),
         date_begin,
         date_end,
-        params.receipt.as_deref(),
-    )
-    .await?;
-    let mut res: Vec<OrderResponse> = vec![];
-    for order in orders {
-        res.push(OrderResponse::from_order(&state.pool, order).await?);
-    }
-    Ok(Json(res))
+        receipt: params.receipt.as_deref(),
+        invoice_number: params.invoice_number.as_deref(),
+        status: params.status,
+        served: params.served,
+        needs_review: params.needs_review,
+        min_amount: params.min_amount.map(Money::from_cents),
+        max_amount: params.max_amount.map(Money::from_cents),
+        product_id: params.product_id,
+    };
+    let limit = params
+        .limit
+        .unwrap_or(SEARCH_DEFAULT_LIMIT)
+        .clamp(1, SEARCH_MAX_LIMIT);
+    let page = orders::search_orders(&state.pool, &filters, params.sort, cursor, limit).await?;
+    Ok(Json(SearchResponse {
+        orders: OrderResponse::from_orders(&state.pool, page.orders).await?,
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    }))
 }
 
 #[derive(Deserialize)]
-struct GetByReceiptParams {
-    receipt: String,
+struct GetByQrCodeParams {
+    /// the signed payload read from the QR code
+    code: String,
 }
-async fn get_by_receipt(
+/// the signature is checked before looking the order up, a receipt alone is rejected
+async fn get_by_qr_code(
     State(state): State<AppState>,
     _user: User,
-    params: Query<GetByReceiptParams>,
+    params: Query<GetByQrCodeParams>,
 ) -> Result<Json<OrderResponse>, OrderManagementError> {
-    let order = Order::get_by_receipt(&state.pool, &params.receipt)
+    let (order_id, receipt) = ReceiptKeys::from_env()?
+        .verify(&params.code)
+        .ok_or(OrderManagementError::InvalidQrCode)?;
+    let order = Order::get_by_receipt(&state.pool, &receipt)
         .await?
+        .filter(|order| order.id == order_id)
         .ok_or_else(|| OrderManagementError::OrderNotFound)?;
     let res = OrderResponse::from_order(&state.pool, order).await?;
 
@@ -134,6 +234,76 @@ async fn get_by_id(
     Ok(Json(res))
 }
 
+/// streams the orders to prepare: every order still waiting when connecting, then each
+/// order that gets paid or (partially) served, as `paid` and `serving_changed` events
+/// carrying an `OrderResponse`. A `resync` event asks the screen to reconnect
+async fn order_queue(
+    State(state): State<AppState>,
+    _user: User,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, OrderManagementError> {
+    // subscribing first so that nothing paid while reading the queue is missed
+    let receiver = state.order_events.subscribe();
+    let queue = orders::get_preparation_queue(&state.pool).await?;
+    let waiting: Vec<_> = OrderResponse::from_orders(&state.pool, queue)
+        .await?
+        .iter()
+        .map(|res| Ok(queue_event("paid", res)))
+        .collect();
+    let updates = stream::unfold((receiver, state), |(mut receiver, state)| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => {
+                    return Some((Ok(Event::default().event("resync")), (receiver, state)))
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            let (name, order_id) = match event {
+                OrderEvent::Paid { order_id } => ("paid", order_id),
+                OrderEvent::ServingChanged { order_id, .. } => ("serving_changed", order_id),
+            };
+            let res = match Order::get(&state.pool, order_id).await {
+                Ok(Some(order)) => OrderResponse::from_order(&state.pool, order).await,
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(res) => return Some((Ok(queue_event(name, &res)), (receiver, state))),
+                Err(e) => {
+                    tracing::error!(error = ?e, "could not fetch order {order_id} for the queue")
+                }
+            }
+        }
+    });
+    Ok(Sse::new(stream::iter(waiting).chain(updates)).keep_alive(KeepAlive::default()))
+}
+fn queue_event(name: &str, order: &OrderResponse) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(order)
+        .expect("OrderResponse is always serializable")
+}
+
+#[derive(Deserialize)]
+struct ClaimParams {
+    order_id: OrderId,
+}
+/// to call once a QR code is scanned, a `409` tells when and by whom the order was already
+/// served or taken
+async fn claim_for_serving(
+    State(state): State<AppState>,
+    user: User,
+    params: Query<ClaimParams>,
+) -> Result<Json<OrderResponse>, OrderManagementError> {
+    let order = Order::get(&state.pool, params.order_id)
+        .await?
+        .ok_or_else(|| OrderManagementError::OrderNotFound)?;
+    order.claim_for_serving(&state.pool, &user).await?;
+    let res = OrderResponse::from_order(&state.pool, order).await?;
+
+    Ok(Json(res))
+}
+
 #[derive(Deserialize)]
 struct SetServedParams {
     order_id: OrderId,
@@ -141,13 +311,104 @@ struct SetServedParams {
 }
 async fn set_served(
     State(state): State<AppState>,
-    _user: User,
+    user: User,
     params: Query<SetServedParams>,
 ) -> Result<OkEmptyResponse, OrderManagementError> {
     let mut order = Order::get(&state.pool, params.order_id)
         .await?
         .ok_or_else(|| OrderManagementError::OrderNotFound)?;
-    order.set_served(&state.pool, params.new_served).await?;
+    order
+        .set_served(&state.pool, &state.order_events, &user, params.new_served)
+        .await?;
 
     Ok(OkEmptyResponse::new())
 }
+
+#[derive(Deserialize)]
+struct ServeDetailParams {
+    order_id: OrderId,
+    detail_id: OrderDetailId,
+    quantity: u32,
+}
+async fn serve_detail(
+    State(state): State<AppState>,
+    user: User,
+    params: Query<ServeDetailParams>,
+) -> Result<Json<OrderResponse>, OrderManagementError> {
+    let mut order = Order::get(&state.pool, params.order_id)
+        .await?
+        .ok_or_else(|| OrderManagementError::OrderNotFound)?;
+    order
+        .serve_detail(
+            &state.pool,
+            &state.order_events,
+            &user,
+            params.detail_id,
+            params.quantity,
+        )
+        .await?;
+    let res = OrderResponse::from_order(&state.pool, order).await?;
+
+    Ok(Json(res))
+}
+
+#[derive(Deserialize)]
+struct CounterOrderRequest {
+    #[serde(flatten)]
+    cart: Cart,
+    payment_method: PaymentMethod,
+}
+async fn create_counter_order(
+    State(state): State<AppState>,
+    user: User,
+    JsonExtractor(Json(request)): JsonExtractor<CounterOrderRequest>,
+) -> Result<Json<OrderResponse>, OrderProcessError> {
+    if !Bar::get(&state.pool).await?.is_open {
+        return Err(OrderProcessError::BarIsClosed);
+    }
+    if !request.cart.elements.iter().any(|e| e.quantity > 0) {
+        return Err(OrderProcessError::EmptyOrder);
+    }
+    let order = Order::generate_from_counter(
+        &state.pool,
+        &state.order_events,
+        &user,
+        request.cart,
+        request.payment_method,
+    )
+    .await?;
+    let res = OrderResponse::from_order(&state.pool, order).await?;
+
+    Ok(Json(res))
+}
+
+#[derive(Deserialize)]
+struct RefundRequest {
+    order_id: OrderId,
+    reason: String,
+    /// the whole order is refunded when no line is given
+    #[serde(default)]
+    lines: Option<Vec<RefundLine>>,
+}
+async fn refund_order(
+    State(state): State<AppState>,
+    user: AdminUser,
+    JsonExtractor(Json(request)): JsonExtractor<RefundRequest>,
+) -> Result<Json<OrderResponse>, OrderManagementError> {
+    let order = Order::get(&state.pool, request.order_id)
+        .await?
+        .ok_or_else(|| OrderManagementError::OrderNotFound)?;
+    refunds::refund_order(
+        &state.pool,
+        state.payment_provider.clone(),
+        &state.order_events,
+        &user,
+        &order,
+        request.lines,
+        &request.reason,
+    )
+    .await?;
+    let res = OrderResponse::from_order(&state.pool, order).await?;
+
+    Ok(Json(res))
+}
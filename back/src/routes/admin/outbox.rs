@@ -0,0 +1,75 @@
+use axum::{
+    extract::State,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    admin::{
+        audit::{self, AuditAction, AuditTarget},
+        user::AdminUser,
+    },
+    errors::{OutboxError, ServerError},
+    outbox::{self, OutboxEntry, OutboxMessageId, OutboxStatus},
+    routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse, AppState},
+    utils::deserialize_empty_as_none,
+};
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_entries))
+        .route("/replay", post(replay))
+}
+
+/// entries returned when no limit is given, and the most that can be asked for
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(Deserialize)]
+struct GetEntriesParams {
+    /// the messages that are not delivered yet when empty
+    #[serde(default)]
+    status: Option<OutboxStatus>,
+    /// id of the last entry of the previous page
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    before: Option<OutboxMessageId>,
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    limit: Option<u32>,
+}
+async fn get_entries(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<GetEntriesParams>,
+) -> Result<Json<Vec<OutboxEntry>>, ServerError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let entries = outbox::get_entries(&state.pool, params.status, params.before, limit).await?;
+    Ok(Json(entries))
+}
+
+#[derive(Deserialize)]
+struct ReplayParams {
+    id: OutboxMessageId,
+}
+async fn replay(
+    State(state): State<AppState>,
+    user: AdminUser,
+    params: Query<ReplayParams>,
+) -> Result<OkEmptyResponse, OutboxError> {
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    if !outbox::replay(&mut transaction, params.id).await? {
+        return Err(OutboxError::MessageNotFound(params.id));
+    }
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::ReplayOutboxMessage,
+        AuditTarget::OutboxMessage(params.id),
+        Value::Null,
+        json!({ "status": OutboxStatus::Pending }),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
+    Ok(OkEmptyResponse::new())
+}
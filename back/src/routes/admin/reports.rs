@@ -1,14 +1,26 @@
-use axum::{extract::State, routing::get, Json, Router};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderValue,
+    },
+    response::Response,
+    routing::get,
+    Json, Router,
+};
 use serde::Deserialize;
 use sqlx::types::time::OffsetDateTime;
 
 use crate::{
     admin::{
-        bar_management,
-        report::{process_orders_to_report, Report},
+        bar_management::{self, BarOpeningId},
+        export::export_order_lines,
+        report::Report,
+        session_report::{self, SessionComparison, SessionReport},
         user::AdminUser,
+        volume_report::{self, ProductVolumeReport},
     },
-    app::orders,
     errors::{OrderManagementError, ServerError},
     routes::{extractors::CustomQuery as Query, AppState},
 };
@@ -17,6 +29,10 @@ pub fn get_router() -> Router<AppState> {
     Router::new()
         .route("/get_bar_openings", get(get_bar_openings))
         .route("/", get(get_report))
+        .route("/session", get(get_session_report))
+        .route("/compare", get(compare_sessions))
+        .route("/volumes", get(get_volume_report))
+        .route("/export", get(export_orders))
 }
 
 async fn get_bar_openings(
@@ -41,7 +57,111 @@ async fn get_report(
         .map_err(|_| OrderManagementError::InvalidDate)?;
     let end = OffsetDateTime::from_unix_timestamp(params.end / 1000)
         .map_err(|_| OrderManagementError::InvalidDate)?;
-    let orders = orders::search_orders(&state.pool, None, Some(begin), Some(end), None).await?;
-    let report = process_orders_to_report(&state.pool, orders).await?;
+    let report = Report::for_period(&state.pool, begin, end).await?;
+    Ok(Json(report))
+}
+
+#[derive(Deserialize)]
+struct SessionReportQuery {
+    bar_opening_id: BarOpeningId,
+}
+async fn get_session_report(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<SessionReportQuery>,
+) -> Result<Json<SessionReport>, OrderManagementError> {
+    let report = get_session(&state, params.bar_opening_id).await?;
     Ok(Json(report))
 }
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    first: BarOpeningId,
+    second: BarOpeningId,
+}
+async fn compare_sessions(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<CompareQuery>,
+) -> Result<Json<SessionComparison>, OrderManagementError> {
+    let first = get_session(&state, params.first).await?;
+    let second = get_session(&state, params.second).await?;
+    Ok(Json(session_report::compare_sessions(first, second)))
+}
+
+async fn get_session(
+    state: &AppState,
+    bar_opening_id: BarOpeningId,
+) -> Result<SessionReport, OrderManagementError> {
+    SessionReport::for_bar_opening(&state.pool, bar_opening_id)
+        .await?
+        .ok_or(OrderManagementError::BarOpeningNotFound(bar_opening_id))
+}
+
+/// a period given either as a bar opening or as `begin` and `end` in milliseconds
+#[derive(Deserialize)]
+struct PeriodQuery {
+    begin: Option<i64>,
+    end: Option<i64>,
+    bar_opening_id: Option<BarOpeningId>,
+}
+impl PeriodQuery {
+    async fn resolve(
+        &self,
+        state: &AppState,
+    ) -> Result<(OffsetDateTime, OffsetDateTime), OrderManagementError> {
+        match (self.bar_opening_id, self.begin, self.end) {
+            (Some(id), _, _) => {
+                let opening = bar_management::get_bar_opening(&state.pool, id)
+                    .await?
+                    .ok_or(OrderManagementError::BarOpeningNotFound(id))?;
+                Ok((opening.begin, opening.end))
+            }
+            (None, Some(begin), Some(end)) => Ok((
+                OffsetDateTime::from_unix_timestamp(begin / 1000)
+                    .map_err(|_| OrderManagementError::InvalidDate)?,
+                OffsetDateTime::from_unix_timestamp(end / 1000)
+                    .map_err(|_| OrderManagementError::InvalidDate)?,
+            )),
+            _ => Err(OrderManagementError::InvalidDate),
+        }
+    }
+}
+
+async fn get_volume_report(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<PeriodQuery>,
+) -> Result<Json<Vec<ProductVolumeReport>>, OrderManagementError> {
+    let (begin, end) = params.resolve(&state).await?;
+    let report = volume_report::get_volume_report(&state.pool, begin, end).await?;
+    Ok(Json(report))
+}
+
+/// every paid order line of a bar opening, or between `begin` and `end`, as a CSV file
+async fn export_orders(
+    State(state): State<AppState>,
+    _user: AdminUser,
+    params: Query<PeriodQuery>,
+) -> Result<Response, OrderManagementError> {
+    let (begin, end) = params.resolve(&state).await?;
+
+    let body = Body::from_stream(export_order_lines(state.pool.clone(), begin, end));
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/csv; charset=utf-8"),
+    );
+    let disposition = format!(
+        "attachment; filename=\"commandes-{}{:02}{:02}.csv\"",
+        begin.year(),
+        begin.month() as u8,
+        begin.day()
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).unwrap(),
+    );
+    Ok(response)
+}
@@ -4,9 +4,13 @@ use axum::{
     Json, Router,
 };
 use serde::Deserialize;
+use serde_json::{json, Value};
 
 use crate::{
-    admin::user::AdminUser,
+    admin::{
+        audit::{self, AuditAction, AuditTarget},
+        user::AdminUser,
+    },
     app::products::{self, MoveDirection, Product},
     errors::{ManageStockError, ServerError},
     routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse, AppState},
@@ -35,16 +39,27 @@ struct InsertProductParams {
 }
 async fn insert_product(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<InsertProductParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
-    products::Product::create(
-        &state.pool,
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    let product = products::Product::create(
+        &mut transaction,
         params.name.clone(),
         params.description.clone(),
         params.stock_quantity,
     )
     .await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::CreateProduct,
+        AuditTarget::Product(product.id),
+        Value::Null,
+        json!(product),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -62,27 +77,41 @@ struct EditProductParams {
 
 async fn edit_product(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<EditProductParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let mut product = match Product::get(&state.pool, params.product_id).await? {
         Some(p) => p,
         None => return Err(ManageStockError::ProductNotFound(params.product_id)),
     };
+    let before = json!(product);
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
 
     if let Some(new_name) = &params.new_name {
-        product.set_name(&state.pool, new_name.to_owned()).await?;
+        product
+            .set_name(&mut transaction, new_name.to_owned())
+            .await?;
     }
     if let Some(new_description) = &params.new_description {
         product
-            .set_description(&state.pool, new_description.to_owned())
+            .set_description(&mut transaction, new_description.to_owned())
             .await?;
     }
     if let Some(new_stock_quantity) = params.new_stock_quantity {
         product
-            .set_stock_quantity(&state.pool, new_stock_quantity)
+            .set_stock_quantity(&mut transaction, new_stock_quantity)
             .await?;
     }
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::EditProduct,
+        AuditTarget::Product(product.id),
+        before,
+        json!(product),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -93,14 +122,26 @@ struct DeleteProductParams {
 }
 async fn delete_product(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<DeleteProductParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let product = match Product::get(&state.pool, params.product_id).await? {
         Some(p) => p,
         None => return Err(ManageStockError::ProductNotFound(params.product_id)),
     };
-    product.delete(&state.pool).await?;
+    let before = json!(product);
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    product.delete(&mut transaction).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::DeleteProduct,
+        AuditTarget::Product(params.product_id),
+        before,
+        Value::Null,
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -118,14 +159,29 @@ struct MoveProductParams {
 }
 async fn move_product(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<MoveProductParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let mut product = match Product::get(&state.pool, params.product_id).await? {
         Some(p) => p,
         None => return Err(ManageStockError::ProductNotFound(params.product_id)),
     };
-    product.move_product(&state.pool, params.direction).await?;
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    let before = product.get_position(&mut transaction).await?;
+    product
+        .move_product(&mut transaction, params.direction)
+        .await?;
+    let after = product.get_position(&mut transaction).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::MoveProduct,
+        AuditTarget::Product(product.id),
+        json!({ "position": before }),
+        json!({ "position": after }),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -141,17 +197,18 @@ struct AddVariationParams {
 }
 async fn add_variation(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<AddVariationParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let mut product = match Product::get(&state.pool, params.product_id).await? {
         Some(p) => p,
         None => return Err(ManageStockError::ProductNotFound(params.product_id)),
     };
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
 
     product
         .add_variation(
-            &state.pool,
+            &mut transaction,
             params.name.to_owned(),
             params.price_ht,
             params.tva,
@@ -159,6 +216,18 @@ async fn add_variation(
             params.available_to_order,
         )
         .await?;
+    if let Some(variation) = product.variations.last() {
+        audit::record(
+            &mut transaction,
+            &user,
+            AuditAction::AddVariation,
+            AuditTarget::Variation(variation.id),
+            Value::Null,
+            json!(variation),
+        )
+        .await?;
+    }
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -170,16 +239,35 @@ struct RemoveVariationParams {
 }
 async fn remove_variation(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<RemoveVariationParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let mut product = match Product::get(&state.pool, params.product_id).await? {
         Some(p) => p,
         None => return Err(ManageStockError::ProductNotFound(params.product_id)),
     };
+    // removing a variation the product does not have changes nothing
+    let before = product
+        .variations
+        .iter()
+        .find(|v| v.id == params.variation_id)
+        .map(|v| json!(v));
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
     product
-        .delete_variation(&state.pool, params.variation_id)
+        .delete_variation(&mut transaction, params.variation_id)
+        .await?;
+    if let Some(before) = before {
+        audit::record(
+            &mut transaction,
+            &user,
+            AuditAction::RemoveVariation,
+            AuditTarget::Variation(params.variation_id),
+            before,
+            Value::Null,
+        )
         .await?;
+    }
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -1,10 +1,14 @@
 use axum::{extract::State, routing::patch, Router};
 use serde::Deserialize;
+use serde_json::json;
 
 use crate::{
-    admin::user::AdminUser,
+    admin::{
+        audit::{self, AuditAction, AuditTarget},
+        user::AdminUser,
+    },
     app::product_variations::Variation,
-    errors::ManageStockError,
+    errors::{ManageStockError, ServerError},
     routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse, AppState},
     utils::deserialize_empty_as_none,
 };
@@ -30,35 +34,49 @@ struct EditVariationsParams {
 
 async fn edit_variation(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<EditVariationsParams>,
 ) -> Result<OkEmptyResponse, ManageStockError> {
     let mut variation = match Variation::get(&state.pool, params.variation_id).await? {
         Some(c) => c,
         None => return Err(ManageStockError::VariationNotFound(params.variation_id)),
     };
+    let before = json!(variation);
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
 
     if let Some(new_name) = &params.new_name {
-        variation.set_name(&state.pool, new_name.to_owned()).await?;
+        variation
+            .set_name(&mut transaction, new_name.to_owned())
+            .await?;
     }
 
     if let Some(new_price) = params.new_price_ht {
-        variation.set_price_ht(&state.pool, new_price).await?;
+        variation.set_price_ht(&mut transaction, new_price).await?;
     }
 
     if let Some(new_tva) = params.new_tva {
-        variation.set_tva(&state.pool, new_tva).await?;
+        variation.set_tva(&mut transaction, new_tva).await?;
     }
 
     if let Some(new_volume) = params.new_volume {
-        variation.set_volume(&state.pool, new_volume).await?;
+        variation.set_volume(&mut transaction, new_volume).await?;
     }
 
     if let Some(new_available_to_order) = params.new_available_to_order {
         variation
-            .set_available_to_order(&state.pool, new_available_to_order)
+            .set_available_to_order(&mut transaction, new_available_to_order)
             .await?;
     }
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::EditVariation,
+        AuditTarget::Variation(variation.id),
+        before,
+        json!(variation),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -7,13 +7,15 @@ use axum::{
 };
 use lettre::message::Mailbox;
 use serde::Deserialize;
+use serde_json::{json, Value};
 
 use crate::{
     admin::{
+        audit::{self, AuditAction, AuditTarget},
         auth::Session,
         user::{AdminUser, Role, User},
     },
-    errors::UserManagementError,
+    errors::{ServerError, UserManagementError},
     routes::{extractors::CustomQuery as Query, reponders::OkEmptyResponse, AppState},
 };
 
@@ -40,7 +42,7 @@ struct AddUserParams {
 }
 async fn add_user(
     State(state): State<AppState>,
-    _user: AdminUser,
+    user: AdminUser,
     params: Query<AddUserParams>,
 ) -> Result<OkEmptyResponse, UserManagementError> {
     if let Some(_existing_user) = User::get_from_email(&state.pool, &params.email).await? {
@@ -52,7 +54,18 @@ async fn add_user(
             e,
         ));
     }
-    User::create(&state.pool, &params.email, params.role).await?;
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
+    User::create(&mut transaction, &params.email, params.role).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::AddUser,
+        AuditTarget::User(params.email.clone()),
+        Value::Null,
+        json!({ "role": params.role }),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -74,11 +87,23 @@ async fn delete_user(
         .await?
         .ok_or_else(|| UserManagementError::UserDoesNotExist(params.email.clone()))?;
 
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
     for session in &user_to_delete.active_sessions {
-        Session::delete_if_exists(&state.pool, &session.uuid).await?;
+        Session::delete_if_exists(&mut transaction, &session.uuid).await?;
     }
 
-    user_to_delete.delete(&state.pool).await?;
+    let before = json!({ "role": user_to_delete.role });
+    user_to_delete.delete(&mut transaction).await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::DeleteUser,
+        AuditTarget::User(params.email.clone()),
+        before,
+        Value::Null,
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -101,9 +126,21 @@ async fn update_role(
         .await?
         .ok_or_else(|| UserManagementError::UserDoesNotExist(params.email.clone()))?;
 
+    let before = json!({ "role": user_to_update.role });
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
     user_to_update
-        .update_role(&state.pool, params.new_role)
+        .update_role(&mut transaction, params.new_role)
         .await?;
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::UpdateRole,
+        AuditTarget::User(params.email.clone()),
+        before,
+        json!({ "role": params.new_role }),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -125,9 +162,20 @@ async fn disconnect_user(
         .await?
         .ok_or_else(|| UserManagementError::UserDoesNotExist(params.email.clone()))?;
 
+    let mut transaction = state.pool.begin().await.map_err(ServerError::Sqlx)?;
     for session in &user_to_disconnect.active_sessions {
-        Session::delete_if_exists(&state.pool, &session.uuid).await?;
+        Session::delete_if_exists(&mut transaction, &session.uuid).await?;
     }
+    audit::record(
+        &mut transaction,
+        &user,
+        AuditAction::DisconnectUser,
+        AuditTarget::User(params.email.clone()),
+        json!({ "active_sessions": user_to_disconnect.active_sessions.len() }),
+        json!({ "active_sessions": 0 }),
+    )
+    .await?;
+    transaction.commit().await.map_err(ServerError::Sqlx)?;
 
     Ok(OkEmptyResponse::new())
 }
@@ -7,13 +7,14 @@ use axum::{
     Json, Router,
 };
 use qrcode::render::svg;
-use reqwest::header::CONTENT_TYPE;
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 use std::env;
 
 use crate::{
     admin::bar_management::Bar,
     app::{
+        invoice::Invoice,
         orders::{Cart, Order, OrderDetailElement, OrderId},
         stripe::payment_intents::PaymentIntentStatus,
     },
@@ -33,6 +34,7 @@ pub fn get_router() -> Router<AppState> {
         .route("/set_email", patch(set_email))
         .route("/get_payment_status", get(get_payment_status))
         .route("/get_qr_code", get(get_qr_code))
+        .route("/get_invoice", get(get_invoice))
 }
 
 #[derive(Serialize)]
@@ -60,7 +62,8 @@ async fn validate_cart(
     if !cart.elements.iter().any(|e| e.quantity > 0) {
         return Err(OrderProcessError::EmptyOrder);
     }
-    let order_id = Order::generate_from_cart(&state.pool, cart).await?;
+    let order_id =
+        Order::generate_from_cart(&state.pool, state.payment_provider.clone(), cart).await?;
     Ok(Json(ValidateCartResponse { order_id }))
 }
 
@@ -85,8 +88,13 @@ async fn get_payment_infos(
         .ok_or_else(|| PaymentIntentError::OrderNotFound(params.order_id))?;
 
     let intent = order
-        .get_payment_intent(&state.pool, state.mail_manager.clone())
-        .await?;
+        .get_payment_intent(
+            &state.pool,
+            state.payment_provider.clone(),
+            &state.order_events,
+        )
+        .await?
+        .ok_or_else(|| PaymentIntentError::OrderNotFound(params.order_id))?;
 
     if intent.status == PaymentIntentStatus::Succeeded {
         return Err(PaymentIntentError::AlreadyPaid);
@@ -140,8 +148,13 @@ async fn get_payment_status(
         .ok_or_else(|| PaymentIntentError::OrderNotFoundFromSecrets)?;
 
     let intent = order
-        .get_payment_intent(&state.pool, state.mail_manager.clone())
-        .await?;
+        .get_payment_intent(
+            &state.pool,
+            state.payment_provider.clone(),
+            &state.order_events,
+        )
+        .await?
+        .ok_or_else(|| PaymentIntentError::OrderNotFoundFromSecrets)?;
     let total_price = intent.amount;
 
     let res = Json(PaymentStatusResponse {
@@ -165,7 +178,7 @@ async fn get_qr_code(
     let receipt = order.receipt.ok_or_else(|| PaymentIntentError::NoReceipt)?;
 
     let img = receipt
-        .get_qr_code()?
+        .get_qr_code(order.id)?
         .render()
         .min_dimensions(200, 200)
         .dark_color(svg::Color("#000000"))
@@ -177,3 +190,152 @@ async fn get_qr_code(
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/svg+xml"));
     Ok(response)
 }
+
+#[derive(Deserialize)]
+struct InvoiceParams {
+    client_secret: Option<String>,
+    receipt: Option<String>,
+}
+/// the invoice can be fetched with the client secret right after the payment, or later on
+/// with the receipt printed on it
+async fn get_invoice(
+    State(state): State<AppState>,
+    params: Query<InvoiceParams>,
+) -> Result<Response, PaymentIntentError> {
+    let order = match (&params.client_secret, &params.receipt) {
+        (Some(client_secret), _) => {
+            Order::get_from_client_secret(&state.pool, client_secret).await?
+        }
+        (None, Some(receipt)) => Order::get_by_receipt(&state.pool, receipt).await?,
+        (None, None) => None,
+    }
+    .ok_or_else(|| PaymentIntentError::OrderNotFoundFromSecrets)?;
+    let invoice = Invoice::for_order(&state.pool, &order)
+        .await?
+        .ok_or_else(|| PaymentIntentError::NoReceipt)?;
+
+    let mut response = Response::new(Body::from(invoice.to_pdf()));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
+    // the file name only contains ascii characters
+    let disposition = format!("attachment; filename=\"{}\"", Invoice::file_name(order.id));
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition).unwrap(),
+    );
+    Ok(response)
+}
+
+#[sqlx::test]
+async fn test_checkout_against_stripe_mock(pool: sqlx::SqlitePool) {
+    use crate::{
+        app::stripe::{api::StripeConfig, mock::StripeMock},
+        mail_manager::TestMailManager,
+        payment_provider::StripeProvider,
+        routes::InnerState,
+    };
+    use axum::{
+        body::to_bytes,
+        http::{Method, Request, StatusCode},
+    };
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tower::util::ServiceExt;
+
+    let mock = StripeMock::start().await;
+    Bar::get(&pool)
+        .await
+        .unwrap()
+        .open(&mut pool.acquire().await.unwrap())
+        .await
+        .unwrap();
+    let state = InnerState {
+        challenge_manager: Default::default(),
+        pool: pool.clone(),
+        mail_manager: Arc::new(Box::new(TestMailManager::default())),
+        payment_provider: Arc::new(Box::new(StripeProvider::new(StripeConfig::new(
+            mock.base_url.clone(),
+            "sk_test_mock".into(),
+        )))),
+        order_events: Default::default(),
+        metrics_token: None,
+    };
+    let app = get_router().with_state(Arc::new(state));
+    async fn call(app: &Router, method: Method, uri: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    let (status, body) = call(
+        &app,
+        Method::POST,
+        "/validate_cart",
+        r#"{"elements": [{"variation_id": 1, "quantity": 2}]}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let order_id = body["order_id"].as_i64().unwrap();
+
+    let (status, body) = call(
+        &app,
+        Method::GET,
+        &format!("/get_payment_infos?order_id={order_id}"),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["total_price"], 2 * 984);
+    let client_secret = body["client_secret"].as_str().unwrap().to_owned();
+
+    let status_uri = format!("/get_payment_status?client_secret={client_secret}");
+    let (_, body) = call(&app, Method::GET, &status_uri, "").await;
+    assert_eq!(body["status"], "requires_payment_method");
+    assert!(body["receipt"].is_null());
+
+    let payment_intent_id = sqlx::query!(
+        "SELECT payment_intent_id FROM Orders WHERE id = ?",
+        order_id
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .payment_intent_id
+    .unwrap();
+    mock.set_status(&payment_intent_id, PaymentIntentStatus::Succeeded)
+        .await;
+
+    let (status, body) = call(&app, Method::GET, &status_uri, "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "succeeded");
+    assert!(body["receipt"].is_string());
+    assert_eq!(body["total_price"], 2 * 984);
+
+    let (status, _) = call(
+        &app,
+        Method::GET,
+        &format!("/get_qr_code?client_secret={client_secret}"),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let request = Request::builder()
+        .uri(format!("/get_invoice?client_secret={client_secret}"))
+        .body(Body::empty())
+        .unwrap();
+    let res = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "application/pdf");
+    let pdf = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    assert!(pdf.starts_with(b"%PDF-"));
+    let (status, _) = call(&app, Method::GET, "/get_invoice?receipt=unknown", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,49 @@
+use axum::{extract::State, http::HeaderMap, routing::post, Router};
+
+use crate::{
+    app::{orders::Order, stripe::webhooks},
+    errors::WebhookError,
+    routes::{reponders::OkEmptyResponse, AppState},
+};
+
+pub fn get_router() -> Router<AppState> {
+    Router::new().route("/webhooks", post(stripe_webhook))
+}
+
+async fn stripe_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<OkEmptyResponse, WebhookError> {
+    let signature = headers
+        .get("Stripe-Signature")
+        .and_then(|h| h.to_str().ok())
+        .ok_or(WebhookError::MissingSignature)?;
+    let event = webhooks::construct_event(&body, signature)?;
+
+    let event_type = event.event_type.clone();
+    if !matches!(
+        event_type.as_str(),
+        "payment_intent.succeeded" | "payment_intent.canceled" | "payment_intent.payment_failed"
+    ) {
+        return Ok(OkEmptyResponse::new());
+    }
+    let event_id = event.id.clone();
+    let payment_intent = event.payment_intent()?;
+    let Some(mut order) =
+        Order::get_from_payment_intent_id(&state.pool, &payment_intent.id).await?
+    else {
+        // not one of ours (e.g. another app on the same stripe account), acknowledge it anyway
+        return Ok(OkEmptyResponse::new());
+    };
+
+    match event_type.as_str() {
+        "payment_intent.succeeded" => order.mark_as_paid(&state.pool, &state.order_events).await?,
+        "payment_intent.canceled" => order.mark_as_canceled(&state.pool).await?,
+        _ => {
+            // the customer can still retry with another payment method until the order expires
+            tracing::warn!("payment failed for order {} (event {event_id})", order.id);
+        }
+    }
+    Ok(OkEmptyResponse::new())
+}
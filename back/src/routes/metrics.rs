@@ -0,0 +1,93 @@
+use std::env;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::{
+    errors::{ErrorResponse, ServerError},
+    monitoring,
+    routes::{reponders, AppState},
+};
+
+pub fn get_router() -> Router<AppState> {
+    Router::new().route("/", get(get_metrics))
+}
+
+/// the bearer token the scraper has to send, `/metrics` is turned off when it is not set
+pub fn token_from_env() -> Option<String> {
+    env::var("METRICS_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+async fn get_metrics(
+    State(state): State<AppState>,
+    request: Request,
+) -> Result<Response, ServerError> {
+    let Some(token) = &state.metrics_token else {
+        return Ok(reponders::handler_404(request).await);
+    };
+    let expected = format!("Bearer {token}");
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|h| h.as_bytes());
+    if given != Some(expected.as_bytes()) {
+        let error = ErrorResponse::json("invalid metrics token".to_owned());
+        return Ok((StatusCode::UNAUTHORIZED, error).into_response());
+    }
+    monitoring::update_bar_gauges(&state.pool).await?;
+    let metrics = monitoring::install().render();
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+        .into_response())
+}
+
+#[sqlx::test]
+async fn test_metrics_token(pool: sqlx::SqlitePool) {
+    use crate::{
+        mail_manager::TestMailManager, payment_provider::TestPaymentProvider,
+        routes::generate_app_state,
+    };
+    use axum::body::Body;
+    use std::sync::Arc;
+    use tower::util::ServiceExt;
+
+    let app = |metrics_token: Option<&str>| {
+        get_router().with_state(generate_app_state(
+            Default::default(),
+            pool.clone(),
+            Arc::new(Box::new(TestMailManager::default())),
+            Arc::new(Box::new(TestPaymentProvider::default())),
+            metrics_token.map(str::to_owned),
+        ))
+    };
+    let call = |app: Router, authorization: Option<&str>| {
+        let mut request = Request::builder().uri("/");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let res = call(app(None), Some("Bearer secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = call(app(Some("secret")), None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = call(app(Some("secret")), Some("Bearer wrong"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = call(app(Some("secret")), Some("Bearer secret"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
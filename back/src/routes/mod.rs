@@ -3,17 +3,25 @@ pub(crate) mod customer;
 
 pub(crate) mod cors;
 pub(crate) mod extractors;
+pub(crate) mod metrics;
 pub(crate) mod reponders;
 
 use sqlx::SqlitePool;
 
-use crate::{admin::challenge::ChallengeManager, mail_manager::MailManager};
+use crate::{
+    admin::challenge::ChallengeManager, app::order_events::OrderEvents, mail_manager::MailManager,
+    payment_provider::PaymentProvider,
+};
 use std::sync::Arc;
 
 pub struct InnerState {
     pub challenge_manager: ChallengeManager,
     pub pool: SqlitePool,
     pub mail_manager: Arc<Box<dyn MailManager>>,
+    pub payment_provider: Arc<Box<dyn PaymentProvider>>,
+    pub order_events: OrderEvents,
+    /// `/metrics` is only served when set
+    pub metrics_token: Option<String>,
 }
 pub type AppState = Arc<InnerState>;
 
@@ -21,10 +29,15 @@ pub fn generate_app_state(
     challenge_manager: ChallengeManager,
     pool: SqlitePool,
     mail_manager: Arc<Box<dyn MailManager>>,
+    payment_provider: Arc<Box<dyn PaymentProvider>>,
+    metrics_token: Option<String>,
 ) -> AppState {
     Arc::new(InnerState {
         challenge_manager,
         pool,
         mail_manager,
+        payment_provider,
+        order_events: OrderEvents::new(),
+        metrics_token,
     })
 }
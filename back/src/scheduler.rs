@@ -0,0 +1,259 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use sqlx::SqlitePool;
+
+use crate::{
+    admin::auth::Session,
+    app::{order_events::OrderEvents, orders, refunds},
+    errors::ServerError,
+    mail_manager::MailManager,
+    outbox,
+    payment_provider::PaymentProvider,
+};
+
+/// a periodic task, a failed run is retried with an exponential backoff before waiting for the
+/// next interval
+#[async_trait]
+pub trait Job: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn interval(&self) -> Duration;
+    async fn run(&self) -> Result<(), ServerError>;
+}
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Arc<dyn Job>>,
+    retry_policy: RetryPolicy,
+}
+impl Scheduler {
+    pub fn new(retry_policy: RetryPolicy) -> Scheduler {
+        Scheduler {
+            jobs: vec![],
+            retry_policy,
+        }
+    }
+    pub fn add_job(mut self, job: impl Job + 'static) -> Scheduler {
+        self.jobs.push(Arc::new(job));
+        self
+    }
+    /// every job gets its own task, the first run happens right away
+    pub fn start(self) {
+        for job in self.jobs {
+            let retry_policy = self.retry_policy;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(job.interval());
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    run_with_retries(job.as_ref(), retry_policy).await;
+                }
+            });
+        }
+    }
+}
+
+/// returns whether the job eventually succeeded
+async fn run_with_retries(job: &dyn Job, retry_policy: RetryPolicy) -> bool {
+    let mut backoff = retry_policy.initial_backoff;
+    for attempt in 0..=retry_policy.max_retries {
+        match job.run().await {
+            Ok(()) => return true,
+            Err(e) if attempt < retry_policy.max_retries => {
+                tracing::warn!(
+                    "job {} failed (attempt {}), retrying in {backoff:?} : {e:?}",
+                    job.name(),
+                    attempt + 1
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => tracing::error!(
+                "job {} failed {} times, giving up until next run : {e:?}",
+                job.name(),
+                attempt + 1
+            ),
+        }
+    }
+    false
+}
+
+pub struct CancelExpiredOrders {
+    pub pool: SqlitePool,
+    pub payment_provider: Arc<Box<dyn PaymentProvider>>,
+    pub order_events: OrderEvents,
+}
+#[async_trait]
+impl Job for CancelExpiredOrders {
+    fn name(&self) -> &'static str {
+        "cancel_expired_orders"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        orders::cancel_expired_orders(
+            &self.pool,
+            self.payment_provider.clone(),
+            &self.order_events,
+        )
+        .await
+    }
+}
+
+pub struct ReleaseExpiredReservations {
+    pub pool: SqlitePool,
+}
+#[async_trait]
+impl Job for ReleaseExpiredReservations {
+    fn name(&self) -> &'static str {
+        "release_expired_reservations"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        orders::release_expired_reservations(&self.pool).await
+    }
+}
+
+pub struct PurgeSessions {
+    pub pool: SqlitePool,
+}
+#[async_trait]
+impl Job for PurgeSessions {
+    fn name(&self) -> &'static str {
+        "purge_sessions"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        Session::delete_old_sessions(&self.pool).await
+    }
+}
+
+pub struct DeliverOutbox {
+    pub pool: SqlitePool,
+    pub payment_provider: Arc<Box<dyn PaymentProvider>>,
+    pub mail_manager: Arc<Box<dyn MailManager>>,
+}
+#[async_trait]
+impl Job for DeliverOutbox {
+    fn name(&self) -> &'static str {
+        "deliver_outbox"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(5)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        outbox::deliver_pending(
+            &self.pool,
+            self.payment_provider.clone(),
+            self.mail_manager.clone(),
+        )
+        .await
+    }
+}
+
+pub struct PurgeDeliveredOutbox {
+    pub pool: SqlitePool,
+}
+#[async_trait]
+impl Job for PurgeDeliveredOutbox {
+    fn name(&self) -> &'static str {
+        "purge_delivered_outbox"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        let deleted = outbox::purge_delivered(&self.pool).await?;
+        tracing::info!(deleted, "purged delivered outbox messages");
+        Ok(())
+    }
+}
+
+pub struct RetryPendingRefunds {
+    pub pool: SqlitePool,
+    pub payment_provider: Arc<Box<dyn PaymentProvider>>,
+    pub order_events: OrderEvents,
+}
+#[async_trait]
+impl Job for RetryPendingRefunds {
+    fn name(&self) -> &'static str {
+        "retry_pending_refunds"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        refunds::retry_pending_refunds(
+            &self.pool,
+            self.payment_provider.clone(),
+            &self.order_events,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+struct FlakyJob {
+    failures_left: std::sync::atomic::AtomicU32,
+    runs: std::sync::atomic::AtomicU32,
+}
+#[cfg(test)]
+#[async_trait]
+impl Job for FlakyJob {
+    fn name(&self) -> &'static str {
+        "flaky"
+    }
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+    async fn run(&self) -> Result<(), ServerError> {
+        use std::sync::atomic::Ordering;
+        self.runs.fetch_add(1, Ordering::SeqCst);
+        if self.failures_left.load(Ordering::SeqCst) > 0 {
+            self.failures_left.fetch_sub(1, Ordering::SeqCst);
+            return Err(ServerError::Sqlx(sqlx::Error::PoolTimedOut));
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_retries() {
+    use std::sync::atomic::Ordering;
+    let retry_policy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+    };
+    let job = FlakyJob {
+        failures_left: 2.into(),
+        runs: 0.into(),
+    };
+    assert!(run_with_retries(&job, retry_policy).await);
+    assert_eq!(job.runs.load(Ordering::SeqCst), 3);
+
+    let job = FlakyJob {
+        failures_left: 10.into(),
+        runs: 0.into(),
+    };
+    assert!(!run_with_retries(&job, retry_policy).await);
+    assert_eq!(job.runs.load(Ordering::SeqCst), 4);
+}
@@ -0,0 +1,164 @@
+//! logs go through `tracing`. `LOG_LEVEL` takes `EnvFilter` directives (`info`,
+//! `biere_n_collect=debug,tower_http=warn`...) and `LOG_FORMAT` is `plain` or `json`.
+//!
+//! Every request gets an id, taken from its `x-request-id` header when the caller sent a
+//! usable one. It is returned in the response, recorded on the request span, sent along the
+//! Stripe calls and mails, and kept with the outbox messages enqueued by the request.
+
+use std::{env, future::Future, str::FromStr};
+
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::errors::ServerError;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = ServerError;
+    fn from_str(s: &str) -> Result<LogFormat, ServerError> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(ServerError::InvalidLogConfig(format!(
+                "unknown log format {s}"
+            ))),
+        }
+    }
+}
+
+/// both variables are optional, logs are plain and at the `info` level by default
+pub fn init() -> Result<(), ServerError> {
+    let level = env::var("LOG_LEVEL")
+        .ok()
+        .filter(|level| !level.is_empty())
+        .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_owned());
+    let filter =
+        EnvFilter::try_new(&level).map_err(|e| ServerError::InvalidLogConfig(e.to_string()))?;
+    let format = match env::var("LOG_FORMAT") {
+        Ok(format) if !format.is_empty() => format.parse()?,
+        _ => LogFormat::default(),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Plain => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+    Ok(())
+}
+
+/// the id of the request being handled, or of the request that enqueued the outbox message
+/// being delivered
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// runs `future` with `request_id` as the current request id
+pub async fn with_request_id<F: Future>(request_id: Option<String>, future: F) -> F::Output {
+    match request_id {
+        Some(request_id) => REQUEST_ID.scope(request_id, future).await,
+        None => future.await,
+    }
+}
+
+/// ids sent by the caller are kept if they cannot garble the logs
+fn is_usable(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// to be layered outside of the trace layer, which reads the id from the request headers
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_usable(value))
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let header = HeaderValue::from_str(&request_id).expect("request ids are ascii");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header.clone());
+    let mut response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
+
+/// query strings are left out, they can hold emails and login codes
+pub fn make_request_span(request: &Request) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        path = request.uri().path(),
+    )
+}
+
+#[tokio::test]
+async fn test_request_id() {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    let app = Router::new()
+        .route(
+            "/",
+            get(|| async { current_request_id().unwrap_or_default() }),
+        )
+        .layer(middleware::from_fn(request_id));
+    let call = |header: Option<&'static str>| {
+        let app = app.clone();
+        async move {
+            let mut request = Request::builder().uri("/");
+            if let Some(header) = header {
+                request = request.header(REQUEST_ID_HEADER, header);
+            }
+            let res = app
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let header = res.headers()[REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_owned();
+            let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+            (header, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    let (header, body) = call(Some("abc-123")).await;
+    assert_eq!((header.as_str(), body.as_str()), ("abc-123", "abc-123"));
+    // replaced by a generated id
+    let (header, body) = call(Some("a b\"c")).await;
+    assert_eq!(header, body);
+    assert!(Uuid::parse_str(&header).is_ok());
+    let (header, _) = call(None).await;
+    assert!(Uuid::parse_str(&header).is_ok());
+
+    assert_eq!(current_request_id(), None);
+    let id = with_request_id(Some("outbox".to_owned()), async { current_request_id() }).await;
+    assert_eq!(id.as_deref(), Some("outbox"));
+    assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+    assert!("yaml".parse::<LogFormat>().is_err());
+}
@@ -65,3 +65,19 @@ where
         Some(s) => FromStr::from_str(s).map_err(de::Error::custom).map(Some),
     }
 }
+
+/// `21:04 UTC`
+pub fn format_time(timestamp: OffsetDateTime) -> String {
+    format!("{:02}:{:02} UTC", timestamp.hour(), timestamp.minute())
+}
+
+pub fn format_datetime(timestamp: OffsetDateTime) -> String {
+    format!(
+        "{:02}/{:02}/{} {:02}:{:02} UTC",
+        timestamp.day(),
+        timestamp.month() as u8,
+        timestamp.year(),
+        timestamp.hour(),
+        timestamp.minute()
+    )
+}
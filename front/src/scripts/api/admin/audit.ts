@@ -0,0 +1,52 @@
+import { base, Error } from '../api'
+
+export type AuditEntry = {
+    id: number
+    actor_email: string
+    action: string
+    target_kind: string
+    target_id: string | null
+    before: any
+    after: any
+    created_at: Date
+}
+
+export type AuditFilters = {
+    actor_email?: string
+    action?: string
+    target_kind?: string
+    target_id?: string
+    date_begin?: Date
+    date_end?: Date
+    // id of the last entry of the previous page
+    before?: number
+    limit?: number
+}
+
+export async function get_audit_log(
+    filters: AuditFilters = {}
+): Promise<AuditEntry[]> {
+    let params = new URLSearchParams()
+    for (let [key, value] of Object.entries(filters)) {
+        if (value == null || value === '') continue
+        params.set(key, value instanceof Date ? `${value.getTime()}` : `${value}`)
+    }
+    let url = `${base}/admin/audit?${params.toString()}`
+    let error_title = "Erreur lors de la récupération du journal d'audit"
+    try {
+        let res = await fetch(url, {
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return []
+        } else {
+            return (res as any[]).map((e) => {
+                return { ...e, created_at: new Date(e.created_at) }
+            })
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return []
+    }
+}
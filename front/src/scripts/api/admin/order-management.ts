@@ -1,9 +1,13 @@
 import { base, Error, toast } from '../api'
 
 export type OrderDetailElement = {
+    detail_id: number
     item_name: string
     variation_id: number
     quantity: number
+    refunded_quantity: number
+    served_quantity: number
+    remaining_quantity: number
     subtotal_ht: number
     subtotal_ttc: number
 }
@@ -12,19 +16,74 @@ export type Order = {
     timestamp: number
     user_email: string
     receipt?: string
+    invoice_number?: string
     payment_intent_id: string
     served: boolean
+    serving_status: 'unserved' | 'partially_served' | 'served'
+    // paid after its cancellation while the stock had run out
+    needs_review: boolean
     total_price_ht: number
     total_price_ttc: number
+    refunded_amount: number
     detail: OrderDetailElement[]
 }
 
+// the server first sends every order still waiting, then each order that gets paid or served
+export function subscribe_order_queue(
+    on_paid: (order: Order) => void,
+    on_serving_changed: (order: Order) => void
+): EventSource {
+    let source = new EventSource(`${base}/admin/orders/queue`, {
+        withCredentials: true,
+    })
+    source.addEventListener('paid', (e) => on_paid(JSON.parse(e.data)))
+    source.addEventListener('serving_changed', (e) =>
+        on_serving_changed(JSON.parse(e.data))
+    )
+    source.addEventListener('resync', () => {
+        source.close()
+        subscribe_order_queue(on_paid, on_serving_changed)
+    })
+    return source
+}
+
+export type OrderSearchOptions = {
+    status?: 'paid' | 'pending' | 'canceled' | 'expired'
+    served?: boolean
+    needs_review?: boolean
+    // cents
+    min_amount?: number
+    max_amount?: number
+    product_id?: number
+    sort?: 'newest' | 'oldest' | 'amount_desc' | 'amount_asc'
+    // next_cursor of the previous page
+    cursor?: string
+    limit?: number
+}
+
+export type OrderPage = {
+    orders: Order[]
+    next_cursor: string | null
+}
+
 export async function get_orders(
     email: string | null,
     date: [Date, Date] | null,
-    receipt: string | null
-): Promise<Order[]> {
-    let url = `${base}/admin/orders/search?email=${encodeURIComponent(email || '')}&date_begin=${date ? encodeURIComponent(date[0].getTime()) : ''}&date_end=${date ? encodeURIComponent(date[1].getTime()) : ''}&receipt=${encodeURIComponent(receipt || '')}`
+    receipt: string | null,
+    invoice_number: string | null = null,
+    options: OrderSearchOptions = {}
+): Promise<OrderPage | null> {
+    let params = new URLSearchParams({
+        email: email || '',
+        date_begin: date ? date[0].getTime().toString() : '',
+        date_end: date ? date[1].getTime().toString() : '',
+        receipt: receipt || '',
+        invoice_number: invoice_number || '',
+    })
+    for (let [key, value] of Object.entries(options)) {
+        if (value !== undefined) params.set(key, value.toString())
+    }
+    let url = `${base}/admin/orders/search?${params}`
     let error_title = 'Erreur lors de la récupération des commandes'
     try {
         let res = await fetch(url, {
@@ -32,13 +91,13 @@ export async function get_orders(
         }).then((e) => e.json())
         if (res.error) {
             new Error(error_title, res.error)
-            return []
+            return null
         } else {
-            return res as Order[]
+            return res as OrderPage
         }
     } catch (e: any) {
         new Error(error_title, e.toString())
-        return []
+        return null
     }
 }
 
@@ -61,13 +120,46 @@ export async function get_order_by_id(id: number): Promise<Order | null> {
     }
 }
 
-export async function get_order_by_receipt(
-    receipt: string
+// `BNC1.<key id>.<order id>.<receipt>.<signature>`, only the server can check the signature
+// but the order id and receipt are enough to find an order the screen already has
+export function parse_qr_code(
+    data: string
+): { order_id: number; receipt: string } | null {
+    let match = data.match(
+        /^BNC1\.[^.]+\.(\d+)\.([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})\.[0-9a-f]+$/
+    )
+    if (match == null) return null
+    return { order_id: parseInt(match[1]), receipt: match[2] }
+}
+
+export async function get_order_by_qr_code(
+    code: string
 ): Promise<Order | null> {
-    let url = `${base}/admin/orders/by_receipt?receipt=${encodeURIComponent(receipt)}`
-    let error_title = 'Erreur lors de la récupération de la commande'
+    let url = `${base}/admin/orders/by_qr_code?code=${encodeURIComponent(code)}`
+    let error_title = 'QR code invalide ou commande introuvable'
+    try {
+        let res = await fetch(url, {
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            return res as Order
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
+
+// takes the order before serving it, the error tells when and by whom it was already served
+export async function claim_for_serving(order: Order): Promise<Order | null> {
+    let url = `${base}/admin/orders/claim?order_id=${encodeURIComponent(order.id)}`
+    let error_title = 'Commande déjà prise en charge'
     try {
         let res = await fetch(url, {
+            method: 'PATCH',
             credentials: 'include',
         }).then((e) => e.json())
         if (res.error) {
@@ -110,3 +202,65 @@ export async function set_served(
         return false
     }
 }
+
+export async function serve_detail(
+    order: Order,
+    detail: OrderDetailElement,
+    quantity: number
+): Promise<Order | null> {
+    let url = `${base}/admin/orders/serve_detail?order_id=${encodeURIComponent(order.id)}&detail_id=${encodeURIComponent(detail.detail_id)}&quantity=${encodeURIComponent(quantity)}`
+    let error_title = 'Erreur lors de la maj de la commande'
+    try {
+        let res = await fetch(url, {
+            method: 'PATCH',
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            return res as Order
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
+
+export type RefundLine = {
+    detail_id: number
+    quantity: number
+}
+
+// refunds the whole order when `lines` is null
+export async function refund_order(
+    order: Order,
+    reason: string,
+    lines: RefundLine[] | null
+): Promise<Order | null> {
+    let url = `${base}/admin/orders/refund`
+    let error_title = 'Erreur lors du remboursement de la commande'
+    try {
+        let res = await fetch(url, {
+            method: 'POST',
+            credentials: 'include',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ order_id: order.id, reason, lines }),
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            if (toast != null)
+                toast.add({
+                    severity: 'success',
+                    detail: `la commande de ${order.user_email} a été remboursée`,
+                    life: 1500,
+                })
+            return res as Order
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
@@ -7,6 +7,7 @@ export type Bar = {
 }
 
 export type BarOpening = {
+    id: number
     begin: Date
     end: Date
 }
@@ -24,7 +25,11 @@ export async function get_bar_openings(): Promise<BarOpening[]> {
             return []
         } else {
             res = (res as any[]).map((e) => {
-                return { begin: new Date(e.begin), end: new Date(e.end) }
+                return {
+                    id: e.id,
+                    begin: new Date(e.begin),
+                    end: new Date(e.end),
+                }
             })
             return res
         }
@@ -42,14 +47,169 @@ export type ReportItem = {
     subtotal_ttc: number
 }
 
+export type PaymentMethodReport = {
+    payment_method: 'stripe' | 'cash' | 'external_card'
+    order_count: number
+    subtotal_ht: number
+    subtotal_ttc: number
+}
+
+export type VatSummaryLine = {
+    rate: number
+    ht: number
+    vat: number
+    ttc: number
+}
+
+export type Report = {
+    items: ReportItem[]
+    payment_methods: PaymentMethodReport[]
+    vat_summary: VatSummaryLine[]
+}
+
 export async function get_report(
     begin: Date,
     end: Date
-): Promise<ReportItem[]> {
+): Promise<Report | null> {
     let url =
         `${base}/admin/reports?begin=${encodeURIComponent(begin.getTime())}` +
         `&end=${encodeURIComponent(end.getTime())}`
     let error_title = "Erreur lors de l'envoi de la récupération du rapport"
+    try {
+        let res = await fetch(url, {
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            return res as Report
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
+
+export type ServingTimes = {
+    served_count: number
+    average: number | null
+    median: number | null
+    max: number | null
+}
+
+export type ProductVolume = {
+    product_id: number
+    product_name: string
+    volume: number
+}
+
+export type SessionReport = {
+    bar_opening: BarOpening
+    order_count: number
+    takings: { ht: number; vat: number; ttc: number }
+    average_basket: number
+    revenue_per_hour: number
+    serving_times: ServingTimes
+    unserved_orders: number[]
+    volumes: ProductVolume[]
+    report: Report
+}
+
+export type SessionComparison = {
+    first: SessionReport
+    second: SessionReport
+    difference: {
+        order_count: number
+        takings_ttc: number
+        average_basket: number
+        revenue_per_hour: number
+        average_serving_time: number | null
+    }
+}
+
+function parse_session_report(res: any): SessionReport {
+    return {
+        ...res,
+        bar_opening: {
+            id: res.bar_opening.id,
+            begin: new Date(res.bar_opening.begin),
+            end: new Date(res.bar_opening.end),
+        },
+    }
+}
+
+export async function get_session_report(
+    bar_opening_id: number
+): Promise<SessionReport | null> {
+    let url = `${base}/admin/reports/session?bar_opening_id=${encodeURIComponent(bar_opening_id)}`
+    let error_title = "Erreur lors de la récupération du rapport d'ouverture"
+    try {
+        let res = await fetch(url, {
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            return parse_session_report(res)
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
+
+export async function compare_sessions(
+    first: number,
+    second: number
+): Promise<SessionComparison | null> {
+    let url =
+        `${base}/admin/reports/compare?first=${encodeURIComponent(first)}` +
+        `&second=${encodeURIComponent(second)}`
+    let error_title = 'Erreur lors de la comparaison des ouvertures'
+    try {
+        let res = await fetch(url, {
+            credentials: 'include',
+        }).then((e) => e.json())
+        if (res.error) {
+            new Error(error_title, res.error)
+            return null
+        } else {
+            return {
+                first: parse_session_report(res.first),
+                second: parse_session_report(res.second),
+                difference: res.difference,
+            }
+        }
+    } catch (e: any) {
+        new Error(error_title, e.toString())
+        return null
+    }
+}
+
+export type ProductVolumeReport = {
+    product_id: number
+    product_name: string
+    sold_volume: number
+    variations: { item_name: string; quantity: number; volume: number }[]
+    stock: {
+        sales: number
+        refunds: number
+        adjustments: number
+        change: number
+        discrepancy: number
+    }
+}
+
+export async function get_volume_report(
+    begin: Date,
+    end: Date
+): Promise<ProductVolumeReport[]> {
+    let url =
+        `${base}/admin/reports/volumes?begin=${encodeURIComponent(begin.getTime())}` +
+        `&end=${encodeURIComponent(end.getTime())}`
+    let error_title = 'Erreur lors de la récupération des volumes vendus'
     try {
         let res = await fetch(url, {
             credentials: 'include',
@@ -58,7 +218,7 @@ export async function get_report(
             new Error(error_title, res.error)
             return []
         } else {
-            return res as ReportItem[]
+            return res as ProductVolumeReport[]
         }
     } catch (e: any) {
         new Error(error_title, e.toString())
@@ -66,6 +226,13 @@ export async function get_report(
     }
 }
 
+export function get_orders_export_url(begin: Date, end: Date): string {
+    return (
+        `${base}/admin/reports/export?begin=${encodeURIComponent(begin.getTime())}` +
+        `&end=${encodeURIComponent(end.getTime())}`
+    )
+}
+
 export async function close_bar(): Promise<boolean> {
     let url = `${base}/admin/bar/close`
     let error_title =
@@ -107,7 +274,21 @@ export async function set_closing_message(message: string): Promise<boolean> {
     }
 }
 
-export async function list_reports(): Promise<string[]> {
+export type ArchivedReport = {
+    id: number
+    bar_opening_id: number
+    begin: Date
+    end: Date
+    csv_file: string
+    pdf_file: string
+    created_at: Date
+}
+
+export function get_archived_report_url(file: string): string {
+    return `${base}/admin/bar/reports/${encodeURIComponent(file)}`
+}
+
+export async function list_reports(): Promise<ArchivedReport[]> {
     let url = `${base}/admin/bar/list_reports`
     let error_title = 'Erreur lors du chargement des rapports'
     try {
@@ -118,7 +299,14 @@ export async function list_reports(): Promise<string[]> {
             new Error(error_title, res.error)
             return []
         } else {
-            return res as string[]
+            return (res as any[]).map((e) => {
+                return {
+                    ...e,
+                    begin: new Date(e.begin),
+                    end: new Date(e.end),
+                    created_at: new Date(e.created_at),
+                }
+            })
         }
     } catch (e: any) {
         new Error(error_title, e.toString())
@@ -131,3 +131,7 @@ export async function get_payment_status(
 export function get_qr_code_url(client_secret: string): string {
     return `${base}/get_qr_code?client_secret=${encodeURIComponent(client_secret)}`
 }
+
+export function get_invoice_url(client_secret: string): string {
+    return `${base}/get_invoice?client_secret=${encodeURIComponent(client_secret)}`
+}
@@ -17,6 +17,15 @@ export type Variation = {
     volume: number
     available_to_order: boolean
 }
+// ttc of `quantity` units, rounded like the server does: the VAT is computed
+// once on the ht total of the line and rounded half away from zero to the cent
+export function line_ttc(variation: Variation, quantity: number): number {
+    let ht = variation.price_ht * quantity
+    // the rate in hundredths of a percent, like the server stores it
+    let rate = Math.round(variation.tva * 10_000)
+    return ht + Math.floor((ht * rate + 5_000) / 10_000)
+}
+
 export type BarStatus = {
     is_open: boolean
     closed_message?: string
@@ -51,9 +60,10 @@ export async function get_available_products(): Promise<Product[]> {
             let prods = res as Product[]
             for (let i = 0; i < prods.length; i++) {
                 for (let j = 0; j < prods[i].variations.length; j++) {
-                    prods[i].variations[j].price_ttc =
-                        prods[i].variations[j].price_ht *
-                        (1 + prods[i].variations[j].tva)
+                    prods[i].variations[j].price_ttc = line_ttc(
+                        prods[i].variations[j],
+                        1
+                    )
                 }
             }
             return prods
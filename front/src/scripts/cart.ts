@@ -1,5 +1,5 @@
 import { type Router } from 'vue-router'
-import { type Product, type Variation } from './api/products'
+import { line_ttc, type Product, type Variation } from './api/products'
 import { validate_cart } from './api/order'
 import { f_price } from './utils'
 
@@ -101,14 +101,14 @@ export class Cart {
             .map((e) => {
                 return {
                     cart_element: e,
-                    subtotal: e.variation.price_ttc * e.quantity,
+                    subtotal: line_ttc(e.variation, e.quantity),
                 }
             })
     }
     get_total(): string {
         return f_price(
             this.elements.reduce(
-                (acc, e) => acc + e.variation.price_ttc * e.quantity,
+                (acc, e) => acc + line_ttc(e.variation, e.quantity),
                 0
             )
         )